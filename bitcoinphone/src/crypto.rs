@@ -0,0 +1,393 @@
+use std::fmt;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use rand::Rng;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN};
+use ring::digest::{self, SHA256};
+use ring::hkdf::{Salt, HKDF_SHA256};
+use secp256k1::{Message, PublicKey, Secp256k1, SecretKey, Signature};
+use secp256k1::ecdh::SharedSecret;
+use sv::transaction::p2pkh::create_lock_script;
+use sv::util::hash160;
+use crate::util::constants::DataPacket;
+use crate::wire::{self, WireError};
+
+pub const SALT_SIZE: usize = 32;
+pub const SECRET_SIZE: usize = 32;
+
+const CALLER_TO_CALLEE: &[u8] = b"bitcoinphone caller to callee";
+const CALLEE_TO_CALLER: &[u8] = b"bitcoinphone callee to caller";
+const CONFERENCE_PARTICIPANT: &[u8] = b"bitcoinphone conference participant";
+const SIGNED_PACKET: &[u8] = b"bitcoinphone signed packet";
+/// How far behind the newest counter a packet may arrive and still be accepted.
+const REPLAY_WINDOW: u64 = 64;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Role {
+    Caller,
+    Callee,
+}
+
+#[derive(Clone, Debug)]
+pub enum SessionError {
+    InvalidPublicKey,
+    InvalidSalt,
+    InvalidSecret,
+    /// The packet belongs to some other call.
+    WrongSession(u64),
+    /// We've already accepted this counter, or it's too old to tell.
+    Replayed(u64),
+    /// The packet failed authentication.
+    Forged,
+    /// The packet is authentic but we can't read what's inside.
+    Unreadable(WireError),
+}
+
+impl fmt::Display for SessionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return match self {
+            SessionError::InvalidPublicKey => f.write_str("Invalid session public key"),
+            SessionError::InvalidSalt => write!(f, "Session salt must be {} bytes", SALT_SIZE),
+            SessionError::InvalidSecret => write!(f, "Conference secret must be {} bytes", SECRET_SIZE),
+            SessionError::WrongSession(id) => write!(f, "Packet is for session {:016x}", id),
+            SessionError::Replayed(counter) => write!(f, "Counter {} was replayed", counter),
+            SessionError::Forged => f.write_str("Packet failed authentication"),
+            SessionError::Unreadable(err) => write!(f, "Unreadable sealed packet: {}", err),
+        };
+    }
+}
+
+/// Encrypts everything we send the peer during a call and authenticates what comes back.
+///
+/// Both ends take an ECDH secret between their communications keys and mix in a fresh
+/// salt from each side, so every call gets new keys even between the same two people.
+/// Each direction has its own ChaCha20-Poly1305 key and the sealing counter is the
+/// nonce, a nonce is never used twice under one key.
+///
+/// The session id and counter are authenticated with every packet, so packets from
+/// another call, forged ones and replays are all refused by `open`.
+///
+/// On a conference each participant seals with a key of their own taken from a secret
+/// the host hands out, everyone else opens with the same key.
+pub struct Session {
+    id: u64,
+    sealing: LessSafeKey,
+    opening: LessSafeKey,
+    next_counter: AtomicU64,
+    received: Mutex<ReplayWindow>,
+}
+
+impl Session {
+    pub fn new_salt() -> Vec<u8> {
+        let mut salt = vec![0; SALT_SIZE];
+        rand::thread_rng().fill(&mut salt[..]);
+        return salt;
+    }
+
+    pub fn new_id() -> u64 {
+        return rand::thread_rng().gen();
+    }
+
+    pub fn new_secret() -> Vec<u8> {
+        let mut secret = vec![0; SECRET_SIZE];
+        rand::thread_rng().fill(&mut secret[..]);
+        return secret;
+    }
+
+    /// Keys for participant `index` on a conference, whose packets carry `base_id + index`.
+    pub fn for_participant(base_id: u64, secret: &[u8], index: u32) -> Result<Session, SessionError> {
+        if secret.len() != SECRET_SIZE {
+            return Err(SessionError::InvalidSecret);
+        }
+
+        let prk = Salt::new(HKDF_SHA256, &[]).extract(secret);
+        let participant = index.to_be_bytes();
+        let info = [CONFERENCE_PARTICIPANT, &participant[..]];
+
+        // The same key both ways, whoever holds this session either only seals or only opens.
+        let derive_key = || {
+            let okm = prk.expand(&info, &CHACHA20_POLY1305)
+                .expect("Key length is valid for HKDF");
+            return LessSafeKey::new(UnboundKey::from(okm));
+        };
+
+        return Ok(Session {
+            id: base_id.wrapping_add(index as u64),
+            sealing: derive_key(),
+            opening: derive_key(),
+            next_counter: AtomicU64::new(0),
+            received: Mutex::new(ReplayWindow::default()),
+        });
+    }
+
+    pub fn derive(
+        id: u64,
+        our_secret: &SecretKey,
+        peer_public: &[u8],
+        caller_salt: &[u8],
+        callee_salt: &[u8],
+        role: Role
+    ) -> Result<Session, SessionError> {
+        let peer_public = PublicKey::from_slice(peer_public)
+            .map_err(|_| SessionError::InvalidPublicKey)?;
+        if caller_salt.len() != SALT_SIZE || callee_salt.len() != SALT_SIZE {
+            return Err(SessionError::InvalidSalt);
+        }
+
+        let shared_secret = SharedSecret::new(&peer_public, our_secret);
+        let salt = [caller_salt, callee_salt].concat();
+        let prk = Salt::new(HKDF_SHA256, &salt).extract(shared_secret.as_ref());
+
+        let derive_key = |info: &[u8]| {
+            let info = [info];
+            let okm = prk.expand(&info, &CHACHA20_POLY1305)
+                .expect("Key length is valid for HKDF");
+            return LessSafeKey::new(UnboundKey::from(okm));
+        };
+
+        let (sealing, opening) = match role {
+            Role::Caller => (derive_key(CALLER_TO_CALLEE), derive_key(CALLEE_TO_CALLER)),
+            Role::Callee => (derive_key(CALLEE_TO_CALLER), derive_key(CALLER_TO_CALLEE)),
+        };
+
+        return Ok(Session {
+            id,
+            sealing,
+            opening,
+            next_counter: AtomicU64::new(0),
+            received: Mutex::new(ReplayWindow::default()),
+        });
+    }
+
+    pub fn get_id(&self) -> u64 {
+        return self.id;
+    }
+
+    /// Wraps the packet in an `Encrypted` packet for the peer.
+    pub fn seal(&self, packet: &DataPacket) -> DataPacket {
+        let counter = self.next_counter.fetch_add(1, Ordering::SeqCst);
+        let mut payload = wire::encode(packet);
+
+        self.sealing
+            .seal_in_place_append_tag(Self::nonce(counter), self.aad(counter), &mut payload)
+            .expect("Unable to encrypt packet");
+
+        return DataPacket::Encrypted { session_id: self.id, counter, payload };
+    }
+
+    /// Checks and decrypts a packet from the peer. Each counter is only accepted once.
+    pub fn open(&self, session_id: u64, counter: u64, mut payload: Vec<u8>) -> Result<DataPacket, SessionError> {
+        if session_id != self.id {
+            return Err(SessionError::WrongSession(session_id));
+        }
+
+        let mut received = self.received.lock().unwrap();
+        if !received.is_new(counter) {
+            return Err(SessionError::Replayed(counter));
+        }
+
+        let plaintext = self.opening
+            .open_in_place(Self::nonce(counter), self.aad(counter), &mut payload)
+            .map_err(|_| SessionError::Forged)?;
+        let packet = wire::decode(plaintext);
+
+        // Only authentic packets move the window, or anyone could push it past the call.
+        received.accept(counter);
+        return packet.map_err(SessionError::Unreadable);
+    }
+
+    fn nonce(counter: u64) -> Nonce {
+        let mut nonce = [0; NONCE_LEN];
+        nonce[NONCE_LEN - 8..].copy_from_slice(&counter.to_be_bytes());
+        return Nonce::assume_unique_for_key(nonce);
+    }
+
+    fn aad(&self, counter: u64) -> Aad<[u8; 16]> {
+        let mut aad = [0; 16];
+        aad[..8].copy_from_slice(&self.id.to_be_bytes());
+        aad[8..].copy_from_slice(&counter.to_be_bytes());
+        return Aad::from(aad);
+    }
+}
+
+/// Wraps the packet in a `Signed` packet, for packets that go out without a session.
+pub fn sign(packet: &DataPacket, secret_key: &SecretKey) -> DataPacket {
+    let curve = Secp256k1::signing_only();
+    let payload = wire::encode(packet);
+    let signature = curve.sign(&signed_message(&payload), secret_key);
+
+    return DataPacket::Signed {
+        public_key: PublicKey::from_secret_key(&curve, secret_key).serialize().to_vec(),
+        signature: signature.serialize_compact().to_vec(),
+        payload,
+    };
+}
+
+/// Checks a signed packet, handing back the output its key pays and the packet inside.
+pub fn open_signed(public_key: &[u8], signature: &[u8], payload: &[u8]) -> Result<(Vec<u8>, DataPacket), SessionError> {
+    let key = PublicKey::from_slice(public_key)
+        .map_err(|_| SessionError::InvalidPublicKey)?;
+    let signature = Signature::from_compact(signature)
+        .map_err(|_| SessionError::Forged)?;
+    Secp256k1::verification_only()
+        .verify(&signed_message(payload), &signature, &key)
+        .map_err(|_| SessionError::Forged)?;
+
+    let packet = wire::decode(payload).map_err(SessionError::Unreadable)?;
+    return Ok((output_of(public_key), packet));
+}
+
+/// The P2PKH output paying a key, which is how peers know each other.
+pub fn output_of(public_key: &[u8]) -> Vec<u8> {
+    return create_lock_script(&hash160(public_key)).0;
+}
+
+fn signed_message(payload: &[u8]) -> Message {
+    let hash = digest::digest(&SHA256, &[SIGNED_PACKET, payload].concat());
+    return Message::from_slice(hash.as_ref())
+        .expect("SHA-256 hashes are the size of a message");
+}
+
+/// The counters seen so far, packets can arrive out of order but never twice.
+#[derive(Default)]
+struct ReplayWindow {
+    /// One past the newest counter accepted.
+    next: u64,
+    /// Bit `n` is set if counter `next - 1 - n` has been accepted.
+    seen: u64,
+}
+
+impl ReplayWindow {
+    fn is_new(&self, counter: u64) -> bool {
+        if counter >= self.next {
+            return true;
+        }
+
+        let age = self.next - 1 - counter;
+        return age < REPLAY_WINDOW && self.seen & (1 << age) == 0;
+    }
+
+    fn accept(&mut self, counter: u64) {
+        if counter >= self.next {
+            let shift = counter + 1 - self.next;
+            self.seen = if shift >= REPLAY_WINDOW { 0 } else { self.seen << shift };
+            self.next = counter + 1;
+        }
+
+        self.seen |= 1 << (self.next - 1 - counter);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use secp256k1::Secp256k1;
+    use super::*;
+
+    const ID: u64 = 0x0102030405060708;
+
+    /// Both ends of a call, the caller first.
+    fn sessions() -> (Session, Session) {
+        let curve = Secp256k1::new();
+        let caller_secret = SecretKey::from_slice(&[1; 32]).unwrap();
+        let callee_secret = SecretKey::from_slice(&[2; 32]).unwrap();
+        let caller_public = PublicKey::from_secret_key(&curve, &caller_secret).serialize();
+        let callee_public = PublicKey::from_secret_key(&curve, &callee_secret).serialize();
+        let (caller_salt, callee_salt) = (Session::new_salt(), Session::new_salt());
+
+        return (
+            Session::derive(ID, &caller_secret, &callee_public, &caller_salt, &callee_salt, Role::Caller).unwrap(),
+            Session::derive(ID, &callee_secret, &caller_public, &caller_salt, &callee_salt, Role::Callee).unwrap(),
+        );
+    }
+
+    fn text(id: u64) -> DataPacket {
+        return DataPacket::Text { id, from: vec![0x76, 0xa9], text: "hello".to_string() };
+    }
+
+    fn open(session: &Session, sealed: DataPacket) -> Result<DataPacket, SessionError> {
+        return match sealed {
+            DataPacket::Encrypted{ session_id, counter, payload } => session.open(session_id, counter, payload),
+            _ => panic!("Expected a sealed packet")
+        };
+    }
+
+    #[test]
+    fn round_trips_both_ways() {
+        let (caller, callee) = sessions();
+
+        let opened = open(&callee, caller.seal(&text(1))).unwrap();
+        assert_eq!(wire::encode(&opened), wire::encode(&text(1)));
+        let opened = open(&caller, callee.seal(&text(2))).unwrap();
+        assert_eq!(wire::encode(&opened), wire::encode(&text(2)));
+    }
+
+    #[test]
+    fn refuses_packets_for_other_sessions() {
+        let (caller, callee) = sessions();
+
+        match caller.seal(&text(1)) {
+            DataPacket::Encrypted{ counter, payload, .. } => assert!(matches!(
+                callee.open(ID + 1, counter, payload),
+                Err(SessionError::WrongSession(id)) if id == ID + 1
+            )),
+            _ => panic!("Expected a sealed packet")
+        }
+        // Our own packets are sealed with the other direction's key.
+        assert!(matches!(open(&caller, caller.seal(&text(1))), Err(SessionError::Forged)));
+    }
+
+    #[test]
+    fn refuses_tampered_packets() {
+        let (caller, callee) = sessions();
+
+        match caller.seal(&text(1)) {
+            DataPacket::Encrypted{ session_id, counter, mut payload } => {
+                *payload.last_mut().unwrap() ^= 1;
+                assert!(matches!(callee.open(session_id, counter, payload), Err(SessionError::Forged)));
+            }
+            _ => panic!("Expected a sealed packet")
+        }
+    }
+
+    #[test]
+    fn refuses_replayed_counters() {
+        let (caller, callee) = sessions();
+        let first = caller.seal(&text(1));
+        let second = caller.seal(&text(2));
+
+        // Out of order is fine, twice isn't.
+        assert!(open(&callee, second.clone()).is_ok());
+        assert!(open(&callee, first.clone()).is_ok());
+        assert!(matches!(open(&callee, first), Err(SessionError::Replayed(0))));
+        assert!(matches!(open(&callee, second), Err(SessionError::Replayed(1))));
+    }
+
+    #[test]
+    fn refuses_counters_older_than_the_window() {
+        let (caller, callee) = sessions();
+        let mut sealed = (0..=REPLAY_WINDOW)
+            .map(|id| caller.seal(&text(id)))
+            .collect::<Vec<DataPacket>>();
+        let newest = sealed.pop().unwrap();
+        let oldest = sealed.remove(0);
+
+        assert!(open(&callee, newest).is_ok());
+        assert!(open(&callee, sealed.pop().unwrap()).is_ok());
+        assert!(matches!(open(&callee, oldest), Err(SessionError::Replayed(0))));
+    }
+
+    #[test]
+    fn forged_packets_dont_move_the_window() {
+        let (caller, callee) = sessions();
+        let first = caller.seal(&text(1));
+
+        match first.clone() {
+            DataPacket::Encrypted{ session_id, payload, .. } => assert!(matches!(
+                callee.open(session_id, REPLAY_WINDOW * 10, payload),
+                Err(SessionError::Forged)
+            )),
+            _ => panic!("Expected a sealed packet")
+        }
+        assert!(open(&callee, first).is_ok());
+    }
+}
@@ -0,0 +1,212 @@
+use std::fmt;
+use crate::util::constants::DataPacket;
+use crate::phone::stats::CallStatsSnapshot;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CallState {
+    Idle,
+    /// We sent a start and are waiting for the peer to acknowledge it.
+    Dialing,
+    /// A peer sent us a start.
+    Ringing,
+    Syncing,
+    InCall,
+    HangingUp,
+    Ended,
+}
+
+impl CallState {
+    pub fn can_transition_to(&self, next: CallState) -> bool {
+        return match (self, next) {
+            (CallState::Idle, CallState::Dialing) => true,
+            (CallState::Idle, CallState::Ringing) => true,
+            (CallState::Dialing, CallState::Syncing) => true,
+            (CallState::Ringing, CallState::Syncing) => true,
+            (CallState::Syncing, CallState::InCall) => true,
+            // A conference's host dials the next participant once the last one has synced.
+            (CallState::Syncing, CallState::Dialing) => true,
+            (CallState::InCall, CallState::HangingUp) => true,
+            (CallState::HangingUp, CallState::Ended) => true,
+            (CallState::Ended, CallState::Idle) => true,
+            // Any call in progress can fail.
+            (CallState::Idle, CallState::Ended) | (CallState::Ended, CallState::Ended) => false,
+            (_, CallState::Ended) => true,
+            _ => false
+        };
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum CallError {
+    /// The state machine was asked to make a move it doesn't allow
+    InvalidTransition { from: CallState, to: CallState },
+    /// A packet arrived that makes no sense in the current state
+    UnexpectedPacket { state: CallState, packet: String },
+    /// The peer sent values we can't use
+    BadPacket(String),
+    /// The peer never acknowledged our start
+    NoAnswer { attempts: u32 },
+    /// The peer is already on a call
+    Busy,
+    /// The peer turned the call down
+    Rejected(String),
+    /// The peer hung up before the call was connected
+    HungUp,
+    /// We hung up before the call was connected
+    Cancelled,
+    /// We turned the call down
+    Declined,
+    /// Nothing arrived in time
+    Timeout(CallState),
+    /// The network side has gone away
+    Disconnected,
+    /// The sound card or WAV file couldn't be opened
+    Audio(String),
+}
+
+impl fmt::Display for CallError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return match self {
+            CallError::InvalidTransition { from, to } =>
+                write!(f, "Invalid call transition from {:?} to {:?}", from, to),
+            CallError::UnexpectedPacket { state, packet } =>
+                write!(f, "Unexpected {} packet while {:?}", packet, state),
+            CallError::BadPacket(reason) => write!(f, "Bad packet: {}", reason),
+            CallError::NoAnswer { attempts } => write!(f, "No answer after {} attempts", attempts),
+            CallError::Busy => f.write_str("Peer is busy"),
+            CallError::Rejected(reason) => write!(f, "Peer rejected the call: {}", reason),
+            CallError::HungUp => f.write_str("Peer hung up"),
+            CallError::Cancelled => f.write_str("Call cancelled"),
+            CallError::Declined => f.write_str("Call declined"),
+            CallError::Timeout(state) => write!(f, "Timed out while {:?}", state),
+            CallError::Disconnected => f.write_str("Network disconnected"),
+            CallError::Audio(reason) => write!(f, "Audio device failed: {}", reason),
+        };
+    }
+}
+
+impl std::error::Error for CallError {}
+
+/// What the UI gets told about calls.
+#[derive(Clone, Debug)]
+pub enum CallEvent {
+    StateChanged { from: CallState, to: CallState },
+    /// Someone is calling, answer with `UIEvent::Accept` or `UIEvent::Decline`.
+    IncomingCall { caller: String },
+    /// A handshake packet went unanswered and was sent again.
+    Retrying { attempt: u32 },
+    /// How the call is going, sent every `STATS_INTERVAL` while it runs.
+    Stats(CallStatsSnapshot),
+    /// How the call went, sent once it has hung up.
+    Summary(CallStatsSnapshot),
+    /// We're on a conference with these others.
+    ConferenceStarted { participants: Vec<String> },
+    /// Someone we dialed for a conference never joined it.
+    ParticipantFailed { participant: String, error: CallError },
+    /// Someone hung up or went quiet, the conference goes on without them.
+    ParticipantLeft { participant: String },
+    /// A text or attachment went out, whoever it's for acks it by this id.
+    MessageSent { id: u64 },
+    TextReceived { from: String, text: String },
+    AttachmentReceived { from: String, name: String, data: Vec<u8> },
+    /// `to` got message `id`.
+    MessageDelivered { id: u64, to: String },
+    /// `to` never acked message `id`.
+    MessageFailed { id: u64, to: String },
+    Failed(CallError),
+}
+
+/// Short name of a packet for logs and errors, without its payload.
+pub fn describe(packet: &DataPacket) -> String {
+    let name = match packet {
+        DataPacket::UIEvent(_) => "UIEvent",
+        DataPacket::Start { .. } => "Start",
+        DataPacket::StartAck { .. } => "StartAck",
+        DataPacket::Sync { .. } => "Sync",
+        DataPacket::SyncReply { .. } => "SyncReply",
+        DataPacket::Data { .. } => "Data",
+        DataPacket::Silence { .. } => "Silence",
+        DataPacket::Repair { .. } => "Repair",
+        DataPacket::HangUp { .. } => "HangUp",
+        DataPacket::Busy { .. } => "Busy",
+        DataPacket::Reject { .. } => "Reject",
+        DataPacket::KeepAlive => "KeepAlive",
+        DataPacket::Encrypted { .. } => "Encrypted",
+        DataPacket::Conference { .. } => "Conference",
+        DataPacket::FromParticipant { .. } => "FromParticipant",
+        DataPacket::Signed { .. } => "Signed",
+        DataPacket::FromSender { .. } => "FromSender",
+        DataPacket::Text { .. } => "Text",
+        DataPacket::Attachment { .. } => "Attachment",
+        DataPacket::MessageAck { .. } => "MessageAck",
+    };
+
+    return name.to_string();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STATES: &[CallState] = &[
+        CallState::Idle,
+        CallState::Dialing,
+        CallState::Ringing,
+        CallState::Syncing,
+        CallState::InCall,
+        CallState::HangingUp,
+        CallState::Ended,
+    ];
+
+    #[test]
+    fn allows_only_the_call_flow() {
+        let allowed = [
+            (CallState::Idle, CallState::Dialing),
+            (CallState::Idle, CallState::Ringing),
+            (CallState::Dialing, CallState::Syncing),
+            (CallState::Ringing, CallState::Syncing),
+            (CallState::Syncing, CallState::InCall),
+            (CallState::Syncing, CallState::Dialing),
+            (CallState::InCall, CallState::HangingUp),
+            (CallState::HangingUp, CallState::Ended),
+            (CallState::Ended, CallState::Idle),
+            (CallState::Dialing, CallState::Ended),
+            (CallState::Ringing, CallState::Ended),
+            (CallState::Syncing, CallState::Ended),
+            (CallState::InCall, CallState::Ended),
+        ];
+
+        for from in STATES {
+            for to in STATES {
+                assert_eq!(
+                    from.can_transition_to(*to),
+                    allowed.contains(&(*from, *to)),
+                    "{:?} -> {:?}",
+                    from,
+                    to
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn only_goes_back_to_idle_once_ended() {
+        for state in STATES {
+            assert_eq!(state.can_transition_to(CallState::Idle), *state == CallState::Ended, "{:?}", state);
+        }
+        // A call can't end before it starts, or end twice.
+        assert!(!CallState::Idle.can_transition_to(CallState::Ended));
+        assert!(!CallState::Ended.can_transition_to(CallState::Ended));
+    }
+
+    #[test]
+    fn describes_errors() {
+        let invalid = CallError::InvalidTransition { from: CallState::Idle, to: CallState::InCall };
+        assert_eq!(invalid.to_string(), "Invalid call transition from Idle to InCall");
+        let unexpected = CallError::UnexpectedPacket { state: CallState::Dialing, packet: describe(&DataPacket::KeepAlive) };
+        assert_eq!(unexpected.to_string(), "Unexpected KeepAlive packet while Dialing");
+        assert_eq!(CallError::NoAnswer { attempts: 3 }.to_string(), "No answer after 3 attempts");
+        assert_eq!(CallError::Timeout(CallState::Syncing).to_string(), "Timed out while Syncing");
+        assert_eq!(CallError::Rejected("busy".to_string()).to_string(), "Peer rejected the call: busy");
+    }
+}
@@ -0,0 +1,182 @@
+use std::time::Duration;
+
+/// Least playout delay we run with, however quiet the network looked during sync.
+pub const MIN_PLAYOUT_DELAY: Duration = Duration::from_millis(200);
+pub const MAX_PLAYOUT_DELAY: Duration = Duration::from_secs(5);
+/// Jitter deviations of headroom added to the spread of delays we saw.
+const JITTER_HEADROOM: f64 = 2.0;
+
+/// One round of the sync exchange, timestamps in nanoseconds since the epoch.
+#[derive(Clone, Copy, Debug)]
+pub struct ClockSample {
+    /// Peer's clock minus ours.
+    pub offset_nanos: f64,
+    /// Time on the network there and back, not counting the peer holding our sync.
+    pub round_trip_nanos: f64,
+}
+
+impl ClockSample {
+    /// Works out a sample the way NTP does from when we sent the sync, when the peer got
+    /// it, when the peer replied and when the reply got back to us.
+    pub fn new(sent: u128, peer_received: u128, peer_replied: u128, received: u128) -> ClockSample {
+        let (t1, t2, t3, t4) = (sent as f64, peer_received as f64, peer_replied as f64, received as f64);
+
+        return ClockSample {
+            offset_nanos: ((t2 - t1) + (t3 - t4)) / 2.0,
+            round_trip_nanos: ((t4 - t1) - (t3 - t2)).max(0.0),
+        };
+    }
+}
+
+/// What the sync exchange tells us about the peer's clock and the network between us.
+#[derive(Clone, Debug)]
+pub struct ClockEstimate {
+    /// Peer's clock minus ours, from the sample that spent least time on the network.
+    pub offset_nanos: f64,
+    /// The true offset is within this much of `offset_nanos`, half that sample's round trip.
+    pub offset_error_nanos: f64,
+    /// Shortest one way delay seen.
+    pub delay_nanos: f64,
+    /// Longest one way delay seen.
+    pub max_delay_nanos: f64,
+    /// Standard deviation of the one way delays.
+    pub jitter_nanos: f64,
+    pub samples: usize,
+}
+
+impl ClockEstimate {
+    pub fn from_samples(samples: &[ClockSample]) -> Option<ClockEstimate> {
+        let best = samples.iter()
+            .min_by(|a, b| a.round_trip_nanos.partial_cmp(&b.round_trip_nanos).unwrap())?;
+
+        // Without knowing how the round trip splits, each way is taken to be half of it.
+        let delays = samples.iter()
+            .map(|sample| sample.round_trip_nanos / 2.0)
+            .collect::<Vec<f64>>();
+        let mean = delays.iter().sum::<f64>() / delays.len() as f64;
+        let variance = delays.iter()
+            .map(|delay| (delay - mean).powi(2))
+            .sum::<f64>() / delays.len() as f64;
+
+        return Some(ClockEstimate {
+            offset_nanos: best.offset_nanos,
+            offset_error_nanos: best.round_trip_nanos / 2.0,
+            delay_nanos: best.round_trip_nanos / 2.0,
+            max_delay_nanos: delays.iter().cloned().fold(0.0, f64::max),
+            jitter_nanos: variance.sqrt(),
+            samples: samples.len(),
+        });
+    }
+
+    /// How long the phone should hold frames back, enough to cover the spread of delays
+    /// we saw plus some room for jitter.
+    pub fn playout_delay(&self) -> Duration {
+        let spread = self.max_delay_nanos - self.delay_nanos;
+        let nanos = spread + JITTER_HEADROOM * self.jitter_nanos;
+
+        return Duration::from_nanos(nanos as u64)
+            .max(MIN_PLAYOUT_DELAY)
+            .min(MAX_PLAYOUT_DELAY);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MS: f64 = 1e6;
+    const START: u128 = 1_600_000_000_000_000_000;
+
+    /// A sync and its reply when the peer's clock is `offset` ms ahead of ours, the sync
+    /// takes `out` ms to arrive, the peer holds it for `hold` ms and the reply takes `back`.
+    fn exchange(offset: f64, out: f64, hold: f64, back: f64) -> ClockSample {
+        let ms = |ms: f64| (ms * MS) as i128;
+        let sent = START as i128;
+        let peer_received = sent + ms(out) + ms(offset);
+        let peer_replied = peer_received + ms(hold);
+        let received = sent + ms(out) + ms(hold) + ms(back);
+
+        return ClockSample::new(sent as u128, peer_received as u128, peer_replied as u128, received as u128);
+    }
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!((actual - expected).abs() < 0.001 * MS, "{} ms, expected {} ms", actual / MS, expected / MS);
+    }
+
+    #[test]
+    fn finds_a_skewed_clock_over_an_even_link() {
+        let ahead = exchange(250.0, 40.0, 5.0, 40.0);
+        assert_close(ahead.offset_nanos, 250.0 * MS);
+        assert_close(ahead.round_trip_nanos, 80.0 * MS);
+
+        let behind = exchange(-1500.0, 40.0, 300.0, 40.0);
+        assert_close(behind.offset_nanos, -1500.0 * MS);
+        // The peer holding the sync isn't time on the network.
+        assert_close(behind.round_trip_nanos, 80.0 * MS);
+    }
+
+    #[test]
+    fn bounds_the_offset_error_over_an_uneven_link() {
+        for (out, back) in [(10.0, 70.0), (70.0, 10.0), (0.0, 80.0)].iter() {
+            let estimate = ClockEstimate::from_samples(&[exchange(250.0, *out, 5.0, *back)]).unwrap();
+
+            // Half the difference between the two ways ends up in the offset.
+            assert_close(estimate.offset_nanos, (250.0 + (out - back) / 2.0) * MS);
+            assert!((estimate.offset_nanos - 250.0 * MS).abs() <= estimate.offset_error_nanos + 0.001 * MS);
+            assert_close(estimate.offset_error_nanos, 40.0 * MS);
+        }
+    }
+
+    #[test]
+    fn trusts_the_quickest_round_trip() {
+        let samples = [
+            exchange(100.0, 60.0, 5.0, 0.0),
+            exchange(100.0, 10.0, 5.0, 10.0),
+            exchange(100.0, 30.0, 5.0, 90.0),
+        ];
+        let estimate = ClockEstimate::from_samples(&samples).unwrap();
+
+        assert_close(estimate.offset_nanos, 100.0 * MS);
+        assert_close(estimate.offset_error_nanos, 10.0 * MS);
+        assert_close(estimate.delay_nanos, 10.0 * MS);
+        assert_close(estimate.max_delay_nanos, 60.0 * MS);
+        // Delays of 30, 10 and 60 ms.
+        assert_close(estimate.jitter_nanos, (3800.0f64 / 9.0).sqrt() * MS);
+        assert_eq!(estimate.samples, 3);
+    }
+
+    #[test]
+    fn needs_a_sample() {
+        assert!(ClockEstimate::from_samples(&[]).is_none());
+    }
+
+    #[test]
+    fn ignores_replies_held_longer_than_the_round_trip() {
+        // A peer whose clock runs fast while it holds the sync.
+        let sample = ClockSample::new(START, START + 10, START + 1_000_000_000, START + 20);
+        assert_eq!(sample.round_trip_nanos, 0.0);
+    }
+
+    #[test]
+    fn covers_the_spread_of_delays() {
+        let estimate = ClockEstimate::from_samples(&[
+            exchange(0.0, 100.0, 0.0, 100.0),
+            exchange(0.0, 700.0, 0.0, 700.0),
+        ]).unwrap();
+
+        // 600 ms between the shortest and longest delay plus two deviations of 300 ms.
+        assert_close(estimate.playout_delay().as_nanos() as f64, 1200.0 * MS);
+    }
+
+    #[test]
+    fn keeps_the_playout_delay_within_bounds() {
+        let steady = ClockEstimate::from_samples(&[exchange(0.0, 20.0, 0.0, 20.0); 5]).unwrap();
+        assert_eq!(steady.playout_delay(), MIN_PLAYOUT_DELAY);
+
+        let wild = ClockEstimate::from_samples(&[
+            exchange(0.0, 10.0, 0.0, 10.0),
+            exchange(0.0, 9000.0, 0.0, 9000.0),
+        ]).unwrap();
+        assert_eq!(wild.playout_delay(), MAX_PLAYOUT_DELAY);
+    }
+}
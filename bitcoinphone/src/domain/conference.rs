@@ -0,0 +1,269 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use sv::script::Script;
+use crate::crypto::Session;
+use crate::domain::call::CallError;
+use crate::domain::clock::ClockEstimate;
+use crate::util::constants::{CodecType, DataPacket};
+
+/// A call the host of a conference has set up with one of its participants.
+pub struct Leg {
+    pub output: Script,
+    pub session: Arc<Session>,
+    pub sample_rate: u32,
+    pub clock: ClockEstimate,
+}
+
+/// Everyone on a conference call and the sessions they seal with.
+///
+/// The host calls each participant the usual way, then hands each of them a secret over
+/// their own session along with who else is on the call. Every participant's sending key
+/// comes from that secret, so one transaction with a dust output for each of the others
+/// reaches all of them and all of them can open it.
+pub struct Conference {
+    invite: DataPacket,
+    participants: Vec<Script>,
+    sessions: Vec<Arc<Session>>,
+    /// Our place in `participants`.
+    index: usize,
+    /// When we last heard from each participant, `None` once they've left.
+    last_heard: Vec<Option<Instant>>,
+}
+
+impl Conference {
+    /// Joins the conference an invite from its host describes.
+    pub fn join(invite: DataPacket) -> Result<Conference, CallError> {
+        let (sessions, index, participants) = match &invite {
+            DataPacket::Conference{ session_id, index, secret, participants, sample_rates } => {
+                // The phone resamples everyone's audio from these.
+                if sample_rates.len() != participants.len() || sample_rates.contains(&0) {
+                    return Err(CallError::BadPacket(format!("Invalid sample rates for {} participants", participants.len())));
+                }
+
+                let sessions = (0..participants.len() as u32)
+                    .map(|participant| Session::for_participant(*session_id, secret, participant).map(Arc::new))
+                    .collect::<Result<Vec<Arc<Session>>, _>>()
+                    .map_err(|err| CallError::BadPacket(err.to_string()))?;
+                (sessions, *index as usize, participants.iter().cloned().map(Script).collect::<Vec<Script>>())
+            }
+            _ => return Err(CallError::BadPacket("Not a conference invite".to_string()))
+        };
+        if index >= participants.len() {
+            return Err(CallError::BadPacket(format!("No participant {} of {}", index, participants.len())));
+        }
+
+        let now = Instant::now();
+        return Ok(Conference {
+            last_heard: (0..participants.len())
+                .map(|participant| if participant == index { None } else { Some(now) })
+                .collect(),
+            invite,
+            participants,
+            sessions,
+            index,
+        });
+    }
+
+    /// What we were invited with, the phone takes the sample rates from it.
+    pub fn get_invite(&self) -> &DataPacket {
+        return &self.invite;
+    }
+
+    /// The session we seal everything we send with.
+    pub fn get_session(&self) -> Arc<Session> {
+        return self.sessions[self.index].clone();
+    }
+
+    /// Sessions for opening what everyone else sends.
+    pub fn get_others(&self) -> Vec<Arc<Session>> {
+        return self.sessions.iter()
+            .enumerate()
+            .filter(|(participant, _)| *participant != self.index)
+            .map(|(_, session)| session.clone())
+            .collect();
+    }
+
+    /// Everyone still on the call apart from us.
+    pub fn get_outputs(&self) -> Vec<Script> {
+        return self.participants.iter()
+            .zip(&self.last_heard)
+            .filter(|(_, last_heard)| last_heard.is_some())
+            .map(|(output, _)| output.clone())
+            .collect();
+    }
+
+    /// True once everyone else has left.
+    pub fn is_empty(&self) -> bool {
+        return self.last_heard.iter().all(Option::is_none);
+    }
+
+    pub fn heard(&mut self, session_id: u64) {
+        if let Some(participant) = self.find(session_id) {
+            if let Some(last_heard) = &mut self.last_heard[participant] {
+                *last_heard = Instant::now();
+            }
+        }
+    }
+
+    /// Marks whoever seals with `session_id` as gone, returning their output unless they
+    /// had already left.
+    pub fn leave(&mut self, session_id: u64) -> Option<Script> {
+        let participant = self.find(session_id)?;
+        self.last_heard[participant].take()?;
+        return Some(self.participants[participant].clone());
+    }
+
+    /// Marks everyone we haven't heard from for `timeout` as gone, returning their outputs.
+    pub fn drop_quiet(&mut self, timeout: Duration) -> Vec<Script> {
+        let mut dropped = vec![];
+        for (participant, last_heard) in self.last_heard.iter_mut().enumerate() {
+            if last_heard.map(|time| time.elapsed() >= timeout).unwrap_or(false) {
+                *last_heard = None;
+                dropped.push(self.participants[participant].clone());
+            }
+        }
+
+        return dropped;
+    }
+
+    fn find(&self, session_id: u64) -> Option<usize> {
+        return self.sessions.iter().position(|session| session.get_id() == session_id);
+    }
+}
+
+/// Where the mic thread sends our audio and what it seals it with, joining or leaving a
+/// conference changes both mid-call.
+pub struct Route {
+    pub session: Option<Arc<Session>>,
+    pub outputs: Vec<Script>,
+    /// Conference participants each agreed a codec with the host alone, so every frame
+    /// has to say which it's in.
+    pub codec: Option<CodecType>,
+}
+
+impl Route {
+    pub fn seal(&self, packet: DataPacket) -> DataPacket {
+        let packet = match (self.codec, packet) {
+            (Some(codec), DataPacket::Data{ counter, format, buffer, codec: None }) => {
+                DataPacket::Data{ counter, format, buffer, codec: Some(codec) }
+            }
+            (Some(codec), DataPacket::Repair{ counters, format, length, parity, codec: None }) => {
+                DataPacket::Repair{ counters, format, length, parity, codec: Some(codec) }
+            }
+            (_, packet) => packet
+        };
+
+        return match &self.session {
+            Some(session) => session.seal(&packet),
+            None => packet
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::util::constants::SampleFormat;
+    use super::*;
+
+    const SESSION_ID: u64 = 1000;
+    const SECRET: [u8; 32] = [7; 32];
+
+    /// An invite for us as participant `index` of three.
+    fn invite(index: u32, sample_rates: Vec<u32>) -> DataPacket {
+        return DataPacket::Conference {
+            session_id: SESSION_ID,
+            index,
+            secret: SECRET.to_vec(),
+            participants: vec![vec![1], vec![2], vec![3]],
+            sample_rates,
+        };
+    }
+
+    fn bad_packet(result: Result<Conference, CallError>) -> bool {
+        return matches!(result, Err(CallError::BadPacket(_)));
+    }
+
+    fn data(codec: Option<CodecType>) -> DataPacket {
+        return DataPacket::Data { counter: 1, format: SampleFormat::I16, buffer: vec![1, 2], codec };
+    }
+
+    #[test]
+    fn checks_the_invite() {
+        assert!(Conference::join(invite(1, vec![8000, 16000, 44100])).is_ok());
+        assert!(bad_packet(Conference::join(invite(3, vec![8000, 8000, 8000]))));
+        assert!(bad_packet(Conference::join(invite(1, vec![8000, 8000]))));
+        assert!(bad_packet(Conference::join(invite(1, vec![8000, 0, 8000]))));
+        assert!(bad_packet(Conference::join(DataPacket::KeepAlive)));
+    }
+
+    #[test]
+    fn seals_with_our_place_on_the_call() {
+        let conference = Conference::join(invite(1, vec![8000; 3])).unwrap();
+
+        assert_eq!(conference.get_session().get_id(), SESSION_ID + 1);
+        let others = conference.get_others().iter().map(|session| session.get_id()).collect::<Vec<u64>>();
+        assert_eq!(others, vec![SESSION_ID, SESSION_ID + 2]);
+        assert_eq!(conference.get_outputs(), vec![Script(vec![1]), Script(vec![3])]);
+    }
+
+    #[test]
+    fn lets_participants_leave_once() {
+        let mut conference = Conference::join(invite(0, vec![8000; 3])).unwrap();
+
+        assert_eq!(conference.leave(SESSION_ID + 2), Some(Script(vec![3])));
+        assert_eq!(conference.leave(SESSION_ID + 2), None);
+        // We can't leave ourselves, and strangers were never on the call.
+        assert_eq!(conference.leave(SESSION_ID), None);
+        assert_eq!(conference.leave(SESSION_ID + 3), None);
+        assert_eq!(conference.get_outputs(), vec![Script(vec![2])]);
+        assert!(!conference.is_empty());
+
+        assert_eq!(conference.leave(SESSION_ID + 1), Some(Script(vec![2])));
+        assert!(conference.get_outputs().is_empty());
+        assert!(conference.is_empty());
+    }
+
+    #[test]
+    fn drops_participants_gone_quiet() {
+        let timeout = Duration::from_secs(30);
+        let mut conference = Conference::join(invite(0, vec![8000; 3])).unwrap();
+        assert!(conference.drop_quiet(timeout).is_empty());
+
+        for last_heard in conference.last_heard.iter_mut().flatten() {
+            *last_heard = last_heard.checked_sub(timeout).unwrap();
+        }
+        conference.heard(SESSION_ID + 1);
+
+        assert_eq!(conference.drop_quiet(timeout), vec![Script(vec![3])]);
+        assert_eq!(conference.get_outputs(), vec![Script(vec![2])]);
+        // Hearing from someone who has left doesn't bring them back.
+        conference.heard(SESSION_ID + 2);
+        assert_eq!(conference.get_outputs(), vec![Script(vec![2])]);
+    }
+
+    #[test]
+    fn tags_frames_with_the_codec() {
+        let route = Route { session: None, outputs: vec![], codec: Some(CodecType::MuLaw) };
+
+        assert!(matches!(route.seal(data(None)), DataPacket::Data{ codec: Some(CodecType::MuLaw), .. }));
+        assert!(matches!(route.seal(data(Some(CodecType::Pcm))), DataPacket::Data{ codec: Some(CodecType::Pcm), .. }));
+        let repair = DataPacket::Repair { counters: vec![1], format: SampleFormat::I16, length: 2, parity: vec![1, 2], codec: None };
+        assert!(matches!(route.seal(repair), DataPacket::Repair{ codec: Some(CodecType::MuLaw), .. }));
+        assert!(matches!(route.seal(DataPacket::KeepAlive), DataPacket::KeepAlive));
+
+        let untagged = Route { session: None, outputs: vec![], codec: None };
+        assert!(matches!(untagged.seal(data(None)), DataPacket::Data{ codec: None, .. }));
+    }
+
+    #[test]
+    fn tags_frames_before_sealing() {
+        let session = Arc::new(Session::for_participant(SESSION_ID, &SECRET, 0).unwrap());
+        let route = Route { session: Some(session.clone()), outputs: vec![], codec: Some(CodecType::ImaAdpcm) };
+
+        let opened = match route.seal(data(None)) {
+            DataPacket::Encrypted{ session_id, counter, payload } => session.open(session_id, counter, payload).unwrap(),
+            packet => panic!("Expected a sealed packet, got {:?}", packet)
+        };
+        assert!(matches!(opened, DataPacket::Data{ codec: Some(CodecType::ImaAdpcm), .. }));
+    }
+}
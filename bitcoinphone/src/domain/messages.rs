@@ -0,0 +1,259 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::{Duration, Instant};
+use rand::Rng;
+use sv::script::Script;
+use crate::util::constants::DataPacket;
+
+/// How long we wait for a message's ack before sending it again.
+pub const ACK_TIMEOUT: Duration = Duration::from_secs(30);
+/// Times a message is sent before we give up on whoever hasn't acked it.
+pub const MESSAGE_ATTEMPTS: u32 = 3;
+/// Messages we remember receiving, so a resend whose ack was lost isn't shown twice.
+const RECEIVED_MEMORY: usize = 1000;
+/// Acks we'll send anyone in `ACK_WINDOW`, each one costs us a transaction.
+pub const ACKS_PER_WINDOW: u32 = 10;
+pub const ACK_WINDOW: Duration = Duration::from_secs(60);
+
+struct Pending {
+    packet: DataPacket,
+    /// Recipients that haven't acked yet.
+    outputs: Vec<Script>,
+    attempts: u32,
+    sent: Instant,
+}
+
+/// What `Outbox::due` wants done.
+pub struct Due {
+    /// Messages to send again and who to.
+    pub resend: Vec<(DataPacket, Vec<Script>)>,
+    /// Messages we've given up on and who never acked them.
+    pub failed: Vec<(u64, Vec<Script>)>,
+}
+
+/// Texts and attachments waiting on acks, and the ones we've already received.
+pub struct Outbox {
+    pending: HashMap<u64, Pending>,
+    received: HashSet<(Vec<u8>, u64)>,
+    received_order: VecDeque<(Vec<u8>, u64)>,
+    /// Everyone we've sent a message to.
+    contacts: HashSet<Vec<u8>>,
+    /// When each sender's current ack window started and the acks sent in it.
+    acks: HashMap<Vec<u8>, (Instant, u32)>,
+}
+
+impl Outbox {
+    pub fn new() -> Outbox {
+        return Outbox {
+            pending: HashMap::new(),
+            received: HashSet::new(),
+            received_order: VecDeque::new(),
+            contacts: HashSet::new(),
+            acks: HashMap::new(),
+        };
+    }
+
+    pub fn new_id() -> u64 {
+        return rand::thread_rng().gen();
+    }
+
+    /// Starts waiting on acks for a message that has just been sent.
+    pub fn add(&mut self, id: u64, packet: DataPacket, outputs: Vec<Script>) {
+        self.contacts.extend(outputs.iter().map(|output| output.0.clone()));
+        self.pending.insert(id, Pending { packet, outputs, attempts: 1, sent: Instant::now() });
+    }
+
+    /// Marks the message delivered to `from`, true the first time they ack it.
+    pub fn acked(&mut self, id: u64, from: &[u8]) -> bool {
+        let pending = match self.pending.get_mut(&id) {
+            Some(pending) => pending,
+            None => return false
+        };
+        let before = pending.outputs.len();
+        pending.outputs.retain(|output| output.0 != from);
+        let acked = pending.outputs.len() < before;
+
+        if pending.outputs.is_empty() {
+            self.pending.remove(&id);
+        }
+        return acked;
+    }
+
+    /// Messages whose acks are overdue, to be resent or given up on.
+    pub fn due(&mut self) -> Due {
+        let mut due = Due { resend: vec![], failed: vec![] };
+        let mut finished = vec![];
+
+        for (id, pending) in self.pending.iter_mut() {
+            if pending.sent.elapsed() < ACK_TIMEOUT {
+                continue;
+            }
+
+            if pending.attempts >= MESSAGE_ATTEMPTS {
+                due.failed.push((*id, pending.outputs.clone()));
+                finished.push(*id);
+            } else {
+                pending.attempts += 1;
+                pending.sent = Instant::now();
+                due.resend.push((pending.packet.clone(), pending.outputs.clone()));
+            }
+        }
+
+        for id in finished {
+            self.pending.remove(&id);
+        }
+        return due;
+    }
+
+    /// When `due` next has something to do.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        return self.pending.values()
+            .map(|pending| pending.sent + ACK_TIMEOUT)
+            .min();
+    }
+
+    /// Whether we've sent `output` a message.
+    pub fn is_contact(&self, output: &[u8]) -> bool {
+        return self.contacts.contains(output);
+    }
+
+    /// Whether we've already received the message, it isn't shown or acked again.
+    pub fn is_received(&self, from: &[u8], id: u64) -> bool {
+        return self.received.contains(&(from.to_vec(), id));
+    }
+
+    /// Counts an ack to `from`, false once they've had `ACKS_PER_WINDOW` of them in this window.
+    pub fn can_ack(&mut self, from: &[u8]) -> bool {
+        let now = Instant::now();
+        let (start, count) = self.acks.entry(from.to_vec()).or_insert((now, 0));
+        if now.duration_since(*start) >= ACK_WINDOW {
+            *start = now;
+            *count = 0;
+        }
+
+        if *count >= ACKS_PER_WINDOW {
+            return false;
+        }
+        *count += 1;
+        return true;
+    }
+
+    /// Remembers the message was received, so resends of it are ignored.
+    pub fn mark_received(&mut self, from: &[u8], id: u64) {
+        let key = (from.to_vec(), id);
+        if !self.received.insert(key.clone()) {
+            return;
+        }
+
+        self.received_order.push_back(key);
+        if self.received_order.len() > RECEIVED_MEMORY {
+            if let Some(oldest) = self.received_order.pop_front() {
+                self.received.remove(&oldest);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(id: u64) -> DataPacket {
+        return DataPacket::Text { id, from: vec![9], text: "hello".to_string() };
+    }
+
+    fn recipients() -> Vec<Script> {
+        return vec![Script(vec![1]), Script(vec![2])];
+    }
+
+    /// Makes every message's ack overdue.
+    fn wait_out(outbox: &mut Outbox) {
+        for pending in outbox.pending.values_mut() {
+            pending.sent = pending.sent.checked_sub(ACK_TIMEOUT).unwrap();
+        }
+    }
+
+    #[test]
+    fn marks_each_recipient_delivered_once() {
+        let mut outbox = Outbox::new();
+        outbox.add(1, text(1), recipients());
+
+        assert!(outbox.acked(1, &[1]));
+        assert!(!outbox.acked(1, &[1]));
+        assert!(!outbox.acked(1, &[3]));
+        assert!(!outbox.acked(2, &[2]));
+        assert!(outbox.next_deadline().is_some());
+        assert!(outbox.acked(1, &[2]));
+        assert!(outbox.next_deadline().is_none());
+        assert!(outbox.is_contact(&[1]) && outbox.is_contact(&[2]) && !outbox.is_contact(&[3]));
+    }
+
+    #[test]
+    fn resends_to_whoever_hasnt_acked() {
+        let mut outbox = Outbox::new();
+        outbox.add(1, text(1), recipients());
+        outbox.acked(1, &[1]);
+
+        assert!(outbox.due().resend.is_empty());
+        wait_out(&mut outbox);
+        let due = outbox.due();
+        assert!(due.failed.is_empty());
+        assert_eq!(due.resend.len(), 1);
+        assert!(matches!(due.resend[0].0, DataPacket::Text{ id: 1, .. }));
+        assert_eq!(due.resend[0].1, vec![Script(vec![2])]);
+        // The resend starts the wait over.
+        assert!(outbox.due().resend.is_empty());
+    }
+
+    #[test]
+    fn gives_up_after_the_last_attempt() {
+        let mut outbox = Outbox::new();
+        outbox.add(1, text(1), recipients());
+
+        for _ in 1..MESSAGE_ATTEMPTS {
+            wait_out(&mut outbox);
+            assert_eq!(outbox.due().resend.len(), 1);
+        }
+        wait_out(&mut outbox);
+        let due = outbox.due();
+        assert!(due.resend.is_empty());
+        assert_eq!(due.failed, vec![(1, recipients())]);
+        assert!(outbox.next_deadline().is_none());
+        assert!(!outbox.acked(1, &[1]));
+    }
+
+    #[test]
+    fn remembers_the_latest_messages_received() {
+        let mut outbox = Outbox::new();
+        outbox.mark_received(&[1], 1);
+
+        assert!(outbox.is_received(&[1], 1));
+        assert!(!outbox.is_received(&[2], 1));
+        assert!(!outbox.is_received(&[1], 2));
+
+        // Marking one twice doesn't take up more memory.
+        outbox.mark_received(&[1], 1);
+        for id in 2..=RECEIVED_MEMORY as u64 {
+            outbox.mark_received(&[1], id);
+        }
+        assert!(outbox.is_received(&[1], 1));
+        outbox.mark_received(&[2], 1);
+        assert!(!outbox.is_received(&[1], 1));
+        assert!(outbox.is_received(&[1], 2));
+        assert!(outbox.is_received(&[2], 1));
+    }
+
+    #[test]
+    fn limits_the_acks_each_sender_gets() {
+        let mut outbox = Outbox::new();
+
+        for _ in 0..ACKS_PER_WINDOW {
+            assert!(outbox.can_ack(&[1]));
+        }
+        assert!(!outbox.can_ack(&[1]));
+        assert!(outbox.can_ack(&[2]));
+
+        let (start, _) = outbox.acks.get_mut(&vec![1]).unwrap();
+        *start = start.checked_sub(ACK_WINDOW).unwrap();
+        assert!(outbox.can_ack(&[1]));
+    }
+}
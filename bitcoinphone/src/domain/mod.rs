@@ -0,0 +1,1194 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, sync_channel, SyncSender, TrySendError};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use sv::address::{addr_encode, AddressType};
+use sv::network::Network;
+use sv::script::Script;
+use sv::util::Hash160;
+
+use crate::{util, phone};
+use crate::net::NetworkInterface;
+use crate::phone::PhoneConfig;
+use crate::phone::recorder::Recorder;
+use crate::phone::stats::CallStats;
+use crate::phone::bitrate::BitrateController;
+use crate::phone::codec::{negotiate, SUPPORTED_CODECS};
+use crate::tx_sender::TxSender;
+use crate::util::constants::{CodecType, CommunicationsKey, DataPacket, UIEvent, Key};
+use crate::util::traits::Spawnable;
+use crate::tx_sender::keys::KeyManager;
+use sv::transaction::p2pkh::create_lock_script;
+use crate::crypto::{self, Role, Session};
+use call::{CallError, CallEvent, CallState};
+use clock::{ClockEstimate, ClockSample};
+use conference::{Conference, Leg, Route};
+use messages::Outbox;
+
+pub mod call;
+pub mod clock;
+pub mod conference;
+pub mod messages;
+
+pub const SYNC_CLICKS: u64 = 5;
+/// How long we wait for a start-ack before sending the start again.
+pub const START_ACK_TIMEOUT: Duration = Duration::from_secs(10);
+/// Times a start is sent before we give up on the peer.
+pub const START_ATTEMPTS: u32 = 3;
+/// How long an incoming call rings, as long as a caller keeps retrying its start.
+pub const RING_TIMEOUT: Duration = Duration::from_secs(30);
+pub const SYNC_TIMEOUT: Duration = Duration::from_secs(10);
+/// A peer we hear nothing from for this long is assumed gone.
+pub const PEER_TIMEOUT: Duration = Duration::from_secs(30);
+/// We send a keep-alive if we've sent nothing else for this long.
+pub const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(10);
+/// How often observers get the call's statistics while it runs.
+pub const STATS_INTERVAL: Duration = Duration::from_secs(5);
+/// Someone calling while we're busy is told so at most once in this long, each busy costs
+/// us a transaction.
+pub const BUSY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// What the peer answered our start with.
+struct PeerAnswer {
+    codec: CodecType,
+    codecs: Vec<CodecType>,
+    sample_rate: u32,
+    public_key: Vec<u8>,
+    salt: Vec<u8>,
+}
+
+pub struct Domain {
+    tx_sender: Arc<TxSender>,
+    network_receiver: Receiver<DataPacket>,
+    key_manager: KeyManager,
+    peer_address: Script,
+    /// What the last sync told us about the peer's clock and the network in between.
+    clock: Option<ClockEstimate>,
+    state: CallState,
+    observers: Vec<SyncSender<CallEvent>>,
+    /// Set once the user asks to quit, we stop after the current call.
+    quitting: bool,
+    /// Identifies the call in progress, every packet in it carries this.
+    session_id: u64,
+    /// Our half of the session key material for the call in progress.
+    salt: Vec<u8>,
+    /// Encrypts the call once both sides have swapped keys.
+    session: Option<Arc<Session>>,
+    /// Codecs the peer can decode, we can switch between these mid-call.
+    peer_codecs: Vec<CodecType>,
+    /// Calls we've set up so far as the host of a conference that hasn't started yet.
+    legs: Vec<Leg>,
+    /// Everyone else on the call when it's a conference.
+    conference: Option<Conference>,
+    /// Texts and attachments, these carry on from one call to the next.
+    outbox: Outbox,
+    /// When we last told each caller we were busy.
+    busy_sent: HashMap<Vec<u8>, Instant>,
+    /// Settings for the call in progress, negotiation changes these.
+    phone_config: PhoneConfig,
+    /// What every call starts out with.
+    default_phone_config: PhoneConfig,
+}
+
+impl Domain {
+    pub fn new(
+        tx_sender: Arc<TxSender>,
+        network_receiver: Receiver<DataPacket>,
+        key_manager: KeyManager,
+        phone_config: PhoneConfig
+    ) -> Domain {
+        return Domain {
+            tx_sender: tx_sender,
+            network_receiver,
+            peer_address: Script(vec![]),
+            key_manager,
+            clock: None,
+            state: CallState::Idle,
+            observers: vec![],
+            quitting: false,
+            session_id: 0,
+            salt: vec![],
+            session: None,
+            peer_codecs: vec![],
+            legs: vec![],
+            conference: None,
+            outbox: Outbox::new(),
+            busy_sent: HashMap::new(),
+            default_phone_config: phone_config.clone(),
+            phone_config
+        };
+    }
+
+    /// Events for every state change and failure from now on.
+    pub fn subscribe(&mut self) -> Receiver<CallEvent> {
+        let (sender, receiver) = sync_channel(100);
+        self.observers.push(sender);
+        return receiver;
+    }
+
+    pub fn get_state(&self) -> CallState {
+        return self.state;
+    }
+
+    /// Handles calls one after another until the user quits or the network goes away.
+    /// Messages are handled in between, and during calls too.
+    pub fn run(&mut self) {
+        while !self.quitting {
+            self.resend_messages();
+            let received = match self.outbox.next_deadline() {
+                Some(deadline) => self.network_receiver.recv_timeout(deadline.saturating_duration_since(Instant::now())),
+                None => self.network_receiver.recv().map_err(|_| RecvTimeoutError::Disconnected)
+            };
+            let packet = match received {
+                Ok(packet) => packet,
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => return
+            };
+            let packet = match self.handle_message(packet) {
+                Some(packet) => packet,
+                None => continue
+            };
+
+            match packet {
+                DataPacket::UIEvent(UIEvent::Start{ .. }) | DataPacket::Start{ .. } => {
+                    if let Err(err) = self.start_processing(packet) {
+                        self.notify(CallEvent::Failed(err));
+                    }
+                    self.reset();
+                }
+                DataPacket::UIEvent(UIEvent::Quit) => return,
+                // Stragglers from a call that has already ended.
+                packet => eprintln!("Ignoring {} packet while idle", call::describe(&packet))
+            }
+        }
+    }
+
+    pub fn start_processing(&mut self, packet: DataPacket) -> Result<(), CallError> {
+        let clock = match packet {
+            DataPacket::UIEvent(UIEvent::Start{ outputs }) => {
+                self.transition(CallState::Dialing)?;
+                self.tx_sender.clone().get_utxos();
+                if outputs.len() > 1 {
+                    self.host_conference(outputs)?
+                } else {
+                    self.peer_address = Script(outputs.into_iter().next().unwrap_or_default());
+                    self.run_sender()?
+                }
+            }
+            DataPacket::Start { output, session_id, codecs, sample_rate, frames_per_buffer, public_key, salt, .. } => {
+                verify_sender(&output, &public_key)?;
+                self.transition(CallState::Ringing)?;
+                self.peer_address = Script(output);
+                self.session_id = session_id;
+
+                // No point ringing for a call we couldn't play or can't encrypt.
+                self.salt = Session::new_salt();
+                let accepted = self.match_peer_audio(sample_rate, frames_per_buffer)
+                    .and_then(|_| self.derive_session(&public_key, &salt, Role::Callee));
+                let session = match accepted {
+                    Ok(session) => session,
+                    Err(err) => {
+                        self.send_to_peer(DataPacket::Reject{ session_id, reason: err.to_string() });
+                        return Err(err);
+                    }
+                };
+                self.phone_config.codec = negotiate(&codecs);
+                self.peer_codecs = codecs;
+
+                self.notify(CallEvent::IncomingCall{ caller: self.get_peer_address() });
+                self.wait_for_answer()?;
+
+                self.tx_sender.clone().get_utxos();
+                self.run_receiver(session)?
+            }
+            packet => {
+                return Err(CallError::UnexpectedPacket {
+                    state: self.state,
+                    packet: call::describe(&packet),
+                });
+            }
+        };
+
+        let playout_delay = clock.playout_delay();
+        eprintln!("Sync finished, running phone with a {} ms playout delay", playout_delay.as_millis());
+        self.clock = Some(clock);
+        self.transition(CallState::InCall)?;
+        let result = self.run_phone(playout_delay.as_nanos() as u64);
+
+        self.transition(CallState::Ended)?;
+        return result;
+    }
+
+    fn transition(&mut self, next: CallState) -> Result<(), CallError> {
+        let previous = self.state;
+        if !previous.can_transition_to(next) {
+            return Err(CallError::InvalidTransition { from: previous, to: next });
+        }
+
+        eprintln!("Call state {:?} -> {:?}", previous, next);
+        self.state = next;
+        self.notify(CallEvent::StateChanged { from: previous, to: next });
+        return Ok(());
+    }
+
+    /// Ends whatever call was in progress and gets ready for the next one.
+    fn reset(&mut self) {
+        if self.state.can_transition_to(CallState::Ended) {
+            self.transition(CallState::Ended)
+                .expect("Every call can end");
+        }
+        if self.state == CallState::Ended {
+            self.transition(CallState::Idle)
+                .expect("Ended calls go back to idle");
+        }
+
+        self.peer_address = Script(vec![]);
+        self.clock = None;
+        self.peer_codecs = vec![];
+        self.phone_config = self.default_phone_config.clone();
+        self.session_id = 0;
+        self.salt = vec![];
+        self.session = None;
+        self.legs = vec![];
+        self.conference = None;
+        self.tx_sender.set_session(None);
+        self.tx_sender.set_conference(vec![]);
+    }
+
+    fn notify(&mut self, event: CallEvent) {
+        // Observers that hang up are dropped, slow ones miss events rather than stall the call.
+        self.observers.retain(|observer| match observer.try_send(event.clone()) {
+            Err(TrySendError::Disconnected(_)) => false,
+            _ => true
+        });
+    }
+
+    /// Deals with packets that mean the same thing whatever stage the call is at, handing
+    /// back any the current stage has to handle itself.
+    fn handle_common(&mut self, packet: DataPacket) -> Result<Option<DataPacket>, CallError> {
+        let packet = match self.handle_message(packet) {
+            Some(packet) => packet,
+            None => return Ok(None)
+        };
+
+        return match packet {
+            // Sent signed while the call is being set up, anyone can sign one so only the
+            // peer's for this call count.
+            DataPacket::FromSender{ output, packet } => match *packet {
+                DataPacket::HangUp{ session_id } | DataPacket::Busy{ session_id } | DataPacket::Reject{ session_id, .. }
+                    if output != self.peer_address.0 || session_id != self.session_id => {
+                    eprintln!("Ignoring hang up or refusal for another call");
+                    Ok(None)
+                }
+                packet => self.handle_common(packet)
+            }
+            DataPacket::HangUp{ .. } => Err(CallError::HungUp),
+            DataPacket::Busy{ .. } => Err(CallError::Busy),
+            DataPacket::Reject{ reason, .. } => Err(CallError::Rejected(reason)),
+            DataPacket::KeepAlive => Ok(None),
+            DataPacket::UIEvent(UIEvent::HangUp) => {
+                self.send_to_peer(DataPacket::HangUp{ session_id: self.session_id });
+                Err(CallError::Cancelled)
+            }
+            DataPacket::UIEvent(UIEvent::Quit) => {
+                self.quitting = true;
+                self.send_to_peer(DataPacket::HangUp{ session_id: self.session_id });
+                Err(CallError::Cancelled)
+            }
+            DataPacket::Start{ output, session_id, public_key, .. } if output != self.peer_address.0 => {
+                self.busy_sent.retain(|_, sent| sent.elapsed() < BUSY_INTERVAL);
+                if verify_sender(&output, &public_key).is_err() {
+                    eprintln!("Ignoring start whose key doesn't match its output");
+                } else if !self.busy_sent.contains_key(&output) {
+                    eprintln!("Another peer is calling, telling them we're busy");
+                    self.busy_sent.insert(output.clone(), Instant::now());
+                    self.tx_sender.clone().send_data(self.sign(&DataPacket::Busy{ session_id }), &[Script(output)]);
+                }
+                Ok(None)
+            }
+            // Participants already on a conference we're still setting up.
+            DataPacket::FromParticipant{ session_id, packet } => {
+                match *packet {
+                    DataPacket::HangUp{ .. } => self.participant_left(session_id),
+                    // Their audio waits for the conference to start.
+                    packet => { self.handle_message(packet); }
+                }
+                Ok(None)
+            }
+            packet => Ok(Some(packet))
+        };
+    }
+
+    /// Sends, acks and shows texts and attachments, handing back any other packet.
+    fn handle_message(&mut self, packet: DataPacket) -> Option<DataPacket> {
+        match packet {
+            DataPacket::UIEvent(UIEvent::Text{ outputs, text }) => {
+                let id = Outbox::new_id();
+                self.send_message(DataPacket::Text{ id, from: self.get_comms_output(), text }, id, outputs);
+            }
+            DataPacket::UIEvent(UIEvent::Attachment{ outputs, name, data }) => {
+                let id = Outbox::new_id();
+                self.send_message(DataPacket::Attachment{ id, from: self.get_comms_output(), name, data }, id, outputs);
+            }
+            // Our own messages come back to us in the change output.
+            DataPacket::Text{ from, .. } | DataPacket::Attachment{ from, .. } | DataPacket::MessageAck{ from, .. }
+                if from == self.get_comms_output() => {}
+            DataPacket::Text{ id, from, text } => {
+                if self.receive_message(&from, id) {
+                    self.notify(CallEvent::TextReceived{ from: format_address(&Script(from)), text });
+                }
+            }
+            DataPacket::Attachment{ id, from, name, data } => {
+                if self.receive_message(&from, id) {
+                    self.notify(CallEvent::AttachmentReceived{ from: format_address(&Script(from)), name, data });
+                }
+            }
+            DataPacket::MessageAck{ id, from } => {
+                if self.outbox.acked(id, &from) {
+                    self.notify(CallEvent::MessageDelivered{ id, to: format_address(&Script(from)) });
+                }
+            }
+            packet => return Some(packet)
+        }
+
+        return None;
+    }
+
+    /// Whether a text or attachment is new and should be shown, acking it if so.
+    ///
+    /// Acks cost us a transaction, so they only go to people we've messaged or are on a
+    /// call with, and at most `ACKS_PER_WINDOW` of them a window. A message over that is dropped unseen, the sender sends it again.
+    fn receive_message(&mut self, from: &[u8], id: u64) -> bool {
+        if self.outbox.is_received(from, id) {
+            return false;
+        }
+
+        let on_call = self.state != CallState::Idle && self.get_peer_outputs().iter().any(|peer| peer.0 == from);
+        let known = on_call || self.outbox.is_contact(from);
+        if known && !self.outbox.can_ack(from) {
+            eprintln!("Too many messages from {}, dropping one", format_address(&Script(from.to_vec())));
+            return false;
+        }
+
+        self.outbox.mark_received(from, id);
+        if known {
+            self.post(DataPacket::MessageAck{ id, from: self.get_comms_output() }, &[Script(from.to_vec())]);
+        }
+        return true;
+    }
+
+    /// Sends a new message to the outputs, or everyone on the call when there are none.
+    fn send_message(&mut self, packet: DataPacket, id: u64, outputs: Vec<Vec<u8>>) {
+        let outputs = if !outputs.is_empty() {
+            outputs.into_iter().map(Script).collect()
+        } else if self.state != CallState::Idle {
+            self.get_peer_outputs()
+        } else {
+            eprintln!("Not on a call, say who the message is for");
+            return;
+        };
+
+        self.post(packet.clone(), &outputs);
+        self.outbox.add(id, packet, outputs);
+        self.notify(CallEvent::MessageSent{ id });
+    }
+
+    /// Sends messages and acks signed, so everyone can tell they're from us. They're sealed
+    /// too when they're only for people on the call, anyone else gets them in the clear.
+    fn post(&self, packet: DataPacket, outputs: &[Script]) {
+        let peers = self.get_peer_outputs();
+        let packet = self.sign(&packet);
+        let packet = match &self.session {
+            Some(session) if outputs.iter().all(|output| peers.iter().any(|peer| peer.0 == output.0)) => session.seal(&packet),
+            _ => packet
+        };
+
+        // Between calls nothing else has fetched funds to send with.
+        if self.tx_sender.get_funds_left() <= 0 {
+            self.tx_sender.clone().get_utxos();
+        }
+        self.tx_sender
+            .clone()
+            .send_data(packet, outputs);
+    }
+
+    /// Sends messages again that haven't been acked, giving up after `MESSAGE_ATTEMPTS`.
+    fn resend_messages(&mut self) {
+        let due = self.outbox.due();
+        for (packet, outputs) in due.resend {
+            eprintln!("No ack for {} yet, sending it again", call::describe(&packet));
+            self.post(packet, &outputs);
+        }
+        for (id, outputs) in due.failed {
+            for output in outputs {
+                self.notify(CallEvent::MessageFailed{ id, to: format_address(&output) });
+            }
+        }
+    }
+
+    /// Sends to the peer, or everyone else on a conference, encrypted once the session is up
+    /// and signed until then.
+    fn send_to_peer(&self, packet: DataPacket) {
+        let packet = match &self.session {
+            Some(session) => session.seal(&packet),
+            None => self.sign(&packet)
+        };
+
+        self.tx_sender
+            .clone()
+            .send_data(packet, &self.get_peer_outputs());
+    }
+
+    fn get_peer_outputs(&self) -> Vec<Script> {
+        return match &self.conference {
+            Some(conference) => conference.get_outputs(),
+            None => vec![self.peer_address.clone()]
+        };
+    }
+
+    /// Where the mic thread sends to, for the call as it stands.
+    fn route(&self) -> Route {
+        return Route {
+            session: self.session.clone(),
+            outputs: self.get_peer_outputs(),
+            codec: self.conference.as_ref().map(|_| self.phone_config.codec),
+        };
+    }
+
+    /// Calls everyone in turn, then tells them all about each other. Anyone who doesn't
+    /// answer is left out, the conference goes ahead as long as someone does.
+    fn host_conference(&mut self, outputs: Vec<Vec<u8>>) -> Result<ClockEstimate, CallError> {
+        let mut last_error = CallError::NoAnswer { attempts: START_ATTEMPTS };
+        for output in outputs {
+            if self.state != CallState::Dialing {
+                self.transition(CallState::Dialing)?;
+            }
+            self.peer_address = Script(output);
+
+            match self.run_sender() {
+                Ok(clock) => {
+                    let session = self.session.take()
+                        .expect("Synced calls have a session");
+                    self.legs.push(Leg {
+                        output: self.peer_address.clone(),
+                        session,
+                        sample_rate: self.phone_config.remote_sample_rate as u32,
+                        clock,
+                    });
+                }
+                Err(err @ CallError::Cancelled) | Err(err @ CallError::Disconnected) => {
+                    self.hang_up_legs();
+                    return Err(err);
+                }
+                Err(err) => {
+                    // Don't leave a half set up call waiting on us.
+                    if self.session.is_some() {
+                        self.send_to_peer(DataPacket::HangUp{ session_id: self.session_id });
+                        self.session = None;
+                    }
+                    eprintln!("{} didn't join the conference: {}", self.get_peer_address(), err);
+                    self.notify(CallEvent::ParticipantFailed { participant: self.get_peer_address(), error: err.clone() });
+                    last_error = err;
+                }
+            }
+
+            // Whoever has joined waits for the rest, their packets come in tagged meanwhile.
+            self.tx_sender.set_session(None);
+            self.tx_sender.set_conference(self.legs.iter().map(|leg| leg.session.clone()).collect());
+        }
+
+        if self.legs.is_empty() {
+            return Err(last_error);
+        }
+        if self.state == CallState::Dialing {
+            self.transition(CallState::Syncing)?;
+        }
+
+        return Ok(self.start_conference());
+    }
+
+    /// Hands everyone who answered the conference's secret and who's on it, then joins it
+    /// ourselves. Returns the clock of the furthest participant, everyone's audio has to
+    /// wait as long as theirs.
+    fn start_conference(&mut self) -> ClockEstimate {
+        let legs = std::mem::replace(&mut self.legs, vec![]);
+        let session_id = Session::new_id();
+        let secret = Session::new_secret();
+        let participants = std::iter::once(self.get_comms_output())
+            .chain(legs.iter().map(|leg| leg.output.0.clone()))
+            .collect::<Vec<Vec<u8>>>();
+        let sample_rates = std::iter::once(self.phone_config.sample_rate as u32)
+            .chain(legs.iter().map(|leg| leg.sample_rate))
+            .collect::<Vec<u32>>();
+
+        let invite = |index: usize| DataPacket::Conference {
+            session_id,
+            index: index as u32,
+            secret: secret.clone(),
+            participants: participants.clone(),
+            sample_rates: sample_rates.clone(),
+        };
+        for (index, leg) in legs.iter().enumerate() {
+            self.tx_sender
+                .clone()
+                .send_data(leg.session.seal(&invite(index + 1)), &[leg.output.clone()]);
+        }
+
+        self.join_conference(invite(0))
+            .expect("Our own invite is valid");
+        return legs.into_iter()
+            .map(|leg| leg.clock)
+            .max_by_key(ClockEstimate::playout_delay)
+            .expect("Conferences have someone on them");
+    }
+
+    fn join_conference(&mut self, invite: DataPacket) -> Result<(), CallError> {
+        let conference = Conference::join(invite)?;
+        self.tx_sender.set_session(None);
+        self.tx_sender.set_conference(conference.get_others());
+        self.session = Some(conference.get_session());
+
+        let participants = conference.get_outputs()
+            .iter()
+            .map(format_address)
+            .collect::<Vec<String>>();
+        eprintln!("Conference with {}", participants.join(", "));
+        self.conference = Some(conference);
+        self.notify(CallEvent::ConferenceStarted { participants });
+        return Ok(());
+    }
+
+    fn participant_left(&mut self, session_id: u64) {
+        let left = match &mut self.conference {
+            Some(conference) => conference.leave(session_id),
+            None => {
+                let leg = self.legs.iter().position(|leg| leg.session.get_id() == session_id);
+                leg.map(|leg| self.legs.remove(leg).output)
+            }
+        };
+
+        if let Some(output) = left {
+            eprintln!("{} left the conference", format_address(&output));
+            self.notify(CallEvent::ParticipantLeft { participant: format_address(&output) });
+        }
+    }
+
+    /// Keeps whoever has joined a conference we're still setting up from giving up on us.
+    fn keep_legs_alive(&self) {
+        self.send_to_legs(|_| DataPacket::KeepAlive);
+    }
+
+    fn hang_up_legs(&mut self) {
+        self.send_to_legs(|session_id| DataPacket::HangUp{ session_id });
+        self.legs = vec![];
+    }
+
+    /// Sends to every leg in one transaction, each sealed with its own session. `packet`
+    /// makes a leg's packet from its session id.
+    fn send_to_legs(&self, packet: impl Fn(u64) -> DataPacket) {
+        if self.legs.is_empty() {
+            return;
+        }
+
+        let packets = self.legs.iter()
+            .map(|leg| leg.session.seal(&packet(leg.session.get_id())))
+            .collect();
+        let outputs = self.legs.iter()
+            .map(|leg| leg.output.clone())
+            .collect::<Vec<Script>>();
+        self.tx_sender
+            .clone()
+            .send_batch(packets, &outputs);
+    }
+
+    fn derive_session(&self, peer_public_key: &[u8], peer_salt: &[u8], role: Role) -> Result<Arc<Session>, CallError> {
+        let Key{ secret_key, .. } = self.key_manager.get_key(CommunicationsKey);
+        let (caller_salt, callee_salt) = match role {
+            Role::Caller => (&self.salt[..], peer_salt),
+            Role::Callee => (peer_salt, &self.salt[..]),
+        };
+
+        let session = Session::derive(self.session_id, &secret_key, peer_public_key, caller_salt, callee_salt, role)
+            .map_err(|err| CallError::BadPacket(err.to_string()))?;
+        return Ok(Arc::new(session));
+    }
+
+    /// From here on we only talk to the peer through the session.
+    fn use_session(&mut self, session: Arc<Session>) {
+        self.tx_sender.set_session(Some(session.clone()));
+        self.session = Some(session);
+    }
+
+    /// Signs a packet with our communications key, for anything sent outside a session.
+    fn sign(&self, packet: &DataPacket) -> DataPacket {
+        return crypto::sign(packet, &self.key_manager.get_key(CommunicationsKey).secret_key);
+    }
+
+    fn get_public_key(&self) -> Vec<u8> {
+        return self.key_manager.get_key(CommunicationsKey).public_key.serialize().to_vec();
+    }
+
+    /// Rings until the user accepts, declines or lets it ring out.
+    fn wait_for_answer(&mut self) -> Result<(), CallError> {
+        eprintln!("Incoming call from {}", self.get_peer_address());
+        let deadline = Instant::now() + RING_TIMEOUT;
+
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            match self.network_receiver.recv_timeout(remaining) {
+                Ok(DataPacket::UIEvent(UIEvent::Accept)) => return Ok(()),
+                Ok(DataPacket::UIEvent(UIEvent::Decline)) | Ok(DataPacket::UIEvent(UIEvent::HangUp)) => {
+                    self.send_to_peer(DataPacket::Reject{ session_id: self.session_id, reason: "Declined".to_string() });
+                    return Err(CallError::Declined);
+                }
+                Ok(packet) => {
+                    // Retried starts from the caller just mean it is still ringing.
+                    if let Some(packet) = self.handle_common(packet)? {
+                        eprintln!("Ignoring {} packet while ringing", call::describe(&packet));
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    self.send_to_peer(DataPacket::Reject{ session_id: self.session_id, reason: "No answer".to_string() });
+                    return Err(CallError::Timeout(CallState::Ringing));
+                }
+                Err(RecvTimeoutError::Disconnected) => return Err(CallError::Disconnected),
+            }
+        }
+    }
+
+    fn run_receiver(&mut self, session: Arc<Session>) -> Result<ClockEstimate, CallError> {
+        // The caller seals everything after our start-ack, so the network side has to be
+        // ready for it first. We can't seal anything until the caller has the ack.
+        self.tx_sender.set_session(Some(session.clone()));
+        self.send_start_ack();
+        self.session = Some(session);
+        self.transition(CallState::Syncing)?;
+
+        return self.sync_clocks(false);
+    }
+
+    fn send_start_ack(&self) {
+        self.tx_sender
+            .clone()
+            .send_data(
+                DataPacket::StartAck{
+                    output: self.get_comms_output(),
+                    session_id: self.session_id,
+                    sync_count: SYNC_CLICKS,
+                    codec: self.phone_config.codec,
+                    sample_rate: self.phone_config.sample_rate as u32,
+                    frames_per_buffer: self.phone_config.frames_per_buffer,
+                    public_key: self.get_public_key(),
+                    salt: self.salt.clone(),
+                    codecs: SUPPORTED_CODECS.to_vec(),
+                },
+                &[self.peer_address.clone()]
+            );
+    }
+
+    fn run_sender(&mut self) -> Result<ClockEstimate, CallError> {
+        self.session_id = Session::new_id();
+        self.salt = Session::new_salt();
+        let start = DataPacket::Start{
+            output: self.get_comms_output(),
+            session_id: self.session_id,
+            sync_count: SYNC_CLICKS,
+            codecs: SUPPORTED_CODECS.to_vec(),
+            sample_rate: self.phone_config.sample_rate as u32,
+            frames_per_buffer: self.phone_config.frames_per_buffer,
+            public_key: self.get_public_key(),
+            salt: self.salt.clone(),
+        };
+
+        let answer = self.dial(start)?;
+        eprintln!("Peer chose codec {:?} at {} Hz", answer.codec, answer.sample_rate);
+        if answer.sample_rate == 0 {
+            return Err(CallError::BadPacket("Peer sent a sample rate of 0 Hz".to_string()));
+        }
+        self.phone_config.codec = answer.codec;
+        self.peer_codecs = answer.codecs;
+        self.phone_config.remote_sample_rate = answer.sample_rate as f64;
+        match self.derive_session(&answer.public_key, &answer.salt, Role::Caller) {
+            Ok(session) => self.use_session(session),
+            Err(err) => {
+                self.send_to_peer(DataPacket::HangUp{ session_id: self.session_id });
+                return Err(err);
+            }
+        }
+
+        self.transition(CallState::Syncing)?;
+        return self.sync_clocks(true);
+    }
+
+    /// Sends the start until the peer acknowledges it.
+    fn dial(&mut self, start: DataPacket) -> Result<PeerAnswer, CallError> {
+        for attempt in 1..=START_ATTEMPTS {
+            if attempt > 1 {
+                eprintln!("No start-ack from peer, retrying ({}/{})", attempt, START_ATTEMPTS);
+                self.notify(CallEvent::Retrying { attempt });
+            }
+
+            self.keep_legs_alive();
+            self.tx_sender
+                .clone()
+                .send_data(start.clone(), &[self.peer_address.clone()]);
+
+            let deadline = Instant::now() + START_ACK_TIMEOUT;
+            loop {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                match self.network_receiver.recv_timeout(remaining) {
+                    Ok(DataPacket::StartAck{ session_id, .. }) if session_id != self.session_id => {
+                        eprintln!("Ignoring start-ack for another call");
+                    }
+                    // Anyone watching the chain can answer our start, only the peer can
+                    // answer it with the key their address pays.
+                    Ok(DataPacket::StartAck{ output, public_key, .. })
+                        if output != self.peer_address.0 || verify_sender(&output, &public_key).is_err() => {
+                        eprintln!("Ignoring start-ack that isn't from the peer");
+                    }
+                    Ok(DataPacket::StartAck{ codec, codecs, sample_rate, public_key, salt, .. }) => {
+                        return Ok(PeerAnswer { codec, codecs, sample_rate, public_key, salt });
+                    }
+                    Ok(packet) => {
+                        if let Some(packet) = self.handle_common(packet)? {
+                            eprintln!("Ignoring {} packet while dialing", call::describe(&packet));
+                        }
+                    }
+                    Err(RecvTimeoutError::Timeout) => break,
+                    Err(RecvTimeoutError::Disconnected) => return Err(CallError::Disconnected),
+                }
+            }
+        }
+
+        // Stop the peer ringing for a call we've given up on.
+        self.send_to_peer(DataPacket::HangUp{ session_id: self.session_id });
+        return Err(CallError::NoAnswer { attempts: START_ATTEMPTS });
+    }
+
+    /// Takes the caller's sample rate and keeps our frames the same length in time as
+    /// theirs, so one of their frames resamples into one of ours.
+    fn match_peer_audio(&mut self, sample_rate: u32, frames_per_buffer: u32) -> Result<(), CallError> {
+        if sample_rate == 0 || frames_per_buffer == 0 {
+            return Err(CallError::BadPacket(format!(
+                "Peer sent an invalid audio format {} Hz / {} frames",
+                sample_rate,
+                frames_per_buffer
+            )));
+        }
+
+        let frame_seconds = frames_per_buffer as f64 / sample_rate as f64;
+        self.phone_config.remote_sample_rate = sample_rate as f64;
+        self.phone_config.frames_per_buffer = (frame_seconds * self.phone_config.sample_rate).round() as u32;
+        eprintln!(
+            "Peer sends {} Hz in {} ms frames",
+            sample_rate,
+            (frame_seconds * 1000.0).round()
+        );
+        return Ok(());
+    }
+
+    /// Times round trips to the peer with syncs while answering the peer's own, until both
+    /// sides have sent `SYNC_CLICKS` of them. The callee waits for the caller's first sync
+    /// before sending any, until then the caller can't read them.
+    fn sync_clocks(&mut self, probe_first: bool) -> Result<ClockEstimate, CallError> {
+        eprintln!("Syncing clocks with peer.");
+        let mut samples = vec![];
+        let mut probing = probe_first;
+        let mut next_count = Some(SYNC_CLICKS - 1);
+        // Count, time sent and reply deadline of the sync we're timing.
+        let mut waiting: Option<(u64, u128, Instant)> = None;
+        let mut heard_peer = false;
+        let mut peer_done = false;
+        let mut acks_resent = 0;
+
+        loop {
+            if let (true, None, Some(count)) = (probing, waiting, next_count) {
+                let time = util::get_timestamp().as_nanos();
+                eprintln!("Sending sync count={}", count);
+                self.send_to_peer(DataPacket::Sync{ time, count });
+                waiting = Some((count, time, Instant::now() + SYNC_TIMEOUT));
+                next_count = count.checked_sub(1);
+            }
+
+            let deadline = waiting
+                .map(|(_, _, deadline)| deadline)
+                .unwrap_or_else(|| Instant::now() + SYNC_TIMEOUT);
+            let packet = match self.network_receiver.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                Ok(packet) => self.handle_common(packet)?,
+                Err(RecvTimeoutError::Timeout) => {
+                    if let Some((count, ..)) = waiting.take() {
+                        eprintln!("No reply to sync count={}", count);
+                    } else if heard_peer && next_count.is_none() {
+                        // Our syncs are done and the peer's last one must have been lost.
+                        break;
+                    } else {
+                        return Err(CallError::Timeout(self.state));
+                    }
+                    None
+                }
+                Err(RecvTimeoutError::Disconnected) => return Err(CallError::Disconnected),
+            };
+            let arrived = util::get_timestamp().as_nanos();
+
+            match packet {
+                Some(DataPacket::Sync { time, count }) => {
+                    eprintln!("Got sync count={}", count);
+                    self.send_to_peer(DataPacket::SyncReply {
+                        count,
+                        origin: time,
+                        received: arrived,
+                        time: util::get_timestamp().as_nanos(),
+                    });
+                    heard_peer = true;
+                    probing = true;
+                    peer_done |= count == 0;
+                }
+                Some(DataPacket::SyncReply { count, origin, received, time }) => match waiting {
+                    Some((expected, sent, _)) if count == expected && origin == sent => {
+                        samples.push(ClockSample::new(origin, received, time, arrived));
+                        waiting = None;
+                    }
+                    _ => eprintln!("Ignoring stale sync reply count={}", count),
+                },
+                Some(DataPacket::Start { session_id, .. }) if session_id != self.session_id => {
+                    eprintln!("Ignoring start for another call");
+                }
+                // The caller didn't get our start-ack and is retrying.
+                Some(DataPacket::Start { .. }) if acks_resent + 1 < START_ATTEMPTS => {
+                    eprintln!("Peer sent start again, resending start-ack");
+                    acks_resent += 1;
+                    self.send_start_ack();
+                }
+                // A retried start crossed with our ack, we already have what we need.
+                Some(DataPacket::StartAck { .. }) | None => {}
+                Some(packet) => {
+                    return Err(CallError::UnexpectedPacket {
+                        state: self.state,
+                        packet: call::describe(&packet),
+                    });
+                }
+            }
+
+            if next_count.is_none() && waiting.is_none() && peer_done {
+                break;
+            }
+        }
+
+        let clock = ClockEstimate::from_samples(&samples)
+            .ok_or(CallError::Timeout(self.state))?;
+        eprintln!(
+            "Peer clock offset {:+.1} ms (within {:.1} ms), delay {:.1} to {:.1} ms, jitter {:.1} ms from {} syncs",
+            clock.offset_nanos / 1e6,
+            clock.offset_error_nanos / 1e6,
+            clock.delay_nanos / 1e6,
+            clock.max_delay_nanos / 1e6,
+            clock.jitter_nanos / 1e6,
+            clock.samples
+        );
+
+        return Ok(clock);
+    }
+
+    /// Runs the call until either side hangs up or the peer goes quiet, then tears the
+    /// phone down.
+    pub fn run_phone(&mut self, jitter_delay_nanos: u64) -> Result<(), CallError> {
+        // Move tx_sender out of the struct since we need it in a seperate thread.
+        let cloned_sender = self.tx_sender.clone();
+        let route = Arc::new(RwLock::new(self.route()));
+        let cloned_route = route.clone();
+        let last_sent = Arc::new(Mutex::new(Instant::now()));
+        let cloned_last_sent = last_sent.clone();
+
+        let config = PhoneConfig{
+            jitter_delay_nanos,
+            ..self.phone_config.clone()
+        };
+        let recorder = self.start_recording(&config);
+        let stats = Arc::new(CallStats::new(
+            Duration::from_secs_f64(config.frames_per_buffer as f64 / config.sample_rate),
+            self.clock.as_ref()
+                .map(|clock| Duration::from_nanos(clock.delay_nanos as u64))
+                .unwrap_or_default()
+        ));
+        let cloned_stats = stats.clone();
+        let cloned_recorder = recorder.clone();
+
+        let mut controller = BitrateController::new(&config, &self.peer_codecs);
+        controller.set_receivers(self.get_peer_outputs().len());
+        controller.update(&stats.snapshot(), self.tx_sender.get_funds_left());
+        let bitrate = Arc::new(Mutex::new(controller.get_settings()));
+        let cloned_bitrate = bitrate.clone();
+        eprintln!("Sending with {:?}", controller.get_settings());
+
+        let (mic_sender, mic_receiver) = sync_channel(1000);
+        let speaker_sender = match phone::Phone::new(config, mic_sender, recorder.clone(), stats.clone(), bitrate.clone()) {
+            Ok(sender) => sender,
+            Err(err) => {
+                self.send_to_peer(DataPacket::HangUp{ session_id: self.session_id });
+                return Err(CallError::Audio(err.to_string()));
+            }
+        };
+        if let Some(conference) = &self.conference {
+            speaker_sender.send(conference.get_invite().clone())
+                .expect("Phone is running");
+        }
+
+        // Ends once the phone has shut its streams and dropped the mic sender.
+        let mic_thread = thread::spawn(move || {
+            let send = |batch: Vec<(Option<u32>, DataPacket)>| {
+                let (counters, packets): (Vec<Option<u32>>, Vec<DataPacket>) = batch.into_iter().unzip();
+                let outputs = cloned_route.read().unwrap().outputs.clone();
+                let txids = cloned_sender.clone()
+                    .send_batch(packets, &outputs);
+                *cloned_last_sent.lock().unwrap() = Instant::now();
+
+                for (counter, txid) in counters.into_iter().zip(txids) {
+                    if let Some(counter) = counter {
+                        cloned_stats.frame_sent();
+                        if let Some(recorder) = &cloned_recorder {
+                            recorder.frame_sent(counter, txid.encode());
+                        }
+                    }
+                }
+            };
+
+            // Frames wait here until there are enough to fill a transaction.
+            let mut batch = vec![];
+            let mut batched_frames = 0;
+            loop {
+                let packet = match mic_receiver.recv() {
+                    Ok(packet) => packet,
+                    Err(_) => break
+                };
+
+                let counter = match &packet {
+                    DataPacket::Data{ counter, .. } => Some(*counter),
+                    _ => None
+                };
+                // Repair frames go with the frames around them, a silence marker means no
+                // more frames are coming for a while.
+                let can_wait = match &packet {
+                    DataPacket::Data{ .. } | DataPacket::Repair{ .. } => true,
+                    _ => false
+                };
+                let packet = cloned_route.read().unwrap().seal(packet);
+                batch.push((counter, packet));
+                if counter.is_some() {
+                    batched_frames += 1;
+                }
+
+                let frames_per_tx = cloned_bitrate.lock().unwrap().frames_per_tx;
+                if can_wait && batched_frames > 0 && batched_frames < frames_per_tx {
+                    continue;
+                }
+                send(std::mem::replace(&mut batch, vec![]));
+                batched_frames = 0;
+            }
+
+            if !batch.is_empty() {
+                send(batch);
+            }
+        });
+
+        let mut last_heard = Instant::now();
+        let mut last_stats = Instant::now();
+        let result = loop {
+            let until_timeout = PEER_TIMEOUT
+                .checked_sub(last_heard.elapsed())
+                .unwrap_or(Duration::from_secs(0));
+
+            match self.network_receiver.recv_timeout(until_timeout.min(STATS_INTERVAL)) {
+                Ok(DataPacket::HangUp{ .. }) => {
+                    eprintln!("Peer hung up");
+                    break Ok(());
+                }
+                Ok(DataPacket::UIEvent(UIEvent::HangUp)) => {
+                    self.send_to_peer(DataPacket::HangUp{ session_id: self.session_id });
+                    break Ok(());
+                }
+                Ok(DataPacket::UIEvent(UIEvent::Quit)) => {
+                    self.quitting = true;
+                    self.send_to_peer(DataPacket::HangUp{ session_id: self.session_id });
+                    break Ok(());
+                }
+                Ok(DataPacket::FromParticipant{ session_id, packet }) => {
+                    last_heard = Instant::now();
+                    if let Some(conference) = &mut self.conference {
+                        conference.heard(session_id);
+                    }
+
+                    match *packet {
+                        DataPacket::HangUp{ .. } => {
+                            self.participant_left(session_id);
+                            *route.write().unwrap() = self.route();
+                        }
+                        packet @ DataPacket::Data{ .. } | packet @ DataPacket::Silence{ .. } | packet @ DataPacket::Repair{ .. } => {
+                            let packet = DataPacket::FromParticipant{ session_id, packet: Box::new(packet) };
+                            if speaker_sender.send(packet).is_err() {
+                                break Err(CallError::Disconnected);
+                            }
+                        }
+                        DataPacket::KeepAlive => {}
+                        packet => if let Some(packet) = self.handle_message(packet) {
+                            eprintln!("Ignoring {} packet from participant", call::describe(&packet));
+                        }
+                    }
+                }
+                // Sealed by the host, so it's what they want us on from now on.
+                Ok(invite @ DataPacket::Conference{ .. }) => {
+                    last_heard = Instant::now();
+                    match self.join_conference(invite.clone()) {
+                        Ok(()) => {
+                            *route.write().unwrap() = self.route();
+                            if speaker_sender.send(invite).is_err() {
+                                break Err(CallError::Disconnected);
+                            }
+                        }
+                        Err(err) => eprintln!("Ignoring conference invite: {}", err)
+                    }
+                }
+                // Refusals from calls we didn't make, nobody on a conference signs these.
+                Ok(DataPacket::FromSender{ .. }) if self.conference.is_some() => {
+                    eprintln!("Ignoring signed refusal during conference");
+                }
+                Ok(packet) => match self.handle_common(packet) {
+                    Ok(Some(packet)) => {
+                        last_heard = Instant::now();
+                        match packet {
+                            DataPacket::Data{ .. } | DataPacket::Silence{ .. } | DataPacket::Repair{ .. } => {
+                                if speaker_sender.send(packet).is_err() {
+                                    break Err(CallError::Disconnected);
+                                }
+                            }
+                            packet => eprintln!("Ignoring {} packet during call", call::describe(&packet))
+                        }
+                    }
+                    Ok(None) => last_heard = Instant::now(),
+                    Err(err) => break Err(err)
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => break Err(CallError::Disconnected),
+            }
+
+            self.resend_messages();
+            if last_heard.elapsed() >= PEER_TIMEOUT {
+                eprintln!("Heard nothing from peer for {} seconds", PEER_TIMEOUT.as_secs());
+                // Their packets might only be held up, don't leave them on a call we've left.
+                self.send_to_peer(DataPacket::HangUp{ session_id: self.session_id });
+                break Err(CallError::Timeout(CallState::InCall));
+            }
+
+            if let Some(conference) = &mut self.conference {
+                let quiet = conference.drop_quiet(PEER_TIMEOUT);
+                for output in &quiet {
+                    eprintln!("Heard nothing from {} for {} seconds", format_address(output), PEER_TIMEOUT.as_secs());
+                    self.notify(CallEvent::ParticipantLeft { participant: format_address(output) });
+                }
+                if !quiet.is_empty() {
+                    *route.write().unwrap() = self.route();
+                }
+            }
+            if self.conference.as_ref().map(Conference::is_empty).unwrap_or(false) {
+                eprintln!("Everyone else has left the conference");
+                break Ok(());
+            }
+
+            let idle = last_sent.lock().unwrap().elapsed();
+            if idle >= KEEPALIVE_INTERVAL {
+                self.send_to_peer(DataPacket::KeepAlive);
+                *last_sent.lock().unwrap() = Instant::now();
+            }
+
+            if last_stats.elapsed() >= STATS_INTERVAL {
+                stats.set_sats_spent(self.tx_sender.get_sats_spent());
+                let snapshot = stats.snapshot();
+                controller.set_receivers(route.read().unwrap().outputs.len());
+                if let Some(settings) = controller.update(&snapshot, self.tx_sender.get_funds_left()) {
+                    eprintln!("Switching to {:?}", settings);
+                    *bitrate.lock().unwrap() = settings;
+                }
+                self.notify(CallEvent::Stats(snapshot));
+                last_stats = Instant::now();
+            }
+        };
+
+        self.transition(CallState::HangingUp)?;
+
+        // Dropping the speaker sender stops the phone, wait for its streams to close so
+        // the devices are free for the next call.
+        drop(speaker_sender);
+        mic_thread.join()
+            .expect("Mic thread panicked");
+
+        stats.set_sats_spent(self.tx_sender.get_sats_spent());
+        let summary = stats.snapshot();
+        if let Some(recorder) = &recorder {
+            recorder.set_summary(summary.clone());
+        }
+        self.notify(CallEvent::Summary(summary));
+
+        return result;
+    }
+
+    /// A recorder for the call if we're recording. A recording that can't be started is
+    /// logged and the call goes ahead without it.
+    fn start_recording(&self, config: &PhoneConfig) -> Option<Arc<Recorder>> {
+        let directory = config.recording.as_ref()?;
+        let recorder = match Recorder::create(directory, self.get_peer_address(), config) {
+            Ok(recorder) => Arc::new(recorder),
+            Err(err) => {
+                eprintln!("Unable to record call in {}: {}", directory.display(), err);
+                return None;
+            }
+        };
+
+        // Txids of the frames we receive come from the network side.
+        let (frame_sender, frame_receiver) = sync_channel(1000);
+        self.tx_sender.watch_frames(frame_sender);
+
+        // Only a weak reference, so the recording is finished off once the call is over.
+        let cloned_recorder = Arc::downgrade(&recorder);
+        thread::spawn(move || loop {
+            match (frame_receiver.recv(), cloned_recorder.upgrade()) {
+                (Ok(frame), Some(recorder)) => recorder.frame_received(frame.counter, frame.txid.encode()),
+                _ => break
+            }
+        });
+
+        return Some(recorder);
+    }
+
+    fn get_peer_address(&self) -> String {
+        return format_address(&self.peer_address);
+    }
+
+    /// Where peers reach us, it pays the key we derive sessions with.
+    fn get_comms_output(&self) -> Vec<u8> {
+        let Key{pubkeyhash, ..} = self.key_manager.get_key(CommunicationsKey);
+
+        return create_lock_script(&pubkeyhash).0;
+    }
+}
+
+/// Checks the key in a start or start-ack is the one its output pays. Only the owner of
+/// that output can then read what we seal for it, or seal anything we'll open.
+fn verify_sender(output: &[u8], public_key: &[u8]) -> Result<(), CallError> {
+    if crypto::output_of(public_key) != output {
+        return Err(CallError::BadPacket("Key doesn't belong to the sender's output".to_string()));
+    }
+    return Ok(());
+}
+
+/// An output's address as shown to users, or the raw script if it isn't P2PKH.
+fn format_address(output: &Script) -> String {
+    let script = &output.0;
+    if script.len() != 25 {
+        return hex::encode(script);
+    }
+
+    let mut pubkeyhash = Hash160::default();
+    pubkeyhash.0.clone_from_slice(&script[3..23]);
+    return addr_encode(&pubkeyhash, AddressType::P2PKH, Network::Mainnet);
+}
\ No newline at end of file
@@ -3,11 +3,19 @@ use domain::Domain;
 use crate::util::traits::Spawnable;
 use crate::net::NetworkInterface;
 use std::sync::mpsc::{sync_channel, RecvError};
-use crate::ui::start;
-use crate::util::constants::DataPacket;
+use crate::ui::{start, watch_calls};
+use crate::ui::cli::{self, Command, Output, watch_headless};
+use crate::util::constants::{DataPacket, UIEvent};
 use crate::tx_sender::keys::{Wallet, KeyManager};
 use crate::tx_sender::TxSender;
+use crate::phone::backend::{AudioDevices, BackendConfig, DeviceSelector, PortAudioBackend};
+use crate::phone::PhoneConfig;
+use crate::phone::fec::{FecConfig, MAX_GROUP_SIZE};
+use crate::util::constants::SampleFormat;
+use std::path::PathBuf;
 
+mod crypto;
+mod wire;
 mod domain;
 mod phone;
 mod net;
@@ -16,14 +24,52 @@ mod util;
 mod tx_sender;
 
 fn main() {
+    let args = std::env::args().collect::<Vec<String>>();
+    if args.iter().any(|arg| arg == "--list-devices") {
+        list_devices();
+        return;
+    }
+    if args.iter().any(|arg| arg == "--help") {
+        println!("{}", cli::USAGE);
+        return;
+    }
+
+    let output = Output::from_args(&args);
+    match Command::parse(&args) {
+        Command::Init => cli::init(output),
+        Command::Address => cli::address(output),
+        Command::Balance => cli::balance(output),
+        Command::Utxos => cli::utxos(output),
+        Command::Sweep{ pubkeyhash } => cli::sweep(pubkeyhash, output),
+        Command::Peers => cli::peers(output),
+        command => run_phone(&args, command, output)
+    }
+}
+
+/// Runs the phone, at the prompt or on its own to make or take calls.
+fn run_phone(args: &[String], command: Command, output: Output) {
+    let sample_rate = get_sample_rate_arg(args);
+    let phone_config = PhoneConfig {
+        sample_rate,
+        // One second frames, whatever the rate.
+        frames_per_buffer: sample_rate as u32,
+        remote_sample_rate: sample_rate,
+        devices: AudioDevices {
+            input: get_device_arg(args, "--input-device"),
+            output: get_device_arg(args, "--output-device"),
+        },
+        sample_format: get_sample_format_arg(args),
+        backend: get_backend_arg(args),
+        recording: get_arg(args, "--record").map(PathBuf::from),
+        fec: get_fec_arg(args),
+        ..PhoneConfig::default()
+    };
+
+    // Start the wallet, funding that turns up while we're connected goes straight into it.
     let wallet = Wallet::new();
     let payment_sender = wallet.spawn_gateway();
     let (data_sender, data_receiver) = sync_channel(1000);
 
-    let wallet = Wallet::new();
-    // Start the wallet.
-    let wallet_sender = wallet.spawn_gateway();
-
     // Start the tx_sender.
     let key_manager = KeyManager::new(wallet.clone());
     let tx_sender = TxSender::new(
@@ -32,19 +78,110 @@ fn main() {
         data_sender.clone()
     );
 
-    // Start the UI sender.
-    let ui_sender = start(key_manager.clone(), data_sender.clone());
-    let packet = data_receiver.recv()
-        .expect("Got an error in data receiver");
+    // Ctrl+C hangs up properly so the other side isn't left waiting.
+    let quit_sender = data_sender.clone();
+    ctrlc::set_handler(move || {
+        quit_sender.send(DataPacket::UIEvent(UIEvent::Quit)).ok();
+    }).expect("Unable to set Ctrl+C handler");
 
     let mut domain = Domain::new(
         tx_sender.clone(),
         data_receiver,
-        key_manager.clone()
+        key_manager.clone(),
+        phone_config
     );
 
-    domain.start_processing(packet);
+    match command {
+        Command::Call{ outputs } => {
+            watch_headless(domain.subscribe(), data_sender.clone(), output, false);
+            data_sender.send(DataPacket::UIEvent(UIEvent::Start{ outputs })).unwrap();
+        }
+        Command::Listen => {
+            cli::print_addresses(&key_manager, output);
+            watch_headless(domain.subscribe(), data_sender.clone(), output, true);
+        }
+        _ => {
+            // Start the UI sender.
+            start(key_manager.clone(), data_sender.clone());
+            watch_calls(domain.subscribe());
+        }
+    }
+
+    domain.run();
+}
+
+fn list_devices() {
+    let devices = PortAudioBackend::list_devices()
+        .unwrap_or_else(|err| cli::fail(&err.to_string()));
+    for device in devices {
+        println!(
+            "{}: {} (in: {}, out: {}, {} Hz)",
+            device.index,
+            device.name,
+            device.max_input_channels,
+            device.max_output_channels,
+            device.default_sample_rate
+        );
+    }
+}
+
+fn get_arg<'a>(args: &'a [String], flag: &str) -> Option<&'a String> {
+    return args.iter()
+        .position(|arg| arg == flag)
+        .and_then(|position| args.get(position + 1));
+}
+
+fn get_device_arg(args: &[String], flag: &str) -> DeviceSelector {
+    return get_arg(args, flag)
+        .map(|value| DeviceSelector::parse(value))
+        .unwrap_or(DeviceSelector::Default);
+}
+
+fn get_sample_rate_arg(args: &[String]) -> f64 {
+    return get_arg(args, "--sample-rate")
+        .map(|value| value.parse::<u32>()
+            .ok()
+            .filter(|rate| *rate > 0)
+            .unwrap_or_else(|| cli::fail(&format!("Invalid sample rate {}, expected a number of Hz", value))) as f64)
+        .unwrap_or(PhoneConfig::default().sample_rate);
+}
+
+fn get_fec_arg(args: &[String]) -> Option<FecConfig> {
+    return get_arg(args, "--fec")
+        .map(|value| FecConfig::parse(value).unwrap_or_else(|| cli::fail(&format!(
+            "Invalid FEC setting {}, expected <group size>:<repair frames> with at most {} frames \
+             in a group and no more repair frames than that",
+            value,
+            MAX_GROUP_SIZE
+        ))));
+}
+
+/// Sound card by default, `--backend wav` plays one file into the call and records the
+/// other end to another.
+fn get_backend_arg(args: &[String]) -> BackendConfig {
+    let value = get_arg(args, "--backend");
+
+    return match value.map(|value| value.as_str()) {
+        None | Some("portaudio") => BackendConfig::PortAudio,
+        Some("wav") => BackendConfig::Wav {
+            input: get_arg(args, "--wav-input")
+                .map(PathBuf::from)
+                .unwrap_or_else(|| cli::fail("--backend wav needs --wav-input <file> to play into the call")),
+            output: get_arg(args, "--wav-output")
+                .map(PathBuf::from)
+                .unwrap_or_else(|| cli::fail("--backend wav needs --wav-output <file> to record the call to")),
+        },
+        Some(other) => cli::fail(&format!("Unknown audio backend {}, expected portaudio or wav", other))
+    };
+}
 
-    //domain.run_phone();
+fn get_sample_format_arg(args: &[String]) -> SampleFormat {
+    let value = get_arg(args, "--sample-format");
 
+    return match value.map(|value| value.as_str()) {
+        None | Some("i16") => SampleFormat::I16,
+        Some("u8") => SampleFormat::U8,
+        Some("f32") => SampleFormat::F32,
+        Some(other) => cli::fail(&format!("Unknown sample format {}, expected u8, i16 or f32", other))
+    };
 }
@@ -0,0 +1,324 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::net::{Ipv4Addr, Shutdown, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::UNIX_EPOCH;
+use sv::messages::{Inv, InvVect, Message, Tx, Version, INV_VECT_TX, NODE_BITCOIN_CASH, NODE_NETWORK, PROTOCOL_VERSION};
+use sv::network::Network;
+use sv::script::op_codes::{OP_PUSHDATA1, OP_PUSHDATA2, OP_PUSHDATA4};
+use sv::util::{secs_since, BloomFilter, Hash256};
+
+/// Peers only connect to nodes that say they're Bitcoin SV.
+const USER_AGENT: &str = "/Bitcoin SV:local/";
+
+/// A connected client and the filter it has loaded.
+struct Client {
+    writer: Mutex<TcpStream>,
+    /// Nothing is announced to a client until it loads a filter.
+    filter: Mutex<Option<BloomFilter>>,
+}
+
+impl Client {
+    fn send(&self, message: &Message) {
+        let mut writer = self.writer.lock().unwrap();
+        // A client that has gone away is noticed by its reader.
+        message.write(&mut *writer, Network::Mainnet.magic()).ok();
+        writer.flush().ok();
+    }
+
+    /// Whether the filter holds the transaction's hash or anything pushed by its outputs.
+    fn matches(&self, tx: &Tx) -> bool {
+        return match &*self.filter.lock().unwrap() {
+            Some(filter) => filter.contains(&tx.hash().0) || tx.outputs.iter()
+                .any(|output| pushes(&output.lock_script.0).iter().any(|data| filter.contains(data))),
+            None => false
+        };
+    }
+}
+
+/// The server side of the P2P protocol on localhost, so `PeerMan` and `MessageHandler`
+/// can be pointed at something other than the real network.
+///
+/// It handshakes like a Bitcoin SV node on mainnet, answers pings and keeps each client's
+/// bloom filter. Clients announce their transactions with `Inv`, the node asks for them
+/// with `GetData` and announces each new one to the other clients whose filters match.
+pub struct LocalNode {
+    port: u16,
+    clients: RwLock<HashMap<usize, Arc<Client>>>,
+    mempool: RwLock<HashMap<Hash256, Tx>>,
+    next_id: AtomicUsize,
+}
+
+impl LocalNode {
+    /// Starts listening on a free port on localhost.
+    pub fn new() -> Arc<LocalNode> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+            .expect("Unable to listen on localhost");
+        let node = Arc::new(LocalNode {
+            port: listener.local_addr().unwrap().port(),
+            clients: RwLock::new(HashMap::new()),
+            mempool: RwLock::new(HashMap::new()),
+            next_id: AtomicUsize::new(0),
+        });
+
+        node.clone().spawn(listener);
+        return node;
+    }
+
+    pub fn port(&self) -> u16 {
+        return self.port;
+    }
+
+    /// Clients that have finished the handshake and are still connected.
+    pub fn client_count(&self) -> usize {
+        return self.clients.read().unwrap().len();
+    }
+
+    fn spawn(self: Arc<Self>, listener: TcpListener) {
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        let node = self.clone();
+                        thread::spawn(move || node.serve(stream));
+                    }
+                    Err(err) => eprintln!("Local node couldn't accept a connection: {}", err)
+                }
+            }
+        });
+    }
+
+    /// Handshakes with a client, then handles what it sends until it hangs up.
+    fn serve(&self, mut stream: TcpStream) {
+        let magic = Network::Mainnet.magic();
+        match Message::read(&mut stream, magic) {
+            Ok(Message::Version(version)) if version.validate().is_ok() => {}
+            _ => {
+                stream.shutdown(Shutdown::Both).ok();
+                return;
+            }
+        }
+
+        let client = Arc::new(Client {
+            writer: Mutex::new(stream.try_clone().expect("Unable to clone stream")),
+            filter: Mutex::new(None),
+        });
+        client.send(&Message::Version(Self::version()));
+        client.send(&Message::Verack);
+
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        self.clients.write().unwrap().insert(id, client.clone());
+
+        while let Ok(message) = Message::read(&mut stream, magic) {
+            self.handle(id, &client, message);
+        }
+
+        self.clients.write().unwrap().remove(&id);
+    }
+
+    fn handle(&self, id: usize, client: &Client, message: Message) {
+        match message {
+            Message::Ping(ping) => client.send(&Message::Pong(ping)),
+            Message::FilterLoad(load) => {
+                *client.filter.lock().unwrap() = Some(load.bloom_filter);
+            }
+            Message::FilterAdd(add) => {
+                if let Some(filter) = client.filter.lock().unwrap().as_mut() {
+                    filter.add(&add.data);
+                }
+            }
+            Message::FilterClear => {
+                *client.filter.lock().unwrap() = None;
+            }
+            Message::Inv(inv) => {
+                let mempool = self.mempool.read().unwrap();
+                let objects = inv.objects.into_iter()
+                    .filter(|object| object.obj_type == INV_VECT_TX && !mempool.contains_key(&object.hash))
+                    .collect::<Vec<InvVect>>();
+                if !objects.is_empty() {
+                    client.send(&Message::GetData(Inv{ objects }));
+                }
+            }
+            Message::GetData(inv) => {
+                for object in &inv.objects {
+                    if let Some(tx) = self.mempool.read().unwrap().get(&object.hash) {
+                        client.send(&Message::Tx(tx.clone()));
+                    }
+                }
+            }
+            Message::Tx(tx) => self.relay(id, tx),
+            _ => {}
+        }
+    }
+
+    /// Keeps the transaction and tells every other client it matches about it.
+    fn relay(&self, from: usize, tx: Tx) {
+        let hash = tx.hash();
+        if self.mempool.read().unwrap().contains_key(&hash) {
+            return;
+        }
+
+        let recipients = self.clients.read().unwrap().iter()
+            .filter(|(id, client)| **id != from && client.matches(&tx))
+            .map(|(_, client)| client.clone())
+            .collect::<Vec<Arc<Client>>>();
+        self.mempool.write().unwrap().insert(hash, tx);
+
+        for client in recipients {
+            client.send(&Message::Inv(Inv{
+                objects: vec![InvVect{ obj_type: INV_VECT_TX, hash }]
+            }));
+        }
+    }
+
+    fn version() -> Version {
+        return Version {
+            version: PROTOCOL_VERSION,
+            services: NODE_NETWORK | NODE_BITCOIN_CASH,
+            timestamp: secs_since(UNIX_EPOCH) as i64,
+            user_agent: USER_AGENT.to_string(),
+            relay: true,
+            ..Default::default()
+        };
+    }
+}
+
+/// The data pushed by a script, which is what bloom filters are matched against.
+fn pushes(script: &[u8]) -> Vec<&[u8]> {
+    let mut pushes = vec![];
+    let mut i = 0;
+    while i < script.len() {
+        let (start, len) = match script[i] {
+            len @ 1..=75 => (i + 1, len as usize),
+            OP_PUSHDATA1 if i + 2 <= script.len() => (i + 2, script[i + 1] as usize),
+            OP_PUSHDATA2 if i + 3 <= script.len() => {
+                (i + 3, script[i + 1] as usize | ((script[i + 2] as usize) << 8))
+            }
+            OP_PUSHDATA4 if i + 5 <= script.len() => {
+                let len = script[i + 1..i + 5].iter().rev()
+                    .fold(0, |len, byte| (len << 8) | *byte as usize);
+                (i + 5, len)
+            }
+            _ => {
+                i += 1;
+                continue;
+            }
+        };
+
+        if start + len > script.len() {
+            break;
+        }
+        pushes.push(&script[start..start + len]);
+        i = start + len;
+    }
+    return pushes;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+    use std::sync::mpsc::{sync_channel, Receiver};
+    use std::time::{Duration, Instant};
+    use sv::messages::FilterAdd;
+    use sv::transaction::p2pkh::create_lock_script;
+    use sv::util::Hash160;
+    use crate::net::peerman::PeerMan;
+    use crate::net::peerman::message_handler::MessageHandler;
+    use crate::net::test_util::{paying, received};
+    use crate::net::tx_bus::TxEvent;
+    use super::*;
+
+    struct TestClient {
+        peerman: Arc<PeerMan>,
+        handler: Arc<MessageHandler>,
+        received: Receiver<TxEvent>,
+    }
+
+    /// A peer manager connected to the node, listening for anything paying `pubkeyhash`.
+    fn connect(node: &LocalNode, pubkeyhash: Option<[u8; 20]>) -> TestClient {
+        let (addr_bus, _) = sync_channel(100);
+        let (tx_bus, received) = sync_channel(100);
+        let handler = MessageHandler::new(addr_bus, tx_bus);
+        let peerman = PeerMan::new(handler.clone());
+
+        let mut filter = BloomFilter::new(2.0, 1e-7).unwrap();
+        if let Some(pubkeyhash) = pubkeyhash {
+            filter.add(&pubkeyhash);
+        }
+        peerman.clone()
+            .add_peer(IpAddr::from(Ipv4Addr::LOCALHOST), node.port(), filter)
+            .expect("Couldn't connect to the local node");
+
+        return TestClient { peerman, handler, received };
+    }
+
+    /// Waits for the node to have handled everything the clients have sent so far.
+    fn settle(node: &LocalNode, clients: usize) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while node.client_count() < clients
+            || node.clients.read().unwrap().values().any(|client| client.filter.lock().unwrap().is_none()) {
+            assert!(Instant::now() < deadline, "Clients never finished connecting");
+            thread::sleep(Duration::from_millis(10));
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+
+    /// Sends the transaction the way `NetworkInterface::broadcast` does.
+    fn broadcast(client: &TestClient, tx: Tx) {
+        let hash = tx.hash();
+        client.handler.clone().send(tx);
+        client.peerman.clone().broadcast(Message::Inv(Inv{
+            objects: vec![InvVect{ obj_type: INV_VECT_TX, hash }]
+        }));
+    }
+
+    #[test]
+    fn finds_matching_pushes() {
+        let script = create_lock_script(&Hash160([7; 20]));
+        assert_eq!(pushes(&script.0), vec![&[7u8; 20][..]]);
+
+        let mut script = vec![0x6a, OP_PUSHDATA1, 3, 1, 2, 3, OP_PUSHDATA2, 2, 0, 4, 5];
+        assert_eq!(pushes(&script), vec![&[1u8, 2, 3][..], &[4u8, 5][..]]);
+
+        // A push running past the end of the script is ignored.
+        script.push(9);
+        assert_eq!(pushes(&script).len(), 2);
+    }
+
+    #[test]
+    fn relays_transactions_to_matching_clients() {
+        let node = LocalNode::new();
+        let a = connect(&node, None);
+        let b = connect(&node, Some([7; 20]));
+        let c = connect(&node, Some([8; 20]));
+        settle(&node, 3);
+
+        let tx = paying([7; 20], 0);
+        broadcast(&a, tx.clone());
+
+        assert_eq!(received(&b.received, Duration::from_secs(5)), Some(tx.hash()));
+        assert_eq!(received(&a.received, Duration::from_millis(500)), None);
+        assert_eq!(received(&c.received, Duration::from_millis(0)), None);
+
+        // Sending it again doesn't announce it twice.
+        broadcast(&a, tx);
+        assert_eq!(received(&b.received, Duration::from_millis(500)), None);
+    }
+
+    #[test]
+    fn honors_filter_add() {
+        let node = LocalNode::new();
+        let a = connect(&node, None);
+        let b = connect(&node, Some([7; 20]));
+        settle(&node, 2);
+
+        b.peerman.clone().broadcast(Message::FilterAdd(FilterAdd{ data: vec![9; 20] }));
+        settle(&node, 2);
+
+        let tx = paying([9; 20], 1);
+        broadcast(&a, tx.clone());
+        assert_eq!(received(&b.received, Duration::from_secs(5)), Some(tx.hash()));
+    }
+}
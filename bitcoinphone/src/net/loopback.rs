@@ -0,0 +1,568 @@
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{self, AtomicU64};
+use std::sync::mpsc::{sync_channel, Receiver, RecvTimeoutError, SyncSender};
+use std::thread;
+use std::time::{Duration, Instant};
+use rand::Rng;
+use sv::messages::{FilterAdd, Inv, InvVect, Message, Tx, TxOut, INV_VECT_TX};
+use sv::script::Script;
+use sv::util::Hash256;
+use crate::net::tx_bus::TxEvent;
+
+/// Messages to and from the relay itself rather than one of its peers.
+const RELAY: usize = usize::MAX;
+
+/// How the relay treats what passes through it.
+#[derive(Clone, Debug, Default)]
+pub struct LinkConditions {
+    /// Every message takes at least this long.
+    pub delay: Duration,
+    /// Messages take up to this much longer at random, so later ones can overtake them.
+    pub jitter: Duration,
+    /// Chance of a transaction never reaching a peer, from 0 to 1.
+    pub loss: f64,
+}
+
+/// A message on its way, the earliest due comes out of the heap first.
+struct Delivery {
+    at: Instant,
+    sequence: u64,
+    from: usize,
+    to: usize,
+    message: Message,
+}
+
+impl Ord for Delivery {
+    fn cmp(&self, other: &Self) -> Ordering {
+        return (other.at, other.sequence).cmp(&(self.at, self.sequence));
+    }
+}
+
+impl PartialOrd for Delivery {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        return Some(self.cmp(other));
+    }
+}
+
+impl PartialEq for Delivery {
+    fn eq(&self, other: &Self) -> bool {
+        return self.sequence == other.sequence;
+    }
+}
+
+impl Eq for Delivery {}
+
+/// Stands in for a node on the network, so phones in one process can call each other.
+///
+/// Peers announce their transactions with `Inv`, the relay asks for them with `GetData`
+/// and announces each one to the other peers whose filters match it, who ask for it in
+/// turn. Every message is held up by the link's delay and jitter, and a transaction can
+/// be lost on its way to any peer. Filter updates always arrive at once.
+pub struct LoopbackRelay {
+    conditions: LinkConditions,
+    /// Each peer's inbox, a peer's id is its place here.
+    peers: Mutex<Vec<SyncSender<(usize, Message)>>>,
+    /// What each peer has asked to hear about.
+    filters: Mutex<HashMap<usize, Vec<Vec<u8>>>>,
+    mempool: RwLock<HashMap<Hash256, Tx>>,
+    queue: SyncSender<Delivery>,
+    sequence: AtomicU64,
+}
+
+impl LoopbackRelay {
+    pub fn new(conditions: LinkConditions) -> Arc<LoopbackRelay> {
+        let (queue, receiver) = sync_channel(10000);
+        let relay = Arc::new(LoopbackRelay {
+            conditions,
+            peers: Mutex::new(vec![]),
+            filters: Mutex::new(HashMap::new()),
+            mempool: RwLock::new(HashMap::new()),
+            queue,
+            sequence: AtomicU64::new(0),
+        });
+
+        relay.clone().spawn(receiver);
+        return relay;
+    }
+
+    /// Pays `sats` to the output out of thin air, as if someone had funded it.
+    pub fn fund(&self, output: Script, sats: i64) {
+        let tx = Tx {
+            version: 1,
+            inputs: vec![],
+            outputs: vec![TxOut { satoshis: sats, lock_script: output }],
+            // Keeps every funding transaction's hash different.
+            lock_time: self.sequence.fetch_add(1, atomic::Ordering::SeqCst) as u32,
+        };
+
+        self.announce(RELAY, tx);
+    }
+
+    fn connect(&self, inbox: SyncSender<(usize, Message)>) -> usize {
+        let mut peers = self.peers.lock().unwrap();
+        peers.push(inbox);
+        return peers.len() - 1;
+    }
+
+    fn spawn(self: Arc<Self>, receiver: Receiver<Delivery>) {
+        thread::spawn(move || {
+            let mut pending = BinaryHeap::new();
+            loop {
+                let received = match pending.peek() {
+                    Some(Delivery{ at, .. }) => receiver.recv_timeout(at.saturating_duration_since(Instant::now())),
+                    None => receiver.recv().map_err(|_| RecvTimeoutError::Disconnected)
+                };
+                match received {
+                    Ok(delivery) => pending.push(delivery),
+                    Err(RecvTimeoutError::Timeout) => {}
+                    Err(RecvTimeoutError::Disconnected) => return
+                }
+
+                while pending.peek().map(|delivery| delivery.at <= Instant::now()).unwrap_or(false) {
+                    let Delivery{ from, to, message, .. } = pending.pop().unwrap();
+                    if to == RELAY {
+                        self.handle(from, message);
+                        continue;
+                    }
+
+                    // Peers that have gone away are skipped.
+                    let inbox = self.peers.lock().unwrap().get(to).cloned();
+                    if let Some(inbox) = inbox {
+                        inbox.send((from, message)).ok();
+                    }
+                }
+            }
+        });
+    }
+
+    /// Queues a message to arrive once the link's delay and some jitter have passed.
+    fn send(&self, from: usize, to: usize, message: Message) {
+        let jitter = self.conditions.jitter.as_nanos() as u64;
+        let jitter = match jitter {
+            0 => Duration::from_secs(0),
+            jitter => Duration::from_nanos(rand::thread_rng().gen_range(0, jitter))
+        };
+
+        self.queue
+            .send(Delivery {
+                at: Instant::now() + self.conditions.delay + jitter,
+                sequence: self.sequence.fetch_add(1, atomic::Ordering::SeqCst),
+                from,
+                to,
+                message,
+            })
+            .expect("Relay has stopped");
+    }
+
+    fn handle(&self, from: usize, message: Message) {
+        match message {
+            Message::FilterAdd(FilterAdd{ data }) => {
+                self.filters
+                    .lock()
+                    .unwrap()
+                    .entry(from)
+                    .or_insert_with(Vec::new)
+                    .push(data);
+            }
+            Message::Inv(inv) => {
+                let mempool = self.mempool.read().unwrap();
+                let objects = inv.objects.into_iter()
+                    .filter(|object| object.obj_type == INV_VECT_TX && !mempool.contains_key(&object.hash))
+                    .collect::<Vec<InvVect>>();
+                if !objects.is_empty() {
+                    self.send(RELAY, from, Message::GetData(Inv{ objects }));
+                }
+            }
+            Message::GetData(inv) => {
+                for object in &inv.objects {
+                    if let Some(tx) = self.mempool.read().unwrap().get(&object.hash) {
+                        self.send(RELAY, from, Message::Tx(tx.clone()));
+                    }
+                }
+            }
+            Message::Tx(tx) => self.announce(from, tx),
+            _ => {}
+        }
+    }
+
+    /// Tells every other peer the transaction is for about it, unless the link loses it.
+    fn announce(&self, from: usize, tx: Tx) {
+        let hash = tx.hash();
+        let recipients = {
+            let filters = self.filters.lock().unwrap();
+            (0..self.peers.lock().unwrap().len())
+                .filter(|peer| *peer != from)
+                .filter(|peer| filters.get(peer).map(|items| Self::matches(items, &tx)).unwrap_or(false))
+                .collect::<Vec<usize>>()
+        };
+        self.mempool.write().unwrap().insert(hash, tx);
+
+        for peer in recipients {
+            // Funding comes from outside the link, so it always arrives.
+            if from != RELAY && rand::thread_rng().gen::<f64>() < self.conditions.loss {
+                continue;
+            }
+            self.send(RELAY, peer, Message::Inv(Inv{
+                objects: vec![InvVect{ obj_type: INV_VECT_TX, hash: hash }]
+            }));
+        }
+    }
+
+    /// Whether any output script holds something the filter is waiting for.
+    fn matches(items: &[Vec<u8>], tx: &Tx) -> bool {
+        return tx.outputs.iter().any(|output| items.iter().any(|item| {
+            !item.is_empty() && output.lock_script.0.windows(item.len()).any(|window| window == &item[..])
+        }));
+    }
+}
+
+/// A phone's connection to a `LoopbackRelay`, it answers the relay the way the message
+/// handler answers real peers.
+pub struct LoopbackPeer {
+    id: usize,
+    relay: Arc<LoopbackRelay>,
+    tx_bus: SyncSender<TxEvent>,
+    out_cache: RwLock<HashMap<Hash256, Tx>>,
+    waiting: Mutex<HashSet<Hash256>>,
+}
+
+impl LoopbackPeer {
+    pub fn connect(relay: &Arc<LoopbackRelay>, tx_bus: SyncSender<TxEvent>) -> Arc<LoopbackPeer> {
+        let (inbox, receiver) = sync_channel(10000);
+        let peer = Arc::new(LoopbackPeer {
+            id: relay.connect(inbox),
+            relay: relay.clone(),
+            tx_bus,
+            out_cache: RwLock::new(HashMap::new()),
+            waiting: Mutex::new(HashSet::new()),
+        });
+
+        peer.clone().spawn(receiver);
+        return peer;
+    }
+
+    pub fn add_filter(&self, data: &[u8]) {
+        self.relay.handle(self.id, Message::FilterAdd(FilterAdd{ data: data.to_vec() }));
+    }
+
+    pub fn broadcast(&self, tx: Tx) {
+        let hash = tx.hash();
+        self.out_cache.write().unwrap().insert(hash, tx);
+        self.relay.send(self.id, RELAY, Message::Inv(Inv{
+            objects: vec![InvVect{ obj_type: INV_VECT_TX, hash }]
+        }));
+    }
+
+    fn spawn(self: Arc<Self>, receiver: Receiver<(usize, Message)>) {
+        thread::spawn(move || loop {
+            match receiver.recv() {
+                Ok((_, message)) => self.handle(message),
+                Err(_) => return
+            }
+        });
+    }
+
+    fn handle(&self, message: Message) {
+        match message {
+            Message::Inv(inv) => {
+                let out_cache = self.out_cache.read().unwrap();
+                let mut waiting = self.waiting.lock().unwrap();
+                let objects = inv.objects.into_iter()
+                    .filter(|object| object.obj_type == INV_VECT_TX
+                        && !out_cache.contains_key(&object.hash)
+                        && waiting.insert(object.hash))
+                    .collect::<Vec<InvVect>>();
+                if !objects.is_empty() {
+                    self.relay.send(self.id, RELAY, Message::GetData(Inv{ objects }));
+                }
+            }
+            Message::GetData(inv) => {
+                for object in &inv.objects {
+                    if let Some(tx) = self.out_cache.read().unwrap().get(&object.hash) {
+                        self.relay.send(self.id, RELAY, Message::Tx(tx.clone()));
+                    }
+                }
+            }
+            Message::Tx(tx) => {
+                self.tx_bus
+                    .send(TxEvent::RawTx(tx))
+                    .unwrap();
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f64::consts::PI;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::process;
+    use std::sync::mpsc::{Receiver, SyncSender};
+    use std::thread::JoinHandle;
+    use sv::util::Hash160;
+    use sv::transaction::p2pkh::create_lock_script;
+    use crate::domain::{Domain, START_ATTEMPTS};
+    use crate::domain::call::{CallError, CallEvent, CallState};
+    use crate::net::NetworkInterface;
+    use crate::net::test_util::{paying, received};
+    use crate::phone::PhoneConfig;
+    use crate::phone::backend::{BackendConfig, MemoryBackend};
+    use crate::phone::stats::CallStatsSnapshot;
+    use crate::phone::wav;
+    use crate::tx_sender::{TxSender, MIN_DUST};
+    use crate::tx_sender::keys::{KeyManager, Wallet, Walletable};
+    use crate::util::constants::{CommunicationsKey, DataPacket, PaymentKey, UIEvent};
+    use crate::util::traits::Spawnable;
+    use super::*;
+
+    const SAMPLE_RATE: f64 = 8000.0;
+    const FRAMES_PER_BUFFER: u32 = 800;
+    const FUNDING: i64 = 10_000_000;
+    const EVENT_TIMEOUT: Duration = Duration::from_secs(60);
+
+    struct TestPhone {
+        input: SyncSender<DataPacket>,
+        events: Receiver<CallEvent>,
+        output: Vec<u8>,
+        domain: JoinHandle<()>,
+    }
+
+    /// A funded phone on the relay with its audio going through the backend.
+    fn start_phone(relay: &Arc<LoopbackRelay>, backend: BackendConfig) -> TestPhone {
+        let wallet = Wallet::in_memory();
+        let key_manager = KeyManager::new(wallet.clone());
+        let (input, data_receiver) = sync_channel(1000);
+        let network = NetworkInterface::loopback(relay, wallet.spawn_gateway(), input.clone());
+        let tx_sender = TxSender::with_network(key_manager.clone(), network);
+
+        relay.fund(create_lock_script(&key_manager.get_key(PaymentKey).pubkeyhash), FUNDING);
+        let deadline = Instant::now() + EVENT_TIMEOUT;
+        while wallet.get_balance() == 0 {
+            assert!(Instant::now() < deadline, "Funding never arrived");
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let config = PhoneConfig {
+            sample_rate: SAMPLE_RATE,
+            frames_per_buffer: FRAMES_PER_BUFFER,
+            remote_sample_rate: SAMPLE_RATE,
+            backend,
+            ..PhoneConfig::default()
+        };
+
+        let output = create_lock_script(&key_manager.get_key(CommunicationsKey).pubkeyhash).0;
+        let mut domain = Domain::new(tx_sender, data_receiver, key_manager, config);
+        let events = domain.subscribe();
+        return TestPhone {
+            input,
+            events,
+            output,
+            domain: thread::spawn(move || domain.run()),
+        };
+    }
+
+    /// Plays a tone into the microphone and keeps what comes out of the speaker.
+    fn tone(frequency: f64) -> MemoryBackend {
+        let tone = (0..SAMPLE_RATE as usize * 20)
+            .map(|sample| ((sample as f64 * frequency * 2.0 * PI / SAMPLE_RATE).sin() * 8000.0) as i16)
+            .collect();
+        return MemoryBackend::new(tone, true);
+    }
+
+    fn wait_for(phone: &TestPhone, expected: impl Fn(&CallEvent) -> bool) -> CallEvent {
+        let deadline = Instant::now() + EVENT_TIMEOUT;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            match phone.events.recv_timeout(remaining) {
+                Ok(CallEvent::Failed(err)) => panic!("Call failed: {}", err),
+                Ok(event) => if expected(&event) {
+                    return event;
+                }
+                Err(_) => panic!("Gave up waiting for a call event")
+            }
+        }
+    }
+
+    fn entered(state: CallState) -> impl Fn(&CallEvent) -> bool {
+        return move |event| match event {
+            CallEvent::StateChanged{ to, .. } => *to == state,
+            _ => false
+        };
+    }
+
+    fn heard_anything(samples: &[i16]) -> bool {
+        return samples.iter().any(|sample| sample.abs() > 1000);
+    }
+
+    /// Power at one frequency summed over each buffer, so gaps between frames don't cancel it out.
+    fn power_at(samples: &[i16], frequency: f64) -> f64 {
+        return samples.chunks(FRAMES_PER_BUFFER as usize)
+            .map(|buffer| {
+                let (sin, cos) = buffer.iter().enumerate().fold((0.0, 0.0), |(sin, cos), (index, sample)| {
+                    let phase = index as f64 * frequency * 2.0 * PI / SAMPLE_RATE;
+                    (sin + *sample as f64 * phase.sin(), cos + *sample as f64 * phase.cos())
+                });
+                sin * sin + cos * cos
+            })
+            .sum();
+    }
+
+    /// The caller calls, the callee answers, and they talk for a while before the caller hangs up.
+    /// Returns the caller's summary of the call.
+    fn talk(caller: &TestPhone, callee: &TestPhone) -> CallStatsSnapshot {
+        caller.input.send(DataPacket::UIEvent(UIEvent::Start{ outputs: vec![callee.output.clone()] })).unwrap();
+        wait_for(callee, |event| match event {
+            CallEvent::IncomingCall{ .. } => true,
+            _ => false
+        });
+        callee.input.send(DataPacket::UIEvent(UIEvent::Accept)).unwrap();
+        wait_for(caller, entered(CallState::InCall));
+        wait_for(callee, entered(CallState::InCall));
+
+        thread::sleep(Duration::from_secs(5));
+        caller.input.send(DataPacket::UIEvent(UIEvent::HangUp)).unwrap();
+        let summary = match wait_for(caller, |event| matches!(event, CallEvent::Summary(_))) {
+            CallEvent::Summary(summary) => summary,
+            event => panic!("Expected a summary, got {:?}", event)
+        };
+        wait_for(caller, entered(CallState::Ended));
+        wait_for(callee, entered(CallState::Ended));
+        return summary;
+    }
+
+    fn quit(phones: Vec<TestPhone>) {
+        for phone in phones {
+            phone.input.send(DataPacket::UIEvent(UIEvent::Quit)).unwrap();
+            phone.domain.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn relays_transactions_to_matching_peers() {
+        let delay = Duration::from_millis(50);
+        let relay = LoopbackRelay::new(LinkConditions { delay, ..LinkConditions::default() });
+        let (a_bus, a_received) = sync_channel(100);
+        let (b_bus, b_received) = sync_channel(100);
+        let (c_bus, c_received) = sync_channel(100);
+        let a = LoopbackPeer::connect(&relay, a_bus);
+        let b = LoopbackPeer::connect(&relay, b_bus);
+        let c = LoopbackPeer::connect(&relay, c_bus);
+        b.add_filter(&[7; 20]);
+        c.add_filter(&[8; 20]);
+
+        let tx = paying([7; 20], 0);
+        let sent = Instant::now();
+        a.broadcast(tx.clone());
+
+        assert_eq!(received(&b_received, Duration::from_secs(5)), Some(tx.hash()));
+        // Inv, GetData and Tx from the sender to the relay, then again to the receiver.
+        assert!(sent.elapsed() >= delay * 6);
+        assert_eq!(received(&a_received, Duration::from_millis(500)), None);
+        assert_eq!(received(&c_received, Duration::from_millis(0)), None);
+    }
+
+    #[test]
+    fn loses_transactions() {
+        let relay = LoopbackRelay::new(LinkConditions { loss: 1.0, ..LinkConditions::default() });
+        let (a_bus, _) = sync_channel(100);
+        let (b_bus, b_received) = sync_channel(100);
+        let a = LoopbackPeer::connect(&relay, a_bus);
+        let b = LoopbackPeer::connect(&relay, b_bus);
+        b.add_filter(&[7; 20]);
+
+        a.broadcast(paying([7; 20], 0));
+        assert_eq!(received(&b_received, Duration::from_millis(500)), None);
+
+        // Funding comes from outside the link.
+        relay.fund(create_lock_script(&Hash160([7; 20])), 1000);
+        assert!(received(&b_received, Duration::from_secs(5)).is_some());
+    }
+
+    #[test]
+    fn two_phones_call_over_loopback() {
+        let relay = LoopbackRelay::new(LinkConditions {
+            delay: Duration::from_millis(20),
+            jitter: Duration::from_millis(30),
+            loss: 0.0,
+        });
+        let alice_audio = tone(440.0);
+        let bob_audio = tone(660.0);
+        let alice = start_phone(&relay, BackendConfig::Memory(alice_audio.clone()));
+        let bob = start_phone(&relay, BackendConfig::Memory(bob_audio.clone()));
+
+        let summary = talk(&alice, &bob);
+
+        assert!(heard_anything(&bob_audio.get_output()), "Bob never heard Alice");
+        assert!(heard_anything(&alice_audio.get_output()), "Alice never heard Bob");
+        // Every transaction replaces the one before, the call costs what the last one pays.
+        assert!(summary.sats_spent >= MIN_DUST && summary.sats_spent < 2 * MIN_DUST, "Call cost {} sats", summary.sats_spent);
+        quit(vec![alice, bob]);
+    }
+
+    #[test]
+    fn calls_from_a_wav_fixture() {
+        let relay = LoopbackRelay::new(LinkConditions {
+            delay: Duration::from_millis(20),
+            ..LinkConditions::default()
+        });
+        // 440 Hz at 16 kHz in 24-bit samples, so it's resampled and narrowed on the way in.
+        let fixture = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/tone-440hz-16khz-24bit.wav");
+        let directory = std::env::temp_dir().join(format!("bitcoinphone-wav-call-{}", process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let recording = directory.join("alice.wav");
+
+        let bob_audio = tone(660.0);
+        let alice = start_phone(&relay, BackendConfig::Wav{ input: fixture, output: recording.clone() });
+        let bob = start_phone(&relay, BackendConfig::Memory(bob_audio.clone()));
+
+        talk(&alice, &bob);
+        quit(vec![alice, bob]);
+
+        let heard = bob_audio.get_output();
+        assert!(heard_anything(&heard), "Bob never heard the fixture");
+        assert!(power_at(&heard, 440.0) > 10.0 * power_at(&heard, 660.0), "Bob didn't hear the fixture's tone");
+
+        let (spec, data) = wav::read(&recording).unwrap();
+        assert_eq!(spec, wav::WavSpec { channels: 1, sample_rate: SAMPLE_RATE as u32, bits_per_sample: 16 });
+        let recorded = data.chunks_exact(2)
+            .map(|sample| i16::from_le_bytes([sample[0], sample[1]]))
+            .collect::<Vec<i16>>();
+        assert!(heard_anything(&recorded), "Nothing from Bob was written to Alice's WAV");
+        assert!(power_at(&recorded, 660.0) > 10.0 * power_at(&recorded, 440.0), "Alice's WAV doesn't hold Bob's tone");
+
+        fs::remove_dir_all(&directory).ok();
+    }
+
+    #[test]
+    fn gives_up_on_a_peer_that_never_answers() {
+        let relay = LoopbackRelay::new(LinkConditions { loss: 1.0, ..LinkConditions::default() });
+        let alice = start_phone(&relay, BackendConfig::Memory(tone(440.0)));
+
+        let nobody = create_lock_script(&Hash160([7; 20])).0;
+        alice.input.send(DataPacket::UIEvent(UIEvent::Start{ outputs: vec![nobody] })).unwrap();
+        let mut attempts = vec![];
+        let mut states = vec![];
+        let mut failure = None;
+        while states.last() != Some(&(CallState::Ended, CallState::Idle)) {
+            match alice.events.recv_timeout(EVENT_TIMEOUT).expect("Gave up waiting for a call event") {
+                CallEvent::Retrying{ attempt } => attempts.push(attempt),
+                CallEvent::StateChanged{ from, to } => states.push((from, to)),
+                CallEvent::Failed(err) => failure = Some(err),
+                _ => {}
+            }
+        }
+
+        assert_eq!(attempts, (2..=START_ATTEMPTS).collect::<Vec<u32>>());
+        assert!(matches!(failure, Some(CallError::NoAnswer{ attempts: START_ATTEMPTS })));
+        assert_eq!(states, vec![
+            (CallState::Idle, CallState::Dialing),
+            (CallState::Dialing, CallState::Ended),
+            (CallState::Ended, CallState::Idle),
+        ]);
+        quit(vec![alice]);
+    }
+}
@@ -1,4 +1,5 @@
 use std::collections::HashMap;
+use std::net::IpAddr;
 use std::ops::{AddAssign, Sub};
 use std::sync::{Arc, Mutex, RwLock};
 use std::sync::mpsc::{Receiver, Sender, sync_channel, SyncSender};
@@ -7,10 +8,11 @@ use std::time::Duration;
 
 use sv::messages::{FilterAdd, Inv, INV_VECT_TX, InvVect, Message, Tx};
 use sv::peer::{Peer, PeerDisconnected};
-use sv::util::Hash160;
+use sv::util::{Hash160, Hash256};
 use sv::util::rx::Observer;
 
 use lazy_static::lazy_static;
+use serde::Serialize;
 use peer_db::{PeerDB, SafePeerDB};
 use peerman::{PeerMan, PPeer};
 use peerman::filter::BloomFilterState;
@@ -18,15 +20,24 @@ use peerman::message_handler::MessageHandler;
 
 use crate::net::addr_bus::{AddrBus, AddressAction, IpTuple, AddrEvent};
 use crate::net::addr_bus::AddrOp;
-pub use crate::net::tx_bus::{TxBus, TxEvent, TxOperation};
+pub use crate::net::tx_bus::{FrameTx, TxBus, TxEvent, TxOperation};
+pub use crate::net::loopback::{LinkConditions, LoopbackRelay};
+use crate::net::loopback::LoopbackPeer;
+pub use crate::net::local_node::LocalNode;
 use sv::script::Script;
 use std::cmp::min;
 use crate::util::constants::{UTXOPacket, DataPacket, Key};
 use crate::util::traits::Spawnable;
 use sv::transaction::p2pkh::create_lock_script;
+use crate::crypto::Session;
 
 mod peer_db;
 mod tx_bus;
+mod reassembly;
+mod loopback;
+mod local_node;
+#[cfg(test)]
+mod test_util;
 
 mod peerman;
 mod addr_bus;
@@ -35,13 +46,32 @@ pub const MAX_FILTER_ITEMS: f64 = 10.0;
 pub const MAX_CONCURRENT_HANDSHAKES: usize = 100;
 pub const MAXIMUM_HANDSHAKE_ATTEMPTS: usize = 20;
 
+/// How transactions get to and from other phones.
+enum Transport {
+    /// Peers on the BSV network.
+    Peers {
+        peerman: Arc<PeerMan>,
+        peer_db: SafePeerDB,
+        addr_bus_sender: SyncSender<AddrEvent>,
+        filter: Arc<BloomFilterState>,
+        handler: Arc<MessageHandler>,
+    },
+    /// A relay in the same process, so phones can call each other without the network.
+    Loopback(Arc<LoopbackPeer>),
+}
+
+/// A peer we're connected to and what it told us about itself.
+#[derive(Clone, Debug, Serialize)]
+pub struct PeerInfo {
+    pub ip: IpAddr,
+    pub port: u16,
+    pub user_agent: String,
+    pub start_height: i32,
+}
+
 pub struct NetworkInterface {
-    peerman: Arc<PeerMan>,
-    peer_db: SafePeerDB,
-    addr_bus_sender: SyncSender<AddrEvent>,
+    transport: Transport,
     tx_bus_sender: SyncSender<TxEvent>,
-    filter: Arc<BloomFilterState>,
-    handler: Arc<MessageHandler>,
 }
 
 impl NetworkInterface {
@@ -64,32 +94,58 @@ impl NetworkInterface {
         let peerman = PeerMan::new(handler.clone());
 
         return NetworkInterface{
-            peerman,
-            peer_db,
-            addr_bus_sender,
+            transport: Transport::Peers {
+                peerman,
+                peer_db,
+                addr_bus_sender,
+                filter: BloomFilterState::new(2.0),
+                handler: handler.clone()
+            },
+            tx_bus_sender,
+        };
+    }
+
+    /// Talks to other phones through the relay instead of the network.
+    pub fn loopback(
+        relay: &Arc<LoopbackRelay>,
+        payment_sender: SyncSender<UTXOPacket>,
+        data_sender: SyncSender<DataPacket>
+    ) -> Self {
+        let tx_bus_sender = TxBus::new(
+            payment_sender,
+            data_sender
+        );
+
+        return NetworkInterface{
+            transport: Transport::Loopback(LoopbackPeer::connect(relay, tx_bus_sender.clone())),
             tx_bus_sender,
-            filter: BloomFilterState::new(2.0),
-            handler: handler.clone()
         };
     }
 
+    /// Finds up to `max_peers` peers, a loopback is connected from the start.
     pub fn connect(&mut self, max_peers: usize) {
         self._connect(max_peers, MAXIMUM_HANDSHAKE_ATTEMPTS);
     }
 
     fn _connect(&mut self, max_peers: usize, attempts: usize) {
-        let current_peers = self.peerman.clone().get_count();
+        let (peerman, peer_db, addr_bus_sender, filter) = match &self.transport {
+            Transport::Peers{ peerman, peer_db, addr_bus_sender, filter, .. } => {
+                (peerman.clone(), peer_db.clone(), addr_bus_sender.clone(), filter.clone())
+            }
+            Transport::Loopback(_) => return
+        };
+        let current_peers = peerman.clone().get_count();
         let mut peer_threads = vec![];
-        let bloom_filter = self.filter.clone().get_filter();
+        let bloom_filter = filter.clone().get_filter();
 
-        if (max_peers <= current_peers) || attempts <= 0 {
-            self.peerman.clone().remove_count(current_peers - max_peers);
+        if (max_peers <= current_peers) || attempts == 0 {
+            peerman.clone().remove_count(current_peers - max_peers);
             return;
         }
         for i in 0..MAX_CONCURRENT_HANDSHAKES {
-            let ip_tuple = match self.peer_db.lock().unwrap().get() {
+            let ip_tuple = match peer_db.lock().unwrap().get() {
                 Some((ip, port)) => {
-                    let peerman = self.peerman.clone();
+                    let peerman = peerman.clone();
                     let bloom = bloom_filter.clone();
                     peer_threads.push(thread::spawn(move || {
                         return peerman.add_peer(ip, port, bloom.clone());
@@ -106,13 +162,21 @@ impl NetworkInterface {
             )
             .collect::<Vec<IpTuple>>();
 
-        self.addr_bus_sender
+        addr_bus_sender
             .send(AddrEvent::Op(AddrOp(bad_apples, AddressAction::Remove)));
 
         thread::sleep(Duration::from_millis(100));
         self._connect(max_peers, attempts - 1);
     }
 
+    /// Peers we're connected to, a loopback has none.
+    pub fn peers(&self) -> Vec<PeerInfo> {
+        return match &self.transport {
+            Transport::Peers{ peerman, .. } => peerman.clone().get_peers(),
+            Transport::Loopback(_) => vec![]
+        };
+    }
+
     pub fn subscribe_to_payments(&self, key: &Key) {
         self.subscribe_to_comms(key);
         self.tx_bus_sender
@@ -124,19 +188,54 @@ impl NetworkInterface {
         self.update_filter(&key.pubkeyhash.0);
     }
 
+    /// Reports the txid of every voice frame we receive.
+    pub fn watch_frames(&self, watcher: SyncSender<FrameTx>) {
+        self.tx_bus_sender
+            .send(TxEvent::WatchFrames(watcher))
+            .unwrap();
+    }
+
+    /// Restricts incoming packets to the given call, or lifts the restriction.
+    pub fn set_session(&self, session: Option<Arc<Session>>) {
+        self.tx_bus_sender
+            .send(TxEvent::SetSession(session))
+            .unwrap();
+    }
+
+    /// Lets packets from these conference participants in as well.
+    pub fn set_conference(&self, sessions: Vec<Arc<Session>>) {
+        self.tx_bus_sender
+            .send(TxEvent::SetConference(sessions))
+            .unwrap();
+    }
+
     pub fn update_filter(&self, data: &[u8]) {
-        self.filter.clone().update_filter(data.clone());
+        let (peerman, filter) = match &self.transport {
+            Transport::Peers{ peerman, filter, .. } => (peerman, filter),
+            Transport::Loopback(peer) => {
+                peer.add_filter(data);
+                return;
+            }
+        };
+        filter.clone().update_filter(data.clone());
 
-        self.peerman.clone().broadcast(Message::FilterAdd(FilterAdd{
+        peerman.clone().broadcast(Message::FilterAdd(FilterAdd{
             data: data.to_vec()
         }));
     }
 
-    pub(crate) fn broadcast(&self, tx: Tx) {
-        println!("Sending TX: {} ", hex::encode(tx.to_bytes()));
+    pub(crate) fn broadcast(&self, tx: Tx) -> Hash256 {
+        eprintln!("Sending TX: {} ", hex::encode(tx.to_bytes()));
         let hash = tx.hash();
-        self.handler.clone().send(tx);
-        self.peerman.clone().broadcast(Message::Inv(Inv{
+        let (peerman, handler) = match &self.transport {
+            Transport::Peers{ peerman, handler, .. } => (peerman, handler),
+            Transport::Loopback(peer) => {
+                peer.broadcast(tx);
+                return hash;
+            }
+        };
+        handler.clone().send(tx);
+        peerman.clone().broadcast(Message::Inv(Inv{
             objects: vec![
                 InvVect{
                     obj_type: INV_VECT_TX,
@@ -144,6 +243,8 @@ impl NetworkInterface {
                 }
             ]
         }));
+
+        return hash;
     }
 }
 
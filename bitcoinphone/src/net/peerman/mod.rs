@@ -11,6 +11,7 @@ use sv::util::BloomFilter;
 use filter::BloomFilterState;
 pub use peer::PPeer;
 
+use crate::net::PeerInfo;
 use crate::net::peer_db::IpTuple;
 use crate::net::peerman::message_handler::MessageHandler;
 
@@ -35,6 +36,13 @@ impl PeerMan {
         return self.peers.lock().unwrap().len();
     }
 
+    pub fn get_peers(self: Arc<Self>) -> Vec<PeerInfo> {
+        return self.peers.lock().unwrap()
+            .values()
+            .map(|peer| peer.get_info())
+            .collect();
+    }
+
     pub(crate) fn add_peer(self: Arc<Self>, ip_address: IpAddr, port: u16, bloom_filter: BloomFilter) -> Result<(), IpTuple> {
         let peer = PPeer::new(
             ip_address,
@@ -5,6 +5,7 @@ use sv::messages::{Version, NodeAddr, Message, FilterAdd, FilterLoad};
 use std::sync::Arc;
 use sv::util::BloomFilter;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use crate::net::PeerInfo;
 use crate::net::peerman::message_handler::MessageHandler;
 use lazy_static::lazy_static;
 use sv::util::rx::Observable;
@@ -54,7 +55,7 @@ impl PPeer {
             return None;
         }
 
-        println!("Connected to peer {}:{}!", ip, port);
+        eprintln!("Connected to peer {}:{}!", ip, port);
         sv_peer.send(&Message::FilterLoad(FilterLoad{
             bloom_filter,
             flags: 0
@@ -80,6 +81,16 @@ impl PPeer {
         return format!("{}:{}", peer.ip.to_string(), peer.port.to_string());
     }
 
+    pub fn get_info(&self) -> PeerInfo {
+        let version = self.sv_peer.version().unwrap_or_default();
+        return PeerInfo{
+            ip: self.sv_peer.ip,
+            port: self.sv_peer.port,
+            user_agent: version.user_agent,
+            start_height: version.start_height
+        };
+    }
+
     pub fn send(&self, msg: &Message) {
         self.sv_peer.send(msg);
     }
@@ -0,0 +1,160 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use crate::tx_sender::MAX_BYTES_PER_PACKET;
+use crate::util::constants::{MAX_ATTACHMENT_SIZE, PHONE_PREFIX};
+use crate::wire::{Fragment, FRAGMENT_OVERHEAD};
+
+/// How long the rest of a packet has to arrive once its first fragment has.
+pub const FRAGMENT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Largest packet put back together, an attachment with room for the fields around it.
+pub const MAX_PACKET_SIZE: usize = MAX_ATTACHMENT_SIZE + 1024;
+/// Incomplete packets kept at once, anyone can send us fragments that never finish.
+const MAX_PENDING: usize = 64;
+/// Bytes kept across every incomplete packet.
+const MAX_PENDING_BYTES: usize = 16 * MAX_PACKET_SIZE;
+/// What each fragment carries, all but the last are full.
+const CHUNK_SIZE: usize = MAX_BYTES_PER_PACKET - PHONE_PREFIX.len() - FRAGMENT_OVERHEAD;
+/// Fragments in a packet of `MAX_PACKET_SIZE`, nobody sends more.
+const MAX_FRAGMENTS: usize = (MAX_PACKET_SIZE + CHUNK_SIZE - 1) / CHUNK_SIZE;
+
+struct Pending {
+    chunks: Vec<Option<Vec<u8>>>,
+    received: usize,
+    bytes: usize,
+    first_seen: Instant,
+}
+
+/// Puts packets that arrived in fragments back together. They can come in any order,
+/// duplicates are ignored and packets still incomplete after `FRAGMENT_TIMEOUT` are dropped.
+pub struct Reassembler {
+    pending: HashMap<u64, Pending>,
+    /// Chunk bytes held in `pending`.
+    pending_bytes: usize,
+}
+
+impl Reassembler {
+    pub fn new() -> Reassembler {
+        return Reassembler { pending: HashMap::new(), pending_bytes: 0 };
+    }
+
+    /// Adds a fragment, returning the whole packet's encoding once every fragment is here.
+    pub fn add(&mut self, fragment: Fragment) -> Option<Vec<u8>> {
+        self.expire();
+
+        if fragment.count as usize > MAX_FRAGMENTS || fragment.chunk.len() > CHUNK_SIZE {
+            eprintln!("Fragment of {:016x} is bigger than any packet we send, dropping", fragment.message_id);
+            return None;
+        }
+        if self.pending_bytes + fragment.chunk.len() > MAX_PENDING_BYTES {
+            eprintln!("Too many bytes in incomplete packets, dropping fragment of {:016x}", fragment.message_id);
+            return None;
+        }
+        if !self.pending.contains_key(&fragment.message_id) && self.pending.len() >= MAX_PENDING {
+            eprintln!("Too many incomplete packets, dropping fragment of {:016x}", fragment.message_id);
+            return None;
+        }
+
+        let pending = self.pending
+            .entry(fragment.message_id)
+            .or_insert_with(|| Pending {
+                chunks: vec![None; fragment.count as usize],
+                received: 0,
+                bytes: 0,
+                first_seen: Instant::now(),
+            });
+        if pending.chunks.len() != fragment.count as usize {
+            eprintln!("Fragment of {:016x} disagrees on the fragment count, dropping", fragment.message_id);
+            return None;
+        }
+
+        let slot = &mut pending.chunks[fragment.index as usize];
+        if slot.is_none() {
+            pending.bytes += fragment.chunk.len();
+            self.pending_bytes += fragment.chunk.len();
+            *slot = Some(fragment.chunk);
+            pending.received += 1;
+        }
+        if pending.received < pending.chunks.len() {
+            return None;
+        }
+
+        let pending = self.pending.remove(&fragment.message_id).unwrap();
+        self.pending_bytes -= pending.bytes;
+        return Some(pending.chunks.into_iter().flatten().flatten().collect());
+    }
+
+    fn expire(&mut self) {
+        let pending_bytes = &mut self.pending_bytes;
+        self.pending.retain(|message_id, pending| {
+            if pending.first_seen.elapsed() < FRAGMENT_TIMEOUT {
+                return true;
+            }
+
+            *pending_bytes -= pending.bytes;
+            eprintln!(
+                "Dropping packet {:016x}, only {} of {} fragments arrived",
+                message_id,
+                pending.received,
+                pending.chunks.len()
+            );
+            return false;
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::wire::{self, Envelope};
+    use super::*;
+
+    fn fragment(message_id: u64, index: u16, count: u16, len: usize) -> Fragment {
+        return Fragment { message_id, index, count, chunk: vec![index as u8; len] };
+    }
+
+    #[test]
+    fn reassembles_out_of_order() {
+        let encoded = (0..MAX_PACKET_SIZE).map(|byte| byte as u8).collect::<Vec<u8>>();
+        let mut fragments = wire::split(encoded.clone(), 1, CHUNK_SIZE + FRAGMENT_OVERHEAD)
+            .iter()
+            .map(|bytes| match wire::decode_envelope(bytes) {
+                Ok(Envelope::Fragment(fragment)) => fragment,
+                _ => panic!("Expected a fragment")
+            })
+            .collect::<Vec<Fragment>>();
+        assert_eq!(fragments.len(), MAX_FRAGMENTS);
+        fragments.reverse();
+
+        let mut reassembler = Reassembler::new();
+        let last = fragments.pop().unwrap();
+        for fragment in fragments {
+            assert_eq!(reassembler.add(fragment.clone()), None);
+            assert_eq!(reassembler.add(fragment), None);
+        }
+        assert_eq!(reassembler.add(last), Some(encoded));
+        assert_eq!(reassembler.pending_bytes, 0);
+    }
+
+    #[test]
+    fn refuses_fragments_bigger_than_any_packet() {
+        let mut reassembler = Reassembler::new();
+        assert_eq!(reassembler.add(fragment(1, 0, MAX_FRAGMENTS as u16 + 1, 1)), None);
+        assert_eq!(reassembler.add(fragment(2, 0, 2, CHUNK_SIZE + 1)), None);
+        assert!(reassembler.pending.is_empty());
+    }
+
+    #[test]
+    fn keeps_pending_bytes_within_budget() {
+        let mut reassembler = Reassembler::new();
+        let fit = MAX_PENDING_BYTES / CHUNK_SIZE;
+        for message_id in 0..fit as u64 {
+            assert_eq!(reassembler.add(fragment(message_id, 0, 2, CHUNK_SIZE)), None);
+        }
+        assert_eq!(reassembler.add(fragment(fit as u64, 0, 2, CHUNK_SIZE)), None);
+        assert_eq!(reassembler.pending.len(), fit);
+
+        // Finishing a packet frees its bytes for the next.
+        assert!(reassembler.add(fragment(0, 1, 2, 1)).is_some());
+        assert_eq!(reassembler.add(fragment(fit as u64, 0, 2, CHUNK_SIZE)), None);
+        assert_eq!(reassembler.pending.len(), fit);
+    }
+}
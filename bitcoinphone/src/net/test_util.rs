@@ -0,0 +1,25 @@
+//! Helpers for the tests of the relays phones send through.
+use std::sync::mpsc::Receiver;
+use std::time::Duration;
+use sv::messages::{Tx, TxOut};
+use sv::transaction::p2pkh::create_lock_script;
+use sv::util::{Hash160, Hash256};
+use crate::net::tx_bus::TxEvent;
+
+/// A transaction paying nothing to `pubkeyhash`, the lock time tells apart ones to the same key.
+pub fn paying(pubkeyhash: [u8; 20], lock_time: u32) -> Tx {
+    return Tx {
+        version: 1,
+        inputs: vec![],
+        outputs: vec![TxOut { satoshis: 0, lock_script: create_lock_script(&Hash160(pubkeyhash)) }],
+        lock_time,
+    };
+}
+
+/// The hash of the next transaction on the bus, if one turns up in time.
+pub fn received(bus: &Receiver<TxEvent>, timeout: Duration) -> Option<Hash256> {
+    return match bus.recv_timeout(timeout) {
+        Ok(TxEvent::RawTx(tx)) => Some(tx.hash()),
+        _ => None
+    };
+}
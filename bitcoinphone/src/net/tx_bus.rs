@@ -1,13 +1,17 @@
 use std::thread;
 use std::net::IpAddr;
 use sv::messages::{OutPoint, Tx, Message, Addr};
-use std::sync::mpsc::{Sender, Receiver, channel, RecvError, SyncSender, sync_channel};
-use std::sync::{Arc, RwLock};
+use std::sync::mpsc::{Sender, Receiver, channel, RecvError, SyncSender, sync_channel, TrySendError};
+use std::sync::{Arc, Mutex, RwLock};
 use sv::script::op_codes::{OP_FALSE, OP_RETURN};
 use sv::script::Script;
 use std::time::{SystemTime, UNIX_EPOCH};
-use sv::util::Hash160;
+use sv::util::{Hash160, Hash256};
 use crate::util::constants::{PHONE_PREFIX, UTXO, UTXOPacket, DataPacket, Key};
+use crate::crypto::{self, Session, SessionError};
+use crate::domain::call::describe;
+use crate::wire::{self, Envelope, WireError};
+use crate::net::reassembly::Reassembler;
 
 unsafe fn HAS_PHONE_PREFIX(val: Vec<u8>) -> bool {
     if val.len() <= PHONE_PREFIX.len() {
@@ -32,12 +36,29 @@ pub enum TxOperation {
 pub enum TxEvent {
     RawTx(Tx),
     AddPaymentOutput(Script),
+    WatchFrames(SyncSender<FrameTx>),
+    /// Only packets from this call's peer are let through until it's cleared.
+    SetSession(Option<Arc<Session>>),
+    /// Packets from these conference participants are let through too, tagged with who
+    /// sent them.
+    SetConference(Vec<Arc<Session>>),
+}
+
+/// The transaction a voice frame arrived in.
+#[derive(Clone, Debug)]
+pub struct FrameTx {
+    pub counter: u32,
+    pub txid: Hash256,
 }
 
 pub struct TxBus {
     outputs: RwLock<Vec<Script>>,
     funding_sender: SyncSender<UTXOPacket>,
     data_sender: SyncSender<DataPacket>,
+    frame_watchers: RwLock<Vec<SyncSender<FrameTx>>>,
+    session: RwLock<Option<Arc<Session>>>,
+    conference: RwLock<Vec<Arc<Session>>>,
+    reassembler: Mutex<Reassembler>,
 }
 
 impl TxBus {
@@ -48,7 +69,11 @@ impl TxBus {
         let bus = Arc::from(TxBus {
             outputs: RwLock::new(Vec::new()),
             funding_sender,
-            data_sender
+            data_sender,
+            frame_watchers: RwLock::new(Vec::new()),
+            session: RwLock::new(None),
+            conference: RwLock::new(Vec::new()),
+            reassembler: Mutex::new(Reassembler::new()),
         });
 
         return bus.spawn();
@@ -69,6 +94,22 @@ impl TxBus {
                             .unwrap()
                             .push(output);
                     }
+                    Ok(TxEvent::WatchFrames(watcher)) => {
+                        this.frame_watchers
+                            .write()
+                            .unwrap()
+                            .push(watcher);
+                    }
+                    Ok(TxEvent::SetSession(session)) => {
+                        *this.session
+                            .write()
+                            .unwrap() = session;
+                    }
+                    Ok(TxEvent::SetConference(sessions)) => {
+                        *this.conference
+                            .write()
+                            .unwrap() = sessions;
+                    }
                     _ => {
                         panic!("Invalid message passed to bus!");
                     }
@@ -88,14 +129,25 @@ impl TxBus {
         for (index, output) in tx.outputs.iter().enumerate() {
             let output_vec = output.clone().lock_script.0;
             if HAS_PHONE_PREFIX(output_vec.clone()) {
-                let packet_try = bincode::deserialize(&output_vec[PHONE_PREFIX.len()..]);
-                if packet_try.is_err() {
-                    println!("Got garbled message, dropping");
-                    continue;
+                let packet_try = match self.read_packet(&output_vec[PHONE_PREFIX.len()..]) {
+                    Ok(Some(packet)) => packet,
+                    Ok(None) => continue,
+                    Err(err) => {
+                        eprintln!("Skipping phone packet: {}", err);
+                        continue;
+                    }
+                };
+
+                let packet = match self.authenticate(packet_try) {
+                    Some(packet) => packet,
+                    None => continue
+                };
+                if let DataPacket::Data{ counter, .. } = &packet {
+                    self.notify_frame_watchers(FrameTx{ counter: *counter, txid: tx.hash() });
                 }
 
                 self.data_sender
-                    .send(packet_try.unwrap())
+                    .send(packet)
                     .unwrap();
             }
 
@@ -119,6 +171,116 @@ impl TxBus {
         }
     }
 
+    /// Decodes the packet in an output, `None` while it's waiting on more fragments.
+    fn read_packet(&self, bytes: &[u8]) -> Result<Option<DataPacket>, WireError> {
+        return match wire::decode_envelope(bytes)? {
+            Envelope::Packet(packet) => Ok(Some(packet)),
+            Envelope::Fragment(fragment) => match self.reassembler.lock().unwrap().add(fragment) {
+                Some(encoded) => wire::decode(&encoded).map(Some),
+                None => Ok(None)
+            }
+        };
+    }
+
+    /// Opens packets sealed for the call in progress and checks signed ones. Anything
+    /// else is dropped, apart from the starts that set calls up.
+    ///
+    /// Packets from conference participants are opened with their own sessions and come
+    /// out wrapped in `FromParticipant`. Hang ups and refusals sent before a session is up
+    /// come signed and out wrapped in `FromSender`, the domain only takes them from the
+    /// peer it's calling.
+    ///
+    /// Texts, attachments and their acks are always signed, sealed or not, and are only
+    /// let through from the output their key pays.
+    fn authenticate(&self, packet: DataPacket) -> Option<DataPacket> {
+        let session = self.session.read().unwrap();
+        let conference = self.conference.read().unwrap();
+
+        let opened = match packet {
+            DataPacket::Encrypted{ session_id, counter, payload } => {
+                let participant = conference.iter().find(|participant| participant.get_id() == session_id);
+                let opened = match (&*session, participant) {
+                    (Some(session), _) if session.get_id() == session_id => session.open(session_id, counter, payload),
+                    (_, Some(participant)) => participant.open(session_id, counter, payload)
+                        .map(|packet| DataPacket::FromParticipant{ session_id, packet: Box::new(packet) }),
+                    (None, None) if conference.is_empty() => {
+                        eprintln!("Dropping encrypted packet, no call in progress");
+                        return None;
+                    }
+                    _ => Err(SessionError::WrongSession(session_id))
+                };
+
+                match opened {
+                    Ok(packet) => packet,
+                    Err(err) => {
+                        eprintln!("Dropping packet: {}", err);
+                        return None;
+                    }
+                }
+            }
+            packet @ DataPacket::Start{ .. } | packet @ DataPacket::StartAck{ .. } => return Some(packet),
+            packet @ DataPacket::Signed{ .. } => packet,
+            _ => {
+                eprintln!("Dropping unauthenticated packet");
+                return None;
+            }
+        };
+
+        return Self::check_signature(opened);
+    }
+
+    /// Opens signed packets, making sure messages are from whoever signed them and tagging
+    /// hang ups and refusals with the output whose key signed them. Messages that aren't
+    /// signed are dropped.
+    fn check_signature(packet: DataPacket) -> Option<DataPacket> {
+        let (output, packet) = match packet {
+            DataPacket::Signed{ public_key, signature, payload } => match crypto::open_signed(&public_key, &signature, &payload) {
+                Ok(opened) => opened,
+                Err(err) => {
+                    eprintln!("Dropping signed packet: {}", err);
+                    return None;
+                }
+            },
+            DataPacket::FromParticipant{ session_id, packet } => {
+                return Self::check_signature(*packet)
+                    .map(|packet| DataPacket::FromParticipant{ session_id, packet: Box::new(packet) });
+            }
+            DataPacket::Text{ .. } | DataPacket::Attachment{ .. } | DataPacket::MessageAck{ .. } => {
+                eprintln!("Dropping unsigned message");
+                return None;
+            }
+            packet => return Some(packet)
+        };
+
+        return match packet {
+            DataPacket::Text{ ref from, .. } | DataPacket::Attachment{ ref from, .. } | DataPacket::MessageAck{ ref from, .. } => {
+                if *from != output {
+                    eprintln!("Dropping message signed by someone other than its sender");
+                    return None;
+                }
+                Some(packet)
+            }
+            DataPacket::HangUp{ .. } | DataPacket::Busy{ .. } | DataPacket::Reject{ .. } => {
+                Some(DataPacket::FromSender{ output, packet: Box::new(packet) })
+            }
+            packet => {
+                eprintln!("Dropping signed {} packet", describe(&packet));
+                None
+            }
+        };
+    }
+
+    /// Watchers are told without blocking, ones that have hung up are forgotten.
+    fn notify_frame_watchers(&self, frame: FrameTx) {
+        self.frame_watchers
+            .write()
+            .unwrap()
+            .retain(|watcher| match watcher.try_send(frame.clone()) {
+                Err(TrySendError::Disconnected(_)) => false,
+                _ => true
+            });
+    }
+
     fn parse_p2pkh(script_slice: Vec<u8>) -> Hash160 {
         let mut hashed = Hash160::default();
         hashed.0.clone_from_slice(&script_slice[3..23]);
@@ -126,3 +288,175 @@ impl TxBus {
         return hashed;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use secp256k1::{PublicKey, Secp256k1, SecretKey};
+    use super::*;
+
+    const SECRET: [u8; 32] = [7; 32];
+
+    fn bus() -> TxBus {
+        return TxBus {
+            outputs: RwLock::new(vec![]),
+            funding_sender: sync_channel(1).0,
+            data_sender: sync_channel(1).0,
+            frame_watchers: RwLock::new(vec![]),
+            session: RwLock::new(None),
+            conference: RwLock::new(vec![]),
+            reassembler: Mutex::new(Reassembler::new()),
+        };
+    }
+
+    /// Both ends of a session, the first seals and the second opens.
+    fn session(id: u64) -> (Session, Arc<Session>) {
+        return (
+            Session::for_participant(id, &SECRET, 0).unwrap(),
+            Arc::new(Session::for_participant(id, &SECRET, 0).unwrap()),
+        );
+    }
+
+    fn key(byte: u8) -> (SecretKey, Vec<u8>) {
+        let secret_key = SecretKey::from_slice(&[byte; 32]).unwrap();
+        let public_key = PublicKey::from_secret_key(&Secp256k1::new(), &secret_key).serialize().to_vec();
+        return (secret_key, public_key);
+    }
+
+    fn refusals() -> Vec<DataPacket> {
+        return vec![
+            DataPacket::HangUp{ session_id: 1 },
+            DataPacket::Busy{ session_id: 1 },
+            DataPacket::Reject{ session_id: 1, reason: "Declined".to_string() },
+        ];
+    }
+
+    #[test]
+    fn drops_unauthenticated_packets() {
+        let bus = bus();
+
+        for packet in refusals().into_iter().chain(vec![DataPacket::KeepAlive, DataPacket::Sync{ time: 1, count: 1 }]) {
+            assert!(bus.authenticate(packet).is_none());
+        }
+        // Nor during a call, unsealed they could be from anyone.
+        *bus.session.write().unwrap() = Some(session(1).1);
+        for packet in refusals() {
+            assert!(bus.authenticate(packet).is_none());
+        }
+    }
+
+    #[test]
+    fn tags_signed_refusals_with_their_signer() {
+        let bus = bus();
+        let (secret_key, public_key) = key(1);
+
+        for packet in refusals() {
+            match bus.authenticate(crypto::sign(&packet, &secret_key)) {
+                Some(DataPacket::FromSender{ output, packet: tagged }) => {
+                    assert_eq!(output, crypto::output_of(&public_key));
+                    assert_eq!(wire::encode(&tagged), wire::encode(&packet));
+                }
+                other => panic!("Expected a tagged packet, got {:?}", other)
+            }
+        }
+    }
+
+    #[test]
+    fn drops_forged_signatures() {
+        let bus = bus();
+        let (secret_key, _) = key(1);
+        let (_, other_public_key) = key(2);
+
+        match crypto::sign(&DataPacket::HangUp{ session_id: 1 }, &secret_key) {
+            DataPacket::Signed{ public_key, signature, payload } => {
+                let claimed = DataPacket::Signed{ public_key: other_public_key, signature: signature.clone(), payload: payload.clone() };
+                assert!(bus.authenticate(claimed).is_none());
+                let changed = DataPacket::Signed{ public_key, signature, payload: wire::encode(&DataPacket::HangUp{ session_id: 2 }) };
+                assert!(bus.authenticate(changed).is_none());
+            }
+            _ => panic!("Expected a signed packet")
+        }
+        // Only hang ups and refusals are taken signed, everything else needs a session.
+        assert!(bus.authenticate(crypto::sign(&DataPacket::KeepAlive, &secret_key)).is_none());
+    }
+
+    #[test]
+    fn only_takes_messages_signed_by_their_sender() {
+        let bus = bus();
+        let (secret_key, public_key) = key(1);
+        let (other_secret_key, _) = key(2);
+        let from = crypto::output_of(&public_key);
+        let messages = vec![
+            DataPacket::Text{ id: 1, from: from.clone(), text: "hi".to_string() },
+            DataPacket::Attachment{ id: 2, from: from.clone(), name: "a".to_string(), data: vec![1] },
+            DataPacket::MessageAck{ id: 3, from: from.clone() },
+        ];
+
+        for message in messages {
+            assert!(bus.authenticate(message.clone()).is_none());
+            assert!(bus.authenticate(crypto::sign(&message, &other_secret_key)).is_none());
+            match bus.authenticate(crypto::sign(&message, &secret_key)) {
+                Some(packet) => assert_eq!(wire::encode(&packet), wire::encode(&message)),
+                None => panic!("Dropped a message signed by its sender")
+            }
+        }
+    }
+
+    #[test]
+    fn checks_signatures_on_sealed_messages() {
+        let bus = bus();
+        let (sealing, opening) = session(5);
+        *bus.conference.write().unwrap() = vec![opening];
+        let (secret_key, public_key) = key(1);
+        let text = DataPacket::Text{ id: 1, from: crypto::output_of(&public_key), text: "hi".to_string() };
+
+        assert!(bus.authenticate(sealing.seal(&text)).is_none());
+        match bus.authenticate(sealing.seal(&crypto::sign(&text, &secret_key))) {
+            Some(DataPacket::FromParticipant{ session_id: 5, packet }) => assert!(matches!(*packet, DataPacket::Text{ id: 1, .. })),
+            other => panic!("Expected a participant's text, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn opens_packets_sealed_for_the_call() {
+        let bus = bus();
+        let (sealing, opening) = session(1);
+        let (other, _) = session(2);
+
+        assert!(bus.authenticate(sealing.seal(&DataPacket::KeepAlive)).is_none());
+        *bus.session.write().unwrap() = Some(opening);
+        let sealed = sealing.seal(&DataPacket::HangUp{ session_id: 1 });
+        assert!(matches!(bus.authenticate(sealed.clone()), Some(DataPacket::HangUp{ session_id: 1 })));
+        assert!(bus.authenticate(sealed).is_none());
+        assert!(bus.authenticate(other.seal(&DataPacket::KeepAlive)).is_none());
+    }
+
+    #[test]
+    fn tags_packets_from_conference_participants() {
+        let bus = bus();
+        let (sealing, opening) = session(5);
+        *bus.conference.write().unwrap() = vec![opening];
+
+        match bus.authenticate(sealing.seal(&DataPacket::KeepAlive)) {
+            Some(DataPacket::FromParticipant{ session_id: 5, packet }) => assert!(matches!(*packet, DataPacket::KeepAlive)),
+            other => panic!("Expected a participant's packet, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn lets_starts_through() {
+        let bus = bus();
+        *bus.session.write().unwrap() = Some(session(1).1);
+        let start = DataPacket::Start {
+            output: vec![1],
+            session_id: 2,
+            sync_count: 5,
+            codecs: vec![],
+            sample_rate: 8000,
+            frames_per_buffer: 800,
+            public_key: vec![],
+            salt: vec![],
+        };
+
+        assert!(matches!(bus.authenticate(start), Some(DataPacket::Start{ session_id: 2, .. })));
+    }
+}
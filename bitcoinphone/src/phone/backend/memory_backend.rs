@@ -0,0 +1,96 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::io;
+use std::sync::{Arc, Mutex};
+use crate::phone::concealment::SILENCE;
+use super::{AudioBackend, AudioStream, ClockedStream, InputCallback, OutputCallback, StreamSettings};
+
+/// Plays samples from memory into the phone and captures what it plays back.
+///
+/// Clones share the same buffers, so a test can keep one handle and give another to
+/// the phone.
+#[derive(Clone)]
+pub struct MemoryBackend {
+    input: Arc<Mutex<VecDeque<i16>>>,
+    output: Arc<Mutex<Vec<i16>>>,
+    realtime: bool,
+}
+
+impl MemoryBackend {
+    pub fn new(input: Vec<i16>, realtime: bool) -> MemoryBackend {
+        return MemoryBackend {
+            input: Arc::new(Mutex::new(input.into_iter().collect())),
+            output: Arc::new(Mutex::new(vec![])),
+            realtime,
+        };
+    }
+
+    /// Queues more microphone samples.
+    pub fn feed(&self, samples: &[i16]) {
+        self.input
+            .lock()
+            .unwrap()
+            .extend(samples.iter());
+    }
+
+    /// Everything the phone has played so far.
+    pub fn get_output(&self) -> Vec<i16> {
+        return self.output
+            .lock()
+            .unwrap()
+            .clone();
+    }
+}
+
+impl fmt::Debug for MemoryBackend {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return write!(f, "MemoryBackend {{ realtime: {} }}", self.realtime);
+    }
+}
+
+impl AudioBackend for MemoryBackend {
+    fn open_input(&self, settings: &StreamSettings, mut callback: InputCallback) -> io::Result<Box<dyn AudioStream>> {
+        let input = self.input.clone();
+        let frames = settings.frames_per_buffer as usize;
+
+        let tick = move || {
+            let mut buffer = {
+                let mut input = input.lock().unwrap();
+                if input.is_empty() {
+                    return false;
+                }
+                let available = frames.min(input.len());
+                input.drain(..available).collect::<Vec<i16>>()
+            };
+
+            // The last buffer is padded out with silence like a sound card would.
+            buffer.resize(frames, SILENCE);
+            callback(&buffer);
+            return true;
+        };
+
+        return Ok(Box::new(ClockedStream::new(
+            settings.frame_duration(),
+            self.realtime,
+            Box::new(tick)
+        )));
+    }
+
+    fn open_output(&self, settings: &StreamSettings, mut callback: OutputCallback) -> io::Result<Box<dyn AudioStream>> {
+        let output = self.output.clone();
+        let frames = settings.frames_per_buffer as usize;
+
+        let tick = move || {
+            let mut buffer = vec![SILENCE; frames];
+            callback(&mut buffer);
+            output.lock().unwrap().extend_from_slice(&buffer);
+            return true;
+        };
+
+        return Ok(Box::new(ClockedStream::new(
+            settings.frame_duration(),
+            self.realtime,
+            Box::new(tick)
+        )));
+    }
+}
@@ -0,0 +1,176 @@
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use crate::util::constants::SampleFormat;
+
+pub use pa_backend::{PortAudioBackend, DeviceDescription};
+pub use wav_backend::WavBackend;
+pub use memory_backend::MemoryBackend;
+
+mod pa_backend;
+mod wav_backend;
+mod memory_backend;
+
+pub type InputCallback = Box<dyn FnMut(&[i16]) + Send>;
+pub type OutputCallback = Box<dyn FnMut(&mut [i16]) + Send>;
+
+#[derive(Clone, Debug)]
+pub enum BackendConfig {
+    PortAudio,
+    Wav {
+        input: PathBuf,
+        output: PathBuf,
+    },
+    /// Samples from memory, the caller keeps a clone to feed it and read what it played.
+    Memory(MemoryBackend),
+}
+
+/// Picks a sound card device either by PortAudio index or by name.
+#[derive(Clone, Debug)]
+pub enum DeviceSelector {
+    Default,
+    Index(u32),
+    Name(String),
+}
+
+impl DeviceSelector {
+    pub fn parse(value: &str) -> DeviceSelector {
+        return match value.trim().parse::<u32>() {
+            Ok(index) => DeviceSelector::Index(index),
+            Err(_) => DeviceSelector::Name(value.trim().to_string())
+        };
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct AudioDevices {
+    pub input: DeviceSelector,
+    pub output: DeviceSelector,
+}
+
+impl Default for AudioDevices {
+    fn default() -> AudioDevices {
+        return AudioDevices {
+            input: DeviceSelector::Default,
+            output: DeviceSelector::Default,
+        };
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct StreamSettings {
+    pub sample_rate: f64,
+    pub frames_per_buffer: u32,
+    /// Sample type the device is opened with, callbacks always see 16-bit samples.
+    pub sample_format: SampleFormat,
+}
+
+impl StreamSettings {
+    pub fn frame_duration(&self) -> Duration {
+        return Duration::from_secs_f64(self.frames_per_buffer as f64 / self.sample_rate);
+    }
+}
+
+pub trait AudioStream: Send {
+    fn start(&mut self) -> io::Result<()>;
+    fn stop(&mut self) -> io::Result<()>;
+}
+
+/// Where the phone gets its microphone samples from and sends its speaker samples to.
+///
+/// Callbacks are handed one buffer of `frames_per_buffer` mono samples at a time,
+/// mirroring PortAudio's non-blocking streams.
+pub trait AudioBackend {
+    fn open_input(&self, settings: &StreamSettings, callback: InputCallback) -> io::Result<Box<dyn AudioStream>>;
+    fn open_output(&self, settings: &StreamSettings, callback: OutputCallback) -> io::Result<Box<dyn AudioStream>>;
+}
+
+pub fn create(config: &BackendConfig, devices: &AudioDevices) -> io::Result<Box<dyn AudioBackend>> {
+    return Ok(match config {
+        BackendConfig::PortAudio => Box::new(PortAudioBackend::new(devices.clone())?),
+        BackendConfig::Wav { input, output } => Box::new(WavBackend::new(
+            input.clone(),
+            output.clone()
+        )),
+        BackendConfig::Memory(backend) => Box::new(backend.clone()),
+    });
+}
+
+/// Stream driven by its own thread instead of a sound card, one tick per buffer.
+///
+/// The tick returns false once the stream has nothing more to give. When not running
+/// in realtime buffers are produced as fast as the tick allows.
+pub struct ClockedStream {
+    tick: Option<Box<dyn FnMut() -> bool + Send>>,
+    frame_duration: Duration,
+    realtime: bool,
+    running: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl ClockedStream {
+    pub fn new(
+        frame_duration: Duration,
+        realtime: bool,
+        tick: Box<dyn FnMut() -> bool + Send>
+    ) -> ClockedStream {
+        return ClockedStream {
+            tick: Some(tick),
+            frame_duration,
+            realtime,
+            running: Arc::new(AtomicBool::new(false)),
+            handle: None,
+        };
+    }
+}
+
+impl AudioStream for ClockedStream {
+    fn start(&mut self) -> io::Result<()> {
+        let mut tick = match self.tick.take() {
+            Some(tick) => tick,
+            None => return Ok(())
+        };
+
+        let running = self.running.clone();
+        let frame_duration = self.frame_duration;
+        let realtime = self.realtime;
+        running.store(true, Ordering::SeqCst);
+
+        self.handle = Some(thread::spawn(move || {
+            let mut deadline = Instant::now();
+            while running.load(Ordering::SeqCst) {
+                if !tick() {
+                    break;
+                }
+
+                if realtime {
+                    deadline += frame_duration;
+                    let now = Instant::now();
+                    if deadline > now {
+                        thread::sleep(deadline - now);
+                    }
+                }
+            }
+            running.store(false, Ordering::SeqCst);
+        }));
+        return Ok(());
+    }
+
+    fn stop(&mut self) -> io::Result<()> {
+        self.running.store(false, Ordering::SeqCst);
+        if let Some(handle) = self.handle.take() {
+            handle.join().expect("Audio stream thread panicked");
+        }
+        return Ok(());
+    }
+}
+
+impl Drop for ClockedStream {
+    fn drop(&mut self) {
+        self.stop().ok();
+    }
+}
@@ -0,0 +1,201 @@
+use std::io::{self, Error, ErrorKind};
+use portaudio as pa;
+use portaudio::{DeviceIndex, NonBlocking, PortAudio, Stream, Time};
+use crate::phone::concealment::SILENCE;
+use crate::phone::samples::PcmSample;
+use crate::util::constants::SampleFormat;
+use super::{AudioBackend, AudioDevices, AudioStream, DeviceSelector, InputCallback, OutputCallback, StreamSettings};
+
+pub const CHANNELS: i32 = 1;
+pub const INTERLEAVED: bool = true;
+
+#[derive(Clone, Debug)]
+pub struct DeviceDescription {
+    pub index: u32,
+    pub name: String,
+    pub max_input_channels: i32,
+    pub max_output_channels: i32,
+    pub default_sample_rate: f64,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Direction {
+    Input,
+    Output,
+}
+
+/// Sound card input and output through PortAudio.
+pub struct PortAudioBackend {
+    pa: PortAudio,
+    devices: AudioDevices,
+}
+
+impl PortAudioBackend {
+    pub fn new(devices: AudioDevices) -> io::Result<PortAudioBackend> {
+        return Ok(PortAudioBackend { pa: start()?, devices });
+    }
+
+    pub fn list_devices() -> io::Result<Vec<DeviceDescription>> {
+        let pa = start()?;
+
+        return Ok(pa.devices()
+            .map_err(|err| Error::new(ErrorKind::Other, format!("Unable to list audio devices: {}", err)))?
+            .filter_map(|device| device.ok())
+            .map(|(DeviceIndex(index), info)| DeviceDescription {
+                index,
+                name: info.name.to_string(),
+                max_input_channels: info.max_input_channels,
+                max_output_channels: info.max_output_channels,
+                default_sample_rate: info.default_sample_rate,
+            })
+            .collect());
+    }
+
+    /// Finds the device for the selector, returning it with its default low latency.
+    fn resolve(&self, selector: &DeviceSelector, direction: Direction) -> io::Result<(DeviceIndex, Time)> {
+        let index = match selector {
+            DeviceSelector::Default => match direction {
+                Direction::Input => self.pa.default_input_device(),
+                Direction::Output => self.pa.default_output_device(),
+            }.map_err(|_| Error::new(ErrorKind::NotFound, "No default audio device available"))?,
+            DeviceSelector::Index(index) => DeviceIndex(*index),
+            DeviceSelector::Name(name) => self.find_by_name(name, direction)?
+                .ok_or_else(|| Error::new(ErrorKind::NotFound, format!(
+                    "No {} device named '{}', run with --list-devices to see what is available",
+                    if direction == Direction::Input { "input" } else { "output" },
+                    name
+                )))?,
+        };
+
+        let info = self.pa.device_info(index)
+            .map_err(|_| Error::new(ErrorKind::NotFound, format!("Invalid audio device index {}", index.0)))?;
+        eprintln!("Using audio device {}: {}", index.0, info.name);
+
+        return Ok(match direction {
+            Direction::Input => (index, info.default_low_input_latency),
+            Direction::Output => (index, info.default_low_output_latency),
+        });
+    }
+
+    /// Exact name matches win, otherwise the first device containing the name.
+    fn find_by_name(&self, name: &str, direction: Direction) -> io::Result<Option<DeviceIndex>> {
+        let candidates = self.pa.devices()
+            .map_err(|err| Error::new(ErrorKind::Other, format!("Unable to list audio devices: {}", err)))?
+            .filter_map(|device| device.ok())
+            .filter(|(_, info)| match direction {
+                Direction::Input => info.max_input_channels >= CHANNELS,
+                Direction::Output => info.max_output_channels >= CHANNELS,
+            })
+            .map(|(index, info)| (index, info.name.to_string()))
+            .collect::<Vec<(DeviceIndex, String)>>();
+
+        let lowercase_name = name.to_lowercase();
+        return Ok(candidates.iter()
+            .find(|(_, device_name)| device_name == name)
+            .or_else(|| candidates.iter()
+                .find(|(_, device_name)| device_name.to_lowercase().contains(&lowercase_name)))
+            .map(|(index, _)| *index));
+    }
+
+    fn open_input_as<S: PcmSample>(&self, settings: &StreamSettings, mut callback: InputCallback) -> io::Result<Box<dyn AudioStream>> {
+        let (device, latency) = self.resolve(&self.devices.input, Direction::Input)?;
+        let params = pa::StreamParameters::<S>::new(device, CHANNELS, INTERLEAVED, latency);
+
+        self.pa.is_input_format_supported(params, settings.sample_rate)
+            .map_err(|err| Error::new(ErrorKind::Other, format!(
+                "Input device {} can't record {:?} at {} Hz mono: {}",
+                device.0, S::FORMAT, settings.sample_rate, err
+            )))?;
+
+        let input_settings = pa::InputStreamSettings::new(
+            params,
+            settings.sample_rate,
+            settings.frames_per_buffer
+        );
+
+        let mut samples = Vec::with_capacity(settings.frames_per_buffer as usize);
+        let cb = move |pa::InputStreamCallbackArgs{ buffer, .. }: pa::InputStreamCallbackArgs<S>| {
+            samples.clear();
+            samples.extend(buffer.iter().map(|sample| sample.to_pcm()));
+            callback(&samples);
+            return pa::Continue;
+        };
+
+        let stream = self.pa
+            .open_non_blocking_stream(input_settings, cb)
+            .map_err(|err| Error::new(ErrorKind::Other, format!("Unable to start input stream: {}", err)))?;
+
+        return Ok(Box::new(stream));
+    }
+
+    fn open_output_as<S: PcmSample>(&self, settings: &StreamSettings, mut callback: OutputCallback) -> io::Result<Box<dyn AudioStream>> {
+        let (device, latency) = self.resolve(&self.devices.output, Direction::Output)?;
+        let params = pa::StreamParameters::<S>::new(device, CHANNELS, INTERLEAVED, latency);
+
+        self.pa.is_output_format_supported(params, settings.sample_rate)
+            .map_err(|err| Error::new(ErrorKind::Other, format!(
+                "Output device {} can't play {:?} at {} Hz mono: {}",
+                device.0, S::FORMAT, settings.sample_rate, err
+            )))?;
+
+        let output_settings = pa::OutputStreamSettings::with_flags(
+            params,
+            settings.sample_rate,
+            settings.frames_per_buffer,
+            pa::stream_flags::CLIP_OFF
+        );
+
+        let mut samples = vec![];
+        let cb = move |pa::OutputStreamCallbackArgs{ buffer, .. }: pa::OutputStreamCallbackArgs<S>| {
+            samples.clear();
+            samples.resize(buffer.len(), SILENCE);
+            callback(&mut samples);
+            for (out, sample) in buffer.iter_mut().zip(samples.iter()) {
+                *out = S::from_pcm(*sample);
+            }
+            return pa::Continue;
+        };
+
+        let stream = self.pa
+            .open_non_blocking_stream(output_settings, cb)
+            .map_err(|err| Error::new(ErrorKind::Other, format!("Unable to start output stream: {}", err)))?;
+
+        return Ok(Box::new(stream));
+    }
+}
+
+impl AudioBackend for PortAudioBackend {
+    fn open_input(&self, settings: &StreamSettings, callback: InputCallback) -> io::Result<Box<dyn AudioStream>> {
+        return match settings.sample_format {
+            SampleFormat::U8 => self.open_input_as::<u8>(settings, callback),
+            SampleFormat::I16 => self.open_input_as::<i16>(settings, callback),
+            SampleFormat::F32 => self.open_input_as::<f32>(settings, callback),
+        };
+    }
+
+    fn open_output(&self, settings: &StreamSettings, callback: OutputCallback) -> io::Result<Box<dyn AudioStream>> {
+        return match settings.sample_format {
+            SampleFormat::U8 => self.open_output_as::<u8>(settings, callback),
+            SampleFormat::I16 => self.open_output_as::<i16>(settings, callback),
+            SampleFormat::F32 => self.open_output_as::<f32>(settings, callback),
+        };
+    }
+}
+
+impl<F: Send + 'static> AudioStream for Stream<NonBlocking, F> {
+    fn start(&mut self) -> io::Result<()> {
+        return Stream::start(self)
+            .map_err(|err| Error::new(ErrorKind::Other, format!("Unable to start audio stream: {}", err)));
+    }
+
+    fn stop(&mut self) -> io::Result<()> {
+        return Stream::stop(self)
+            .map_err(|err| Error::new(ErrorKind::Other, format!("Unable to stop audio stream: {}", err)));
+    }
+}
+
+/// Starts PortAudio, which fails on hosts without any sound system.
+fn start() -> io::Result<PortAudio> {
+    return pa::PortAudio::new()
+        .map_err(|err| Error::new(ErrorKind::Other, format!("Unable to start PortAudio: {}", err)));
+}
@@ -0,0 +1,128 @@
+use std::io::{self, Error, ErrorKind};
+use std::path::PathBuf;
+use crate::phone::concealment::SILENCE;
+use crate::phone::resampler::Resampler;
+use crate::phone::samples::PcmSample;
+use crate::phone::wav::{self, WavSpec, WavWriter};
+use super::{AudioBackend, AudioStream, ClockedStream, InputCallback, MemoryBackend, OutputCallback, StreamSettings};
+
+/// Reads the microphone from one WAV file and records the speaker to another.
+///
+/// Runs in realtime so a call paced by the fixture behaves like one on a sound card.
+pub struct WavBackend {
+    input: PathBuf,
+    output: PathBuf,
+}
+
+impl WavBackend {
+    pub fn new(input: PathBuf, output: PathBuf) -> WavBackend {
+        return WavBackend { input, output };
+    }
+
+    /// Loads the fixture as mono at the phone's sample rate, keeping only the first channel.
+    fn load_input(&self, settings: &StreamSettings) -> io::Result<Vec<i16>> {
+        let (spec, data) = wav::read(&self.input)
+            .map_err(|err| Error::new(err.kind(), format!("{}: {}", self.input.display(), err)))?;
+
+        let frame_size = spec.block_align() as usize;
+        if frame_size == 0 || spec.sample_rate == 0 {
+            return Err(Error::new(ErrorKind::InvalidData, "WAV file has no samples"));
+        }
+
+        // Wider samples keep their top 16 bits.
+        let samples: Vec<i16> = match spec.bits_per_sample {
+            8 => data.chunks_exact(frame_size)
+                .map(|frame| frame[0].to_pcm())
+                .collect(),
+            16 | 24 | 32 => {
+                let top = (spec.bits_per_sample / 8) as usize - 2;
+                data.chunks_exact(frame_size)
+                    .map(|frame| i16::from_le_bytes([frame[top], frame[top + 1]]))
+                    .collect()
+            }
+            bits => return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Unsupported WAV sample size {} bits", bits)
+            ))
+        };
+
+        return Ok(Resampler::new(spec.sample_rate as f64, settings.sample_rate)?
+            .resample(&samples));
+    }
+}
+
+impl AudioBackend for WavBackend {
+    fn open_input(&self, settings: &StreamSettings, callback: InputCallback) -> io::Result<Box<dyn AudioStream>> {
+        let samples = self.load_input(settings)?;
+
+        return MemoryBackend::new(samples, true)
+            .open_input(settings, callback);
+    }
+
+    fn open_output(&self, settings: &StreamSettings, mut callback: OutputCallback) -> io::Result<Box<dyn AudioStream>> {
+        let mut writer = WavWriter::create(&self.output, WavSpec {
+            channels: 1,
+            sample_rate: settings.sample_rate as u32,
+            bits_per_sample: 16,
+        }).map_err(|err| Error::new(err.kind(), format!("{}: {}", self.output.display(), err)))?;
+
+        let frames = settings.frames_per_buffer as usize;
+
+        let tick = move || {
+            let mut buffer = vec![SILENCE; frames];
+            callback(&mut buffer);
+            // Stops the stream, the call carries on without a speaker.
+            if let Err(err) = writer.write_samples(&buffer) {
+                eprintln!("Unable to write output WAV file: {}", err);
+                return false;
+            }
+            return true;
+        };
+
+        return Ok(Box::new(ClockedStream::new(
+            settings.frame_duration(),
+            true,
+            Box::new(tick)
+        )));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::process;
+    use crate::util::constants::SampleFormat;
+    use super::*;
+
+    fn load(name: &str, spec: WavSpec, data: &[u8]) -> io::Result<Vec<i16>> {
+        let path = std::env::temp_dir().join(format!("bitcoinphone-{}-{}.wav", name, process::id()));
+        WavWriter::create(&path, spec).unwrap()
+            .write_bytes(data).unwrap();
+
+        let settings = StreamSettings {
+            sample_rate: spec.sample_rate as f64,
+            frames_per_buffer: 1,
+            sample_format: SampleFormat::I16,
+        };
+        let samples = WavBackend::new(path.clone(), path.clone()).load_input(&settings);
+        fs::remove_file(&path).ok();
+        return samples;
+    }
+
+    #[test]
+    fn keeps_top_bits_of_wide_samples() {
+        let spec = WavSpec { channels: 2, sample_rate: 8000, bits_per_sample: 24 };
+        let data = [0x56, 0x34, 0x12, 0xff, 0xff, 0xff, 0x00, 0x00, 0x80, 0x01, 0x00, 0x00];
+        assert_eq!(load("24bit", spec, &data).unwrap(), vec![0x1234, i16::MIN]);
+
+        let spec = WavSpec { channels: 1, sample_rate: 8000, bits_per_sample: 32 };
+        let data = [0x78, 0x56, 0x34, 0x12, 0xff, 0xff, 0xff, 0x7f];
+        assert_eq!(load("32bit", spec, &data).unwrap(), vec![0x1234, i16::MAX]);
+    }
+
+    #[test]
+    fn rejects_unsupported_sample_sizes() {
+        let spec = WavSpec { channels: 1, sample_rate: 8000, bits_per_sample: 12 };
+        assert_eq!(load("12bit", spec, &[0, 0]).unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+}
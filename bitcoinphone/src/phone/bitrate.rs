@@ -0,0 +1,273 @@
+use std::time::Duration;
+use crate::phone::PhoneConfig;
+use crate::phone::codec::{self, SUPPORTED_CODECS};
+use crate::phone::fec::FecConfig;
+use crate::phone::stats::CallStatsSnapshot;
+use crate::tx_sender::{MAX_BYTES_PER_PACKET, MIN_DUST, SATS_PER_KB};
+use crate::util::constants::{CodecType, SampleFormat};
+
+/// Codecs from best sounding to cheapest.
+const CODECS_BY_QUALITY: &[CodecType] = &[CodecType::Pcm, CodecType::MuLaw, CodecType::ImaAdpcm];
+/// How many frames we try putting in each transaction, fewer is better for latency.
+const FRAMES_PER_TX: &[u32] = &[1, 2, 4];
+/// The funds left should keep the call going at least this much longer.
+const RUNWAY: Duration = Duration::from_secs(600);
+/// Spare funds needed before moving to better settings, so we don't flip back and forth.
+const STEP_UP_MARGIN: f64 = 1.5;
+/// Loss rate past which we add repair frames if none were asked for.
+const HIGH_LOSS: f64 = 0.05;
+const LOSSY_FEC: FecConfig = FecConfig { group_size: 4, repair_frames: 1 };
+/// Latency we'd rather not pass by waiting to fill transactions.
+const MAX_LATENCY: Duration = Duration::from_secs(3);
+/// Inputs, outputs and the rest of a transaction around its data outputs.
+const TX_OVERHEAD_BYTES: usize = 260;
+/// A dust output for each receiver past the first.
+const RECEIVER_OUTPUT_BYTES: usize = 34;
+/// Prefix, envelope and encryption around each packet.
+const PACKET_OVERHEAD_BYTES: usize = 80;
+
+/// What the phone sends its audio with.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BitrateSettings {
+    pub codec: CodecType,
+    /// Frames held back and sent together in one transaction.
+    pub frames_per_tx: u32,
+    pub fec: Option<FecConfig>,
+}
+
+/// Picks the best settings the call can afford.
+///
+/// The funds left have to cover `RUNWAY` more of the call at the chosen settings, when
+/// they can't we step down to a cheaper codec, more frames per transaction or fewer
+/// repair frames rather than run dry part way through. Repair frames are added when
+/// we're losing a lot of the peer's frames. The peer never says how many of ours arrive,
+/// so we take it the network loses about as many either way.
+pub struct BitrateController {
+    codecs: Vec<CodecType>,
+    fec: Option<FecConfig>,
+    samples_per_frame: usize,
+    format: SampleFormat,
+    frame_duration: Duration,
+    /// Everyone each transaction pays dust to.
+    receivers: usize,
+    current: BitrateSettings,
+}
+
+impl BitrateController {
+    /// `peer_codecs` are the ones the peer can decode, the call's own codec always is.
+    pub fn new(config: &PhoneConfig, peer_codecs: &[CodecType]) -> BitrateController {
+        let codecs = CODECS_BY_QUALITY.iter()
+            .filter(|codec| **codec == config.codec || (peer_codecs.contains(codec) && SUPPORTED_CODECS.contains(codec)))
+            .cloned()
+            .collect();
+
+        return BitrateController {
+            codecs,
+            fec: config.fec,
+            samples_per_frame: config.frames_per_buffer as usize,
+            format: config.sample_format,
+            frame_duration: Duration::from_secs_f64(config.frames_per_buffer as f64 / config.sample_rate),
+            receivers: 1,
+            current: BitrateSettings {
+                codec: config.codec,
+                frames_per_tx: 1,
+                fec: config.fec,
+            },
+        };
+    }
+
+    pub fn get_settings(&self) -> BitrateSettings {
+        return self.current;
+    }
+
+    pub fn set_receivers(&mut self, receivers: usize) {
+        self.receivers = receivers.max(1);
+    }
+
+    /// Picks settings for how the call is going, returning them if they changed.
+    pub fn update(&mut self, stats: &CallStatsSnapshot, funds_left: i64) -> Option<BitrateSettings> {
+        let ladder = self.ladder(stats.loss_rate >= HIGH_LOSS);
+        let current = ladder.iter().position(|settings| *settings == self.current);
+        let latency = Duration::from_secs_f64(stats.latency_ms / 1000.0);
+
+        let affordable = |index: usize, settings: &BitrateSettings| {
+            let margin = match current {
+                Some(current) if index < current => STEP_UP_MARGIN,
+                _ => 1.0
+            };
+            return self.cost_per_second(settings) * RUNWAY.as_secs_f64() * margin <= funds_left as f64;
+        };
+        let quick = |settings: &BitrateSettings| {
+            return latency + self.frame_duration * (settings.frames_per_tx - 1) <= MAX_LATENCY;
+        };
+
+        // Money comes before latency, a late call beats one that stops.
+        let chosen = ladder.iter().enumerate()
+            .find(|(index, settings)| quick(settings) && affordable(*index, settings))
+            .or_else(|| ladder.iter().enumerate().find(|(index, settings)| affordable(*index, settings)))
+            .map(|(_, settings)| *settings)
+            .unwrap_or(ladder[ladder.len() - 1]);
+
+        if chosen == self.current {
+            return None;
+        }
+        self.current = chosen;
+        return Some(chosen);
+    }
+
+    /// Every combination we might send with, best first.
+    fn ladder(&self, lossy: bool) -> Vec<BitrateSettings> {
+        let mut fec_options = vec![self.fec.or(if lossy { Some(LOSSY_FEC) } else { None })];
+        if fec_options[0].is_some() {
+            fec_options.push(None);
+        }
+
+        let mut ladder = vec![];
+        for fec in &fec_options {
+            for codec in &self.codecs {
+                for frames_per_tx in FRAMES_PER_TX {
+                    ladder.push(BitrateSettings { codec: *codec, frames_per_tx: *frames_per_tx, fec: *fec });
+                }
+            }
+        }
+
+        return ladder;
+    }
+
+    /// Rough satoshis a second of audio costs, dust and fees for the transactions plus fees
+    /// for the bytes.
+    fn cost_per_second(&self, settings: &BitrateSettings) -> f64 {
+        let repair_share = settings.fec
+            .map(|fec| fec.repair_frames as f64 / fec.group_size as f64)
+            .unwrap_or(0.0);
+        let packet_bytes = codec::encoded_size(settings.codec, self.samples_per_frame, self.format)
+            + PACKET_OVERHEAD_BYTES;
+
+        // Frames too big for one output go in fragments of their own and can't share.
+        let fragments = (packet_bytes as f64 / MAX_BYTES_PER_PACKET as f64).ceil();
+        let per_tx = if fragments > 1.0 { 1.0 } else { settings.frames_per_tx as f64 };
+        let transactions = fragments * (1.0 + repair_share) / per_tx;
+        let bytes = packet_bytes as f64 * (1.0 + repair_share);
+
+        let sats_per_byte = SATS_PER_KB as f64 / 1000.0;
+        let dust = (MIN_DUST * self.receivers as i64) as f64;
+        let tx_bytes = TX_OVERHEAD_BYTES + RECEIVER_OUTPUT_BYTES * (self.receivers - 1);
+        let per_frame = transactions * (dust + tx_bytes as f64 * sats_per_byte)
+            + bytes * sats_per_byte;
+
+        return per_frame / self.frame_duration.as_secs_f64();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::phone::stats::CallStats;
+    use super::*;
+
+    fn controller() -> BitrateController {
+        let config = PhoneConfig {
+            sample_rate: 8000.0,
+            frames_per_buffer: 800,
+            codec: CodecType::Pcm,
+            sample_format: SampleFormat::I16,
+            fec: None,
+            ..PhoneConfig::default()
+        };
+        return BitrateController::new(&config, SUPPORTED_CODECS);
+    }
+
+    fn stats(loss_rate: f64, latency_ms: f64) -> CallStatsSnapshot {
+        let mut stats = CallStats::new(Duration::from_millis(100), Duration::from_millis(0)).snapshot();
+        stats.loss_rate = loss_rate;
+        stats.latency_ms = latency_ms;
+        return stats;
+    }
+
+    fn settings(codec: CodecType, frames_per_tx: u32) -> BitrateSettings {
+        return BitrateSettings { codec, frames_per_tx, fec: None };
+    }
+
+    /// Just enough to keep sending with the settings for `RUNWAY`, times the margin.
+    fn funds_for(controller: &BitrateController, settings: BitrateSettings, margin: f64) -> i64 {
+        return (controller.cost_per_second(&settings) * RUNWAY.as_secs_f64() * margin).ceil() as i64;
+    }
+
+    #[test]
+    fn orders_the_ladder_from_best_to_cheapest() {
+        let controller = controller();
+        let ladder = controller.ladder(false);
+
+        assert_eq!(ladder.len(), CODECS_BY_QUALITY.len() * FRAMES_PER_TX.len());
+        assert_eq!(ladder[0], settings(CodecType::Pcm, 1));
+        assert_eq!(ladder[1], settings(CodecType::Pcm, 2));
+        assert_eq!(ladder[FRAMES_PER_TX.len()], settings(CodecType::MuLaw, 1));
+        assert_eq!(ladder[ladder.len() - 1], settings(CodecType::ImaAdpcm, 4));
+        for codec in CODECS_BY_QUALITY {
+            let costs = FRAMES_PER_TX.iter()
+                .map(|frames_per_tx| controller.cost_per_second(&settings(*codec, *frames_per_tx)))
+                .collect::<Vec<f64>>();
+            assert!(costs.windows(2).all(|pair| pair[1] < pair[0]), "{:?} doesn't get cheaper", codec);
+        }
+    }
+
+    #[test]
+    fn keeps_the_best_settings_it_can_afford() {
+        let mut controller = controller();
+
+        assert_eq!(controller.update(&stats(0.0, 0.0), i64::MAX), None);
+        assert_eq!(controller.get_settings(), settings(CodecType::Pcm, 1));
+    }
+
+    #[test]
+    fn steps_down_as_funds_run_low() {
+        let mut controller = controller();
+        let batched = settings(CodecType::Pcm, 2);
+
+        let funds = funds_for(&controller, batched, 1.0);
+        assert_eq!(controller.update(&stats(0.0, 0.0), funds), Some(batched));
+        let cheapest = settings(CodecType::ImaAdpcm, 4);
+        assert_eq!(controller.update(&stats(0.0, 0.0), 0), Some(cheapest));
+    }
+
+    #[test]
+    fn needs_a_margin_to_step_back_up() {
+        let mut controller = controller();
+        let best = settings(CodecType::Pcm, 1);
+        controller.update(&stats(0.0, 0.0), funds_for(&controller, settings(CodecType::Pcm, 2), 1.0));
+
+        assert_eq!(controller.update(&stats(0.0, 0.0), funds_for(&controller, best, 1.0)), None);
+        assert_eq!(controller.update(&stats(0.0, 0.0), funds_for(&controller, best, STEP_UP_MARGIN)), Some(best));
+    }
+
+    #[test]
+    fn picks_a_cheaper_codec_over_waiting_on_frames() {
+        let mut controller = controller();
+        let funds = funds_for(&controller, settings(CodecType::Pcm, 4), 1.0);
+
+        assert_eq!(controller.update(&stats(0.0, 0.0), funds), Some(settings(CodecType::Pcm, 4)));
+        // Batching four frames would take the call past `MAX_LATENCY`.
+        let chosen = controller.update(&stats(0.0, 2950.0), funds).unwrap();
+        assert_eq!(chosen.frames_per_tx, 1);
+        assert!(controller.cost_per_second(&chosen) * RUNWAY.as_secs_f64() <= funds as f64);
+        // When nothing is quick enough money still comes first.
+        let mut controller = self::controller();
+        assert_eq!(controller.update(&stats(0.0, 5000.0), funds), Some(settings(CodecType::Pcm, 4)));
+    }
+
+    #[test]
+    fn adds_repair_frames_while_losing_frames() {
+        let mut controller = controller();
+        let protected = BitrateSettings { fec: Some(LOSSY_FEC), ..settings(CodecType::Pcm, 1) };
+
+        assert_eq!(controller.update(&stats(HIGH_LOSS, 0.0), i64::MAX), Some(protected));
+        assert_eq!(controller.update(&stats(0.0, 0.0), i64::MAX), Some(settings(CodecType::Pcm, 1)));
+
+        // Repair frames are kept over a better codec or fewer frames per transaction, and
+        // only go when nothing with them can be afforded.
+        let funds = funds_for(&controller, settings(CodecType::Pcm, 1), 1.0);
+        let chosen = controller.update(&stats(HIGH_LOSS, 0.0), funds).unwrap();
+        assert_eq!(chosen.fec, Some(LOSSY_FEC));
+        assert!(controller.cost_per_second(&chosen) * RUNWAY.as_secs_f64() <= funds as f64);
+        assert_eq!(controller.update(&stats(HIGH_LOSS, 0.0), 0), Some(settings(CodecType::ImaAdpcm, 4)));
+    }
+}
@@ -0,0 +1,360 @@
+use std::convert::TryInto;
+use crate::util::constants::{CodecType, SampleFormat};
+use crate::phone::samples;
+
+/// Codecs we can speak, in order of preference.
+pub const SUPPORTED_CODECS: &[CodecType] = &[CodecType::ImaAdpcm, CodecType::MuLaw, CodecType::Pcm];
+
+/// Turns linear PCM frames into the bytes we put on chain and back.
+///
+/// Every frame is coded independently, a lost transaction must never corrupt the
+/// frames that follow it. `format` is the sample format the sending phone captured
+/// in, only raw PCM keeps it on the wire.
+pub trait AudioCodec: Send + Sync {
+    fn codec_type(&self) -> CodecType;
+    fn encode(&self, samples: &[i16], format: SampleFormat) -> Vec<u8>;
+    fn decode(&self, data: &[u8], format: SampleFormat) -> Vec<i16>;
+}
+
+pub fn get_codec(codec_type: CodecType) -> Box<dyn AudioCodec> {
+    return match codec_type {
+        CodecType::Pcm => Box::new(PcmCodec),
+        CodecType::MuLaw => Box::new(MuLawCodec),
+        CodecType::ImaAdpcm => Box::new(ImaAdpcmCodec),
+    };
+}
+
+/// Bytes a frame of `samples` samples takes once encoded with `codec_type`.
+pub fn encoded_size(codec_type: CodecType, samples: usize, format: SampleFormat) -> usize {
+    return match codec_type {
+        CodecType::Pcm => samples * samples::bytes_per_sample(format),
+        CodecType::MuLaw => samples,
+        CodecType::ImaAdpcm => IMA_HEADER_SIZE + samples / 2,
+    };
+}
+
+/// Picks the first codec the caller offered that we also support.
+pub fn negotiate(offered: &[CodecType]) -> CodecType {
+    return offered.iter()
+        .find(|codec| SUPPORTED_CODECS.contains(codec))
+        .cloned()
+        .unwrap_or(CodecType::Pcm);
+}
+
+/// Uncompressed samples in the sender's capture format.
+pub struct PcmCodec;
+
+impl AudioCodec for PcmCodec {
+    fn codec_type(&self) -> CodecType {
+        return CodecType::Pcm;
+    }
+
+    fn encode(&self, samples: &[i16], format: SampleFormat) -> Vec<u8> {
+        return samples::encode(samples, format);
+    }
+
+    fn decode(&self, data: &[u8], format: SampleFormat) -> Vec<i16> {
+        return samples::decode(data, format);
+    }
+}
+
+const MULAW_BIAS: i32 = 0x84;
+const MULAW_CLIP: i32 = 32635;
+
+/// G.711 µ-law, one byte per sample.
+pub struct MuLawCodec;
+
+impl MuLawCodec {
+    pub fn encode_sample(sample: i16) -> u8 {
+        let mut pcm = sample as i32;
+        let sign = if pcm < 0 {
+            pcm = -pcm;
+            0x80
+        } else {
+            0
+        };
+
+        pcm = pcm.min(MULAW_CLIP) + MULAW_BIAS;
+
+        let mut exponent = 7;
+        let mut mask = 0x4000;
+        while exponent > 0 && pcm & mask == 0 {
+            exponent -= 1;
+            mask >>= 1;
+        }
+
+        let mantissa = (pcm >> (exponent + 3)) & 0x0F;
+        return !((sign | (exponent << 4) | mantissa) as u8);
+    }
+
+    pub fn decode_sample(byte: u8) -> i16 {
+        let byte = !byte;
+        let exponent = ((byte >> 4) & 0x07) as i32;
+        let mantissa = (byte & 0x0F) as i32;
+        let magnitude = (((mantissa << 3) + MULAW_BIAS) << exponent) - MULAW_BIAS;
+
+        return if byte & 0x80 != 0 {
+            -magnitude as i16
+        } else {
+            magnitude as i16
+        };
+    }
+}
+
+impl AudioCodec for MuLawCodec {
+    fn codec_type(&self) -> CodecType {
+        return CodecType::MuLaw;
+    }
+
+    fn encode(&self, samples: &[i16], _format: SampleFormat) -> Vec<u8> {
+        return samples.iter()
+            .map(|sample| Self::encode_sample(*sample))
+            .collect();
+    }
+
+    fn decode(&self, data: &[u8], _format: SampleFormat) -> Vec<i16> {
+        return data.iter()
+            .map(|byte| Self::decode_sample(*byte))
+            .collect();
+    }
+}
+
+const IMA_INDEX_TABLE: [i32; 16] = [-1, -1, -1, -1, 2, 4, 6, 8, -1, -1, -1, -1, 2, 4, 6, 8];
+const IMA_STEP_TABLE: [i32; 89] = [
+    7, 8, 9, 10, 11, 12, 13, 14, 16, 17, 19, 21, 23, 25, 28, 31, 34, 37, 41, 45, 50, 55, 60,
+    66, 73, 80, 88, 97, 107, 118, 130, 143, 157, 173, 190, 209, 230, 253, 279, 307, 337, 371,
+    408, 449, 494, 544, 598, 658, 724, 796, 876, 963, 1060, 1166, 1282, 1411, 1552, 1707,
+    1878, 2066, 2272, 2499, 2749, 3024, 3327, 3660, 4026, 4428, 4871, 5358, 5894, 6484, 7132,
+    7845, 8630, 9493, 10442, 11487, 12635, 13899, 15289, 16818, 18500, 20350, 22385, 24623,
+    27086, 29794, 32767,
+];
+/// Sample count (u32), initial predictor (i16) and initial step index (u8).
+const IMA_HEADER_SIZE: usize = 7;
+
+/// IMA ADPCM, four bits per sample.
+///
+/// Each frame starts with a header holding the first sample and step index, the
+/// remaining samples are packed two per byte, low nibble first.
+pub struct ImaAdpcmCodec;
+
+struct ImaState {
+    predictor: i32,
+    index: i32,
+}
+
+impl ImaState {
+    fn update(&mut self, nibble: u8) {
+        let step = IMA_STEP_TABLE[self.index as usize];
+        let mut delta = step >> 3;
+        if nibble & 4 != 0 {
+            delta += step;
+        }
+        if nibble & 2 != 0 {
+            delta += step >> 1;
+        }
+        if nibble & 1 != 0 {
+            delta += step >> 2;
+        }
+
+        if nibble & 8 != 0 {
+            self.predictor -= delta;
+        } else {
+            self.predictor += delta;
+        }
+
+        self.predictor = self.predictor.max(i16::MIN as i32).min(i16::MAX as i32);
+        self.index = (self.index + IMA_INDEX_TABLE[nibble as usize]).max(0).min(88);
+    }
+
+    fn encode(&mut self, sample: i16) -> u8 {
+        let step = IMA_STEP_TABLE[self.index as usize];
+        let mut diff = sample as i32 - self.predictor;
+        let mut nibble = 0;
+
+        if diff < 0 {
+            nibble = 8;
+            diff = -diff;
+        }
+        if diff >= step {
+            nibble |= 4;
+            diff -= step;
+        }
+        if diff >= step >> 1 {
+            nibble |= 2;
+            diff -= step >> 1;
+        }
+        if diff >= step >> 2 {
+            nibble |= 1;
+        }
+
+        // Track the decoder's reconstruction rather than the input so errors don't add up.
+        self.update(nibble);
+        return nibble;
+    }
+}
+
+impl AudioCodec for ImaAdpcmCodec {
+    fn codec_type(&self) -> CodecType {
+        return CodecType::ImaAdpcm;
+    }
+
+    fn encode(&self, samples: &[i16], _format: SampleFormat) -> Vec<u8> {
+        let mut data = Vec::with_capacity(IMA_HEADER_SIZE + samples.len() / 2 + 1);
+        data.extend_from_slice(&(samples.len() as u32).to_le_bytes());
+
+        if samples.is_empty() {
+            return data;
+        }
+
+        // Start from the step that best fits the first movement in the frame.
+        let first_delta = samples.get(1)
+            .map(|next| (*next as i32 - samples[0] as i32).abs())
+            .unwrap_or(0);
+        let index = IMA_STEP_TABLE.iter()
+            .position(|step| *step >= first_delta)
+            .unwrap_or(88) as i32;
+
+        data.extend_from_slice(&samples[0].to_le_bytes());
+        data.push(index as u8);
+
+        let mut state = ImaState { predictor: samples[0] as i32, index };
+        for pair in samples[1..].chunks(2) {
+            let low = state.encode(pair[0]);
+            let high = match pair.get(1) {
+                Some(sample) => state.encode(*sample),
+                None => 0
+            };
+            data.push(low | (high << 4));
+        }
+
+        return data;
+    }
+
+    fn decode(&self, data: &[u8], _format: SampleFormat) -> Vec<i16> {
+        if data.len() < 4 {
+            return vec![];
+        }
+
+        let count = u32::from_le_bytes(data[0..4].try_into().unwrap()) as usize;
+        if count == 0 || data.len() < IMA_HEADER_SIZE {
+            return vec![];
+        }
+
+        let first = i16::from_le_bytes(data[4..6].try_into().unwrap());
+        let mut state = ImaState {
+            predictor: first as i32,
+            index: (data[6] as i32).min(88),
+        };
+
+        // The count comes from the peer, there can't be more samples than the nibbles sent.
+        let count = count.min(1 + 2 * (data.len() - IMA_HEADER_SIZE));
+        let mut samples = Vec::with_capacity(count);
+        samples.push(first);
+
+        'bytes: for byte in &data[IMA_HEADER_SIZE..] {
+            for nibble in [byte & 0x0F, byte >> 4].iter() {
+                if samples.len() >= count {
+                    break 'bytes;
+                }
+                state.update(*nibble);
+                samples.push(state.predictor as i16);
+            }
+        }
+
+        return samples;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f64::consts::PI;
+    use super::*;
+
+    /// A second of 440 Hz at 8 kHz, loud enough to use most of the range.
+    fn tone() -> Vec<i16> {
+        return (0..8000)
+            .map(|sample| ((sample as f64 * 440.0 * 2.0 * PI / 8000.0).sin() * 20000.0) as i16)
+            .collect();
+    }
+
+    fn energy(samples: impl Iterator<Item = i64>) -> i64 {
+        return samples.map(|sample| sample * sample).sum();
+    }
+
+    #[test]
+    fn encodes_known_mu_law_values() {
+        assert_eq!(MuLawCodec::encode_sample(0), 0xFF);
+        assert_eq!(MuLawCodec::encode_sample(i16::MAX), 0x80);
+        assert_eq!(MuLawCodec::encode_sample(i16::MIN), 0x00);
+        assert_eq!(MuLawCodec::decode_sample(0xFF), 0);
+        assert_eq!(MuLawCodec::decode_sample(0x80), 32124);
+        assert_eq!(MuLawCodec::decode_sample(0x00), -32124);
+    }
+
+    #[test]
+    fn round_trips_mu_law() {
+        let codec = get_codec(CodecType::MuLaw);
+        let samples = (i16::MIN..=i16::MAX).step_by(7).collect::<Vec<i16>>();
+        let encoded = codec.encode(&samples, SampleFormat::I16);
+        assert_eq!(encoded.len(), encoded_size(CodecType::MuLaw, samples.len(), SampleFormat::I16));
+
+        // Each segment's step doubles, so the error grows with the sample.
+        for (sample, decoded) in samples.iter().zip(codec.decode(&encoded, SampleFormat::I16)) {
+            let error = (*sample as i32 - decoded as i32).abs();
+            assert!(error <= (*sample as i32).abs() / 8 + 16, "{} came back as {}", sample, decoded);
+        }
+    }
+
+    #[test]
+    fn writes_ima_adpcm_header() {
+        let codec = get_codec(CodecType::ImaAdpcm);
+        let samples = tone();
+        let encoded = codec.encode(&samples[..800], SampleFormat::I16);
+
+        assert_eq!(encoded.len(), encoded_size(CodecType::ImaAdpcm, 800, SampleFormat::I16));
+        assert_eq!(encoded[0..4], 800u32.to_le_bytes());
+        assert_eq!(encoded[4..6], samples[0].to_le_bytes());
+        assert!(encoded[6] <= 88);
+
+        // An odd sample count leaves the last high nibble unused.
+        let encoded = codec.encode(&samples[..5], SampleFormat::I16);
+        assert_eq!(encoded.len(), IMA_HEADER_SIZE + 2);
+        assert_eq!(codec.decode(&encoded, SampleFormat::I16).len(), 5);
+    }
+
+    #[test]
+    fn round_trips_ima_adpcm() {
+        let codec = get_codec(CodecType::ImaAdpcm);
+        let samples = tone();
+
+        for frame in samples.chunks(800) {
+            let decoded = codec.decode(&codec.encode(frame, SampleFormat::I16), SampleFormat::I16);
+            assert_eq!(decoded.len(), frame.len());
+            assert_eq!(decoded[0], frame[0]);
+
+            // At least 20 dB of signal to noise.
+            let signal = energy(frame.iter().map(|sample| *sample as i64));
+            let noise = energy(frame.iter().zip(&decoded).map(|(sample, decoded)| *sample as i64 - *decoded as i64));
+            assert!(noise * 100 < signal, "Noise {} against signal {}", noise, signal);
+        }
+        assert!(codec.decode(&codec.encode(&[], SampleFormat::I16), SampleFormat::I16).is_empty());
+    }
+
+    #[test]
+    fn ignores_ima_adpcm_counts_past_the_data() {
+        let codec = get_codec(CodecType::ImaAdpcm);
+        let mut frame = vec![0xff, 0xff, 0xff, 0xff, 0x10, 0x00, 0x00];
+
+        assert_eq!(codec.decode(&frame, SampleFormat::I16), vec![0x10]);
+        frame.push(0x00);
+        assert_eq!(codec.decode(&frame, SampleFormat::I16).len(), 3);
+    }
+
+    #[test]
+    fn negotiates_in_the_callers_order() {
+        assert_eq!(negotiate(SUPPORTED_CODECS), CodecType::ImaAdpcm);
+        assert_eq!(negotiate(&[CodecType::MuLaw, CodecType::ImaAdpcm]), CodecType::MuLaw);
+        assert_eq!(negotiate(&[CodecType::Pcm, CodecType::MuLaw]), CodecType::Pcm);
+        assert_eq!(negotiate(&[]), CodecType::Pcm);
+    }
+}
@@ -0,0 +1,174 @@
+use rand::Rng;
+
+pub const SILENCE: i16 = 0;
+/// Loudest comfort noise we will generate, whatever level the sender asks for.
+const MAX_COMFORT_NOISE: u16 = 2048;
+/// Gaps up to this many frames are bridged by fading out the last good frame.
+pub const MAX_CONCEALED_FRAMES: u32 = 3;
+
+/// Fills in voice frames that never arrived.
+///
+/// Short gaps repeat the previous frame with a decreasing gain so a single lost
+/// transaction doesn't drop out entirely. Anything longer than `MAX_CONCEALED_FRAMES`
+/// plays silence rather than looping stale audio.
+pub struct Concealer {
+    last_frame: Option<Vec<i16>>,
+    consecutive_losses: u32,
+    concealed: u64,
+}
+
+impl Concealer {
+    pub fn new() -> Concealer {
+        return Concealer {
+            last_frame: None,
+            consecutive_losses: 0,
+            concealed: 0,
+        };
+    }
+
+    /// Records a frame that was played as received.
+    pub fn received(&mut self, frame: &[i16]) {
+        self.consecutive_losses = 0;
+        self.last_frame = Some(frame.to_vec());
+    }
+
+    /// Produces a replacement for a missing frame, `gap` being the number of frames
+    /// missing before the next one we have, including this one.
+    pub fn conceal(&mut self, len: usize, gap: u32) -> Vec<i16> {
+        self.consecutive_losses += 1;
+        self.concealed += 1;
+
+        // The whole gap is the frames already concealed plus the ones still missing.
+        let losses = self.consecutive_losses;
+        if losses + gap - 1 > MAX_CONCEALED_FRAMES {
+            return Self::silence(len);
+        }
+
+        return match &self.last_frame {
+            Some(frame) if frame.len() == len => {
+                let gain = 1.0 - losses as f32 / (MAX_CONCEALED_FRAMES + 1) as f32;
+                Self::fade(frame, gain)
+            }
+            _ => Self::silence(len)
+        };
+    }
+
+    /// Low level noise for stretches the sender marked as silent, so the line doesn't
+    /// sound dead.
+    pub fn comfort_noise(&mut self, len: usize, level: u16) -> Vec<i16> {
+        self.consecutive_losses = 0;
+        self.last_frame = None;
+
+        let amplitude = level.max(1).min(MAX_COMFORT_NOISE) as i16;
+        let mut rng = rand::thread_rng();
+
+        return (0..len)
+            .map(|_| rng.gen_range(-amplitude, amplitude + 1))
+            .collect();
+    }
+
+    pub fn get_concealed_count(&self) -> u64 {
+        return self.concealed;
+    }
+
+    pub fn silence(len: usize) -> Vec<i16> {
+        return vec![SILENCE; len];
+    }
+
+    /// Scales the frame towards silence, ramping the gain down across the frame so
+    /// consecutive concealed frames join up without a click.
+    fn fade(frame: &[i16], gain: f32) -> Vec<i16> {
+        let fade_per_frame = 1.0 / (MAX_CONCEALED_FRAMES + 1) as f32;
+        let step = fade_per_frame / frame.len().max(1) as f32;
+
+        return frame.iter()
+            .enumerate()
+            .map(|(index, sample)| {
+                let sample_gain = (gain + fade_per_frame - step * index as f32).max(0.0);
+                (*sample as f32 * sample_gain).round() as i16
+            })
+            .collect();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peak(frame: &[i16]) -> i16 {
+        return frame.iter().map(|sample| sample.abs()).max().unwrap_or(0);
+    }
+
+    #[test]
+    fn fades_the_last_frame_over_a_short_gap() {
+        let mut concealer = Concealer::new();
+        concealer.received(&[8000; 100]);
+
+        let mut last_peak = 8000;
+        for missing in 0..MAX_CONCEALED_FRAMES {
+            let frame = concealer.conceal(100, MAX_CONCEALED_FRAMES - missing);
+            assert_eq!(frame.len(), 100);
+            assert!(frame.iter().all(|sample| *sample > 0), "Frame {} isn't the last one repeated", missing);
+            // Each frame ramps down across itself and starts where the one before ended.
+            assert!(frame.windows(2).all(|pair| pair[1] <= pair[0]));
+            assert!(peak(&frame) <= last_peak);
+            last_peak = frame[frame.len() - 1];
+        }
+        // Down to a quarter by the end of the last one.
+        assert!((last_peak - 2000).abs() <= 100, "Faded to {}", last_peak);
+        assert_eq!(concealer.get_concealed_count(), MAX_CONCEALED_FRAMES as u64);
+    }
+
+    #[test]
+    fn plays_silence_over_a_long_gap() {
+        let mut concealer = Concealer::new();
+        concealer.received(&[8000; 100]);
+
+        let gap = MAX_CONCEALED_FRAMES + 1;
+        for missing in 0..gap {
+            assert_eq!(concealer.conceal(100, gap - missing), Concealer::silence(100));
+        }
+        assert_eq!(concealer.get_concealed_count(), gap as u64);
+    }
+
+    #[test]
+    fn stops_repeating_once_a_gap_grows_too_long() {
+        let mut concealer = Concealer::new();
+        concealer.received(&[8000; 100]);
+
+        // Gaps that looked short can grow as more frames go missing behind them.
+        for _ in 0..MAX_CONCEALED_FRAMES {
+            assert_ne!(concealer.conceal(100, 1), Concealer::silence(100));
+        }
+        assert_eq!(concealer.conceal(100, 1), Concealer::silence(100));
+
+        // A frame arriving starts over.
+        concealer.received(&[8000; 100]);
+        assert_ne!(concealer.conceal(100, 1), Concealer::silence(100));
+    }
+
+    #[test]
+    fn plays_silence_without_a_frame_to_repeat() {
+        let mut concealer = Concealer::new();
+        assert_eq!(concealer.conceal(100, 1), Concealer::silence(100));
+
+        // Nor when the last frame was a different length.
+        concealer.received(&[8000; 50]);
+        assert_eq!(concealer.conceal(100, 1), Concealer::silence(100));
+    }
+
+    #[test]
+    fn keeps_comfort_noise_quiet() {
+        let mut concealer = Concealer::new();
+
+        let noise = concealer.comfort_noise(1000, 300);
+        assert_eq!(noise.len(), 1000);
+        assert!(peak(&noise) <= 300 && peak(&noise) > 0);
+        assert!(peak(&concealer.comfort_noise(1000, u16::MAX)) <= MAX_COMFORT_NOISE as i16);
+
+        // Comfort noise isn't repeated into a gap after it.
+        concealer.received(&[8000; 100]);
+        concealer.comfort_noise(100, 300);
+        assert_eq!(concealer.conceal(100, 1), Concealer::silence(100));
+    }
+}
@@ -0,0 +1,286 @@
+use std::collections::BTreeMap;
+use crate::util::constants::{CodecType, DataPacket, SampleFormat};
+
+/// Largest group of frames that can share repair frames.
+pub const MAX_GROUP_SIZE: u32 = 16;
+/// Received frames kept around to rebuild others from.
+const HISTORY_FRAMES: usize = 2 * MAX_GROUP_SIZE as usize;
+/// Repair frames kept waiting for the frames they cover.
+const MAX_PENDING_REPAIRS: usize = 16;
+
+/// How much redundancy we send. Every `group_size` data frames are followed by
+/// `repair_frames` repair frames, each able to rebuild one lost frame of the group.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FecConfig {
+    pub group_size: u32,
+    pub repair_frames: u32,
+}
+
+impl FecConfig {
+    /// Parses `<group size>:<repair frames>`, for example `4:1`.
+    pub fn parse(value: &str) -> Option<FecConfig> {
+        let mut parts = value.splitn(2, ':');
+        let group_size = parts.next()?.parse::<u32>().ok()?;
+        let repair_frames = parts.next()?.parse::<u32>().ok()?;
+
+        if group_size == 0 || group_size > MAX_GROUP_SIZE || repair_frames == 0 || repair_frames > group_size {
+            return None;
+        }
+
+        return Some(FecConfig { group_size, repair_frames });
+    }
+}
+
+/// Builds XOR repair frames for the data frames we send.
+///
+/// The frames of a group are interleaved across its repair frames, repair frame `j` covers
+/// every frame whose position in the group is `j` modulo the number of repair frames. A
+/// group survives as many losses as it has repair frames, as long as no two lost frames
+/// share one.
+pub struct FecEncoder {
+    config: FecConfig,
+    group: Vec<(u32, Vec<u8>)>,
+    format: SampleFormat,
+    codec: Option<CodecType>,
+}
+
+impl FecEncoder {
+    /// Every frame added has to be in `codec`, start a new encoder when it changes.
+    pub fn new(config: FecConfig, format: SampleFormat, codec: Option<CodecType>) -> FecEncoder {
+        return FecEncoder { config, group: vec![], format, codec };
+    }
+
+    /// Adds a frame we've sent, returning the group's repair frames once it's complete.
+    pub fn add(&mut self, counter: u32, buffer: &[u8]) -> Vec<DataPacket> {
+        self.group.push((counter, buffer.to_vec()));
+        if self.group.len() < self.config.group_size as usize {
+            return vec![];
+        }
+
+        return self.flush();
+    }
+
+    /// Repair frames for whatever is in the current group, used when the sender goes quiet
+    /// part way through one.
+    pub fn flush(&mut self) -> Vec<DataPacket> {
+        let group = std::mem::replace(&mut self.group, vec![]);
+        let repair_frames = self.config.repair_frames as usize;
+
+        return (0..repair_frames.min(group.len()))
+            .map(|offset| {
+                let covered = group.iter()
+                    .skip(offset)
+                    .step_by(repair_frames)
+                    .collect::<Vec<&(u32, Vec<u8>)>>();
+
+                let mut parity = vec![];
+                let mut length = 0;
+                for (_, buffer) in &covered {
+                    xor_into(&mut parity, buffer);
+                    length ^= buffer.len() as u32;
+                }
+
+                DataPacket::Repair {
+                    counters: covered.iter().map(|(counter, _)| *counter).collect(),
+                    format: self.format,
+                    length,
+                    parity,
+                    codec: self.codec,
+                }
+            })
+            .collect();
+    }
+}
+
+struct PendingRepair {
+    counters: Vec<u32>,
+    format: SampleFormat,
+    length: u32,
+    parity: Vec<u8>,
+    codec: Option<CodecType>,
+}
+
+/// Rebuilds lost data frames from the peer's repair frames.
+///
+/// Nothing is kept until the first repair frame arrives, so a peer that doesn't send
+/// any costs us nothing.
+pub struct FecDecoder {
+    enabled: bool,
+    received: BTreeMap<u32, Vec<u8>>,
+    pending: Vec<PendingRepair>,
+}
+
+impl FecDecoder {
+    pub fn new() -> FecDecoder {
+        return FecDecoder {
+            enabled: false,
+            received: BTreeMap::new(),
+            pending: vec![],
+        };
+    }
+
+    /// Notes a data frame from the peer, returning any frames it lets us rebuild.
+    pub fn received(&mut self, counter: u32, buffer: &[u8]) -> Vec<DataPacket> {
+        if !self.enabled {
+            return vec![];
+        }
+
+        self.remember(counter, buffer.to_vec());
+        return self.recover();
+    }
+
+    /// Takes a repair frame from the peer, returning any frames it lets us rebuild.
+    pub fn repair(
+        &mut self,
+        counters: Vec<u32>,
+        format: SampleFormat,
+        length: u32,
+        parity: Vec<u8>,
+        codec: Option<CodecType>
+    ) -> Vec<DataPacket> {
+        self.enabled = true;
+
+        if self.pending.len() >= MAX_PENDING_REPAIRS {
+            self.pending.remove(0);
+        }
+        self.pending.push(PendingRepair { counters, format, length, parity, codec });
+
+        return self.recover();
+    }
+
+    fn remember(&mut self, counter: u32, buffer: Vec<u8>) {
+        self.received.insert(counter, buffer);
+        while self.received.len() > HISTORY_FRAMES {
+            let oldest = *self.received.keys().next().unwrap();
+            self.received.remove(&oldest);
+        }
+    }
+
+    fn recover(&mut self) -> Vec<DataPacket> {
+        let mut recovered = vec![];
+        let oldest = self.received.keys().next().cloned().unwrap_or(0);
+
+        let received = &self.received;
+        self.pending.retain(|repair| {
+            // Frames older than our history are either played or lost for good.
+            if repair.counters.iter().all(|counter| *counter < oldest) {
+                return false;
+            }
+
+            let missing = repair.counters.iter()
+                .filter(|counter| !received.contains_key(counter))
+                .collect::<Vec<&u32>>();
+            if missing.len() > 1 {
+                return true;
+            }
+            if let Some(counter) = missing.first() {
+                recovered.push(Self::rebuild(repair, **counter, received));
+            }
+            return false;
+        });
+
+        for packet in &recovered {
+            if let DataPacket::Data{ counter, buffer, .. } = packet {
+                self.remember(*counter, buffer.clone());
+            }
+        }
+        return recovered;
+    }
+
+    fn rebuild(repair: &PendingRepair, lost: u32, received: &BTreeMap<u32, Vec<u8>>) -> DataPacket {
+        let mut buffer = repair.parity.clone();
+        let mut length = repair.length;
+        for counter in repair.counters.iter().filter(|counter| **counter != lost) {
+            let other = &received[counter];
+            xor_into(&mut buffer, other);
+            length ^= other.len() as u32;
+        }
+        buffer.truncate(length as usize);
+
+        return DataPacket::Data {
+            counter: lost,
+            format: repair.format,
+            buffer,
+            codec: repair.codec,
+        };
+    }
+}
+
+/// XORs `buffer` into `parity`, shorter buffers count as zero padded.
+fn xor_into(parity: &mut Vec<u8>, buffer: &[u8]) {
+    if parity.len() < buffer.len() {
+        parity.resize(buffer.len(), 0);
+    }
+
+    parity.iter_mut()
+        .zip(buffer)
+        .for_each(|(parity, byte)| *parity ^= byte);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(counter: u32, length: usize) -> Vec<u8> {
+        return (0..length).map(|index| (counter as usize * 31 + index) as u8).collect();
+    }
+
+    /// Hands a repair frame from the encoder to the decoder.
+    fn repair(decoder: &mut FecDecoder, packet: DataPacket) -> Vec<DataPacket> {
+        return match packet {
+            DataPacket::Repair{ counters, format, length, parity, codec } => decoder.repair(counters, format, length, parity, codec),
+            _ => panic!("Expected a repair frame")
+        };
+    }
+
+    fn rebuilt(packets: Vec<DataPacket>) -> Vec<(u32, Vec<u8>)> {
+        return packets.into_iter()
+            .map(|packet| match packet {
+                DataPacket::Data{ counter, buffer, .. } => (counter, buffer),
+                _ => panic!("Expected a data frame")
+            })
+            .collect();
+    }
+
+    #[test]
+    fn rebuilds_lost_frames_of_mixed_lengths() {
+        let config = FecConfig::parse("3:1").unwrap();
+        let mut encoder = FecEncoder::new(config, SampleFormat::I16, None);
+        let mut decoder = FecDecoder::new();
+        let lengths = [5, 9, 2];
+
+        // The first repair frame turns the decoder on, then the shortest frame is lost and
+        // then the longest, the lengths decide how much of the parity is theirs.
+        for (group, lost) in vec![(0, None), (1, Some(2)), (2, Some(1))] {
+            let mut repairs = vec![];
+            for (position, length) in lengths.iter().enumerate() {
+                let counter = group * 3 + position as u32;
+                repairs.extend(encoder.add(counter, &frame(counter, *length)));
+                if lost != Some(position) {
+                    assert!(decoder.received(counter, &frame(counter, *length)).is_empty());
+                }
+            }
+
+            assert_eq!(repairs.len(), 1);
+            let expected = lost
+                .map(|position| group * 3 + position as u32)
+                .map(|counter| (counter, frame(counter, lengths[(counter % 3) as usize])));
+            assert_eq!(rebuilt(repair(&mut decoder, repairs.remove(0))), expected.into_iter().collect::<Vec<(u32, Vec<u8>)>>());
+        }
+    }
+
+    #[test]
+    fn waits_for_the_rest_of_the_group() {
+        let config = FecConfig::parse("3:1").unwrap();
+        let mut encoder = FecEncoder::new(config, SampleFormat::I16, None);
+        let mut decoder = FecDecoder::new();
+        encoder.add(0, &frame(0, 4));
+        encoder.add(1, &frame(1, 6));
+        let repair_frame = encoder.add(2, &frame(2, 3)).remove(0);
+
+        // Two frames missing is one more than the repair frame can rebuild.
+        assert!(repair(&mut decoder, repair_frame).is_empty());
+        assert!(decoder.received(2, &frame(2, 3)).is_empty());
+        assert_eq!(rebuilt(decoder.received(0, &frame(0, 4))), vec![(1, frame(1, 6))]);
+    }
+}
@@ -0,0 +1,304 @@
+use std::collections::BTreeMap;
+use std::time::Duration;
+use crate::util::get_timestamp;
+
+/// Weight given to each new transit sample when smoothing jitter (RFC 3550 uses 1/16).
+const JITTER_SMOOTHING: f64 = 16.0;
+/// How many jitter deviations of headroom to keep buffered on top of the base delay.
+const JITTER_HEADROOM: f64 = 4.0;
+pub const MAX_BUFFERED_FRAMES: usize = 64;
+
+pub enum Playout {
+    /// The frame for the expected counter.
+    Frame(Vec<i16>),
+    /// The expected frame never arrived, `gap` counts the frames missing before the next
+    /// buffered one.
+    Missing { counter: u32, gap: u32 },
+    /// The sender stopped transmitting because nobody was talking, play comfort noise
+    /// at the given level.
+    Silence(u16),
+    /// Still filling up to the playout delay.
+    Buffering,
+}
+
+/// Counters from `start` up to `end` the sender left silent, to be filled with comfort noise.
+struct SilenceRange {
+    start: u32,
+    end: u32,
+    level: u16,
+}
+
+/// Reorders voice frames by their packet counter and releases them at a steady pace.
+///
+/// Frames reach us from several peers in whatever order the network delivers them, so
+/// they are held here until the playout delay has elapsed. The delay adapts to the
+/// observed inter-arrival variance of the frames.
+pub struct JitterBuffer {
+    frames: BTreeMap<u32, Vec<i16>>,
+    silences: Vec<SilenceRange>,
+    next_expected: Option<u32>,
+    frame_duration: Duration,
+    base_delay: Duration,
+    jitter_nanos: f64,
+    last_arrival: Option<(u32, Duration)>,
+    buffering: bool,
+}
+
+impl JitterBuffer {
+    pub fn new(frame_duration: Duration, base_delay: Duration) -> JitterBuffer {
+        return JitterBuffer {
+            frames: BTreeMap::new(),
+            silences: vec![],
+            next_expected: None,
+            frame_duration,
+            base_delay,
+            jitter_nanos: 0.0,
+            last_arrival: None,
+            buffering: true,
+        };
+    }
+
+    /// Adds a frame to the buffer, returning false if it was a duplicate or arrived too late.
+    pub fn push(&mut self, counter: u32, frame: Vec<i16>) -> bool {
+        self.update_jitter(counter);
+
+        // Counters wrap, anything up to half the range behind the playout position is late.
+        if let Some(next) = self.next_expected {
+            if next.wrapping_sub(counter) as i32 > 0 {
+                return false;
+            }
+        }
+
+        if self.frames.contains_key(&counter) {
+            return false;
+        }
+
+        self.frames.insert(counter, frame);
+
+        // Never grow without bound if the speaker stalls, drop the oldest frames instead.
+        while self.frames.len() > MAX_BUFFERED_FRAMES {
+            let oldest = *self.frames.keys().next().unwrap();
+            self.frames.remove(&oldest);
+            self.next_expected = Some(oldest.wrapping_add(1));
+        }
+
+        return true;
+    }
+
+    /// Marks `frames` counters starting at `counter` as intentionally left silent by the
+    /// sender. Real frames that turn up for those counters still take precedence.
+    pub fn push_silence(&mut self, counter: u32, frames: u32, level: u16) -> bool {
+        let end = counter.saturating_add(frames);
+        if let Some(next) = self.next_expected {
+            if end <= next {
+                return false;
+            }
+        }
+
+        self.silences.push(SilenceRange { start: counter, end, level });
+        return true;
+    }
+
+    /// Pulls the frame for the next expected counter, advancing the playout position.
+    pub fn pop(&mut self) -> Playout {
+        if let Some(next) = self.next_expected {
+            self.silences.retain(|range| range.end > next);
+
+            let silence = self.silences.iter()
+                .find(|range| range.start <= next && !self.frames.contains_key(&next));
+            if let Some(range) = silence {
+                // Leave the buffering state alone so playout refills once speech resumes.
+                let level = range.level;
+                self.next_expected = Some(next.wrapping_add(1));
+                return Playout::Silence(level);
+            }
+        }
+
+        if self.buffering {
+            if self.frames.len() < self.target_depth() {
+                return Playout::Buffering;
+            }
+            self.buffering = false;
+        }
+
+        let next = match self.next_expected {
+            Some(next) => next,
+            None => match self.frames.keys().next() {
+                Some(first) => *first,
+                None => {
+                    self.buffering = true;
+                    return Playout::Buffering;
+                }
+            }
+        };
+
+        let first_buffered = match self.frames.keys().next() {
+            Some(first) => *first,
+            None => {
+                // Underrun, refill to the playout delay before resuming.
+                self.buffering = true;
+                return Playout::Buffering;
+            }
+        };
+
+        self.next_expected = Some(next.wrapping_add(1));
+        return match self.frames.remove(&next) {
+            Some(frame) => Playout::Frame(frame),
+            None => Playout::Missing {
+                counter: next,
+                gap: first_buffered.wrapping_sub(next),
+            }
+        };
+    }
+
+    /// Number of frames the buffer holds back before starting playout.
+    pub fn target_depth(&self) -> usize {
+        let delay_nanos = self.base_delay.as_nanos() as f64 + JITTER_HEADROOM * self.jitter_nanos;
+        let frame_nanos = self.frame_duration.as_nanos().max(1) as f64;
+
+        return ((delay_nanos / frame_nanos).ceil() as usize).max(1);
+    }
+
+    pub fn len(&self) -> usize {
+        return self.frames.len();
+    }
+
+    /// Counters between the next frame to play and the newest one buffered, lost ones included.
+    pub fn depth(&self) -> u32 {
+        let (first, last) = match (self.frames.keys().next(), self.frames.keys().next_back()) {
+            (Some(first), Some(last)) => (*first, *last),
+            _ => return 0,
+        };
+
+        return last - self.next_expected.unwrap_or(first).min(first) + 1;
+    }
+
+    pub fn next_expected(&self) -> Option<u32> {
+        return self.next_expected;
+    }
+
+    pub fn get_jitter(&self) -> Duration {
+        return Duration::from_nanos(self.jitter_nanos as u64);
+    }
+
+    fn update_jitter(&mut self, counter: u32) {
+        let now = get_timestamp();
+
+        if let Some((last_counter, last_time)) = self.last_arrival {
+            let arrival_delta = now.as_nanos() as f64 - last_time.as_nanos() as f64;
+            let expected_delta = (counter as f64 - last_counter as f64)
+                * self.frame_duration.as_nanos() as f64;
+            let deviation = (arrival_delta - expected_delta).abs();
+
+            self.jitter_nanos += (deviation - self.jitter_nanos) / JITTER_SMOOTHING;
+        }
+
+        self.last_arrival = Some((counter, now));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FRAME: Duration = Duration::from_millis(20);
+
+    /// Pushes a frame holding its own counter, arriving right on the frame clock.
+    fn push(buffer: &mut JitterBuffer, counter: u32) -> bool {
+        buffer.last_arrival = None;
+        return buffer.push(counter, vec![counter as i16]);
+    }
+
+    fn played(playout: Playout) -> Option<i16> {
+        return match playout {
+            Playout::Frame(frame) => Some(frame[0]),
+            _ => None
+        };
+    }
+
+    #[test]
+    fn reorders_frames() {
+        let mut buffer = JitterBuffer::new(FRAME, FRAME * 2);
+        assert!(push(&mut buffer, 3));
+        assert!(matches!(buffer.pop(), Playout::Buffering));
+        assert!(push(&mut buffer, 1));
+        assert!(push(&mut buffer, 2));
+
+        assert_eq!(played(buffer.pop()), Some(1));
+        assert_eq!(played(buffer.pop()), Some(2));
+        assert_eq!(played(buffer.pop()), Some(3));
+        assert!(matches!(buffer.pop(), Playout::Buffering));
+    }
+
+    #[test]
+    fn reports_missing_frames() {
+        let mut buffer = JitterBuffer::new(FRAME, FRAME * 2);
+        push(&mut buffer, 1);
+        push(&mut buffer, 4);
+
+        assert_eq!(played(buffer.pop()), Some(1));
+        assert!(matches!(buffer.pop(), Playout::Missing{ counter: 2, gap: 2 }));
+        assert!(matches!(buffer.pop(), Playout::Missing{ counter: 3, gap: 1 }));
+        assert_eq!(played(buffer.pop()), Some(4));
+    }
+
+    #[test]
+    fn drops_duplicate_and_late_frames() {
+        let mut buffer = JitterBuffer::new(FRAME, FRAME);
+        assert!(push(&mut buffer, 5));
+        assert!(!push(&mut buffer, 5));
+
+        assert_eq!(played(buffer.pop()), Some(5));
+        assert!(!push(&mut buffer, 5));
+        assert!(!push(&mut buffer, 4));
+        assert!(push(&mut buffer, 6));
+        assert_eq!(buffer.len(), 1);
+    }
+
+    #[test]
+    fn evicts_oldest_frames_when_full() {
+        let mut buffer = JitterBuffer::new(FRAME, FRAME);
+        for counter in 0..MAX_BUFFERED_FRAMES as u32 + 2 {
+            assert!(push(&mut buffer, counter));
+        }
+
+        assert_eq!(buffer.len(), MAX_BUFFERED_FRAMES);
+        assert_eq!(buffer.next_expected(), Some(2));
+        assert!(!push(&mut buffer, 1));
+        assert_eq!(played(buffer.pop()), Some(2));
+    }
+
+    #[test]
+    fn plays_on_when_counters_wrap() {
+        let mut buffer = JitterBuffer::new(FRAME, FRAME);
+        assert!(push(&mut buffer, u32::MAX - 1));
+        assert!(matches!(buffer.pop(), Playout::Frame(_)));
+
+        assert!(push(&mut buffer, 0));
+        assert!(push(&mut buffer, u32::MAX));
+        assert!(!push(&mut buffer, u32::MAX - 1));
+        assert!(!push(&mut buffer, u32::MAX - 2));
+        assert_eq!(buffer.next_expected(), Some(u32::MAX));
+        assert!(matches!(buffer.pop(), Playout::Frame(frame) if frame[0] == u32::MAX as i16));
+        assert_eq!(played(buffer.pop()), Some(0));
+        assert!(!push(&mut buffer, u32::MAX));
+        assert!(push(&mut buffer, 1));
+    }
+
+    #[test]
+    fn adapts_target_depth_to_jitter() {
+        let mut buffer = JitterBuffer::new(FRAME, Duration::from_millis(50));
+        assert_eq!(buffer.target_depth(), 3);
+
+        // On time, 20 ms after the frame before it.
+        buffer.last_arrival = Some((0, get_timestamp() - FRAME));
+        buffer.push(1, vec![1]);
+        assert_eq!(buffer.target_depth(), 3);
+
+        // A second late, the buffer holds more back to ride out the next one.
+        buffer.last_arrival = Some((1, get_timestamp() - Duration::from_secs(1)));
+        buffer.push(2, vec![2]);
+        assert!(buffer.get_jitter() > Duration::from_millis(50));
+        assert!(buffer.target_depth() > 3);
+    }
+}
@@ -1,151 +1,371 @@
-use portaudio as pa;
+use std::collections::{BTreeMap, HashMap};
+use std::collections::btree_map::Entry;
 use std::sync::mpsc::{Receiver, SyncSender, sync_channel, RecvError};
 use std::sync::{Arc, Mutex, RwLock};
 use std::thread;
-use portaudio::stream::{InputCallbackArgs, OutputCallbackArgs};
 use std::convert::TryInto;
-use portaudio::{OutputStreamSettings, InputStreamSettings, PortAudio, Stream, NonBlocking, Output, Input};
-use std::collections::LinkedList;
 use std::time::Duration;
-use crate::util::constants::DataPacket;
+use std::io;
+use std::path::PathBuf;
+use crate::util::constants::{DataPacket, CodecType, SampleFormat};
+use codec::AudioCodec;
+use vad::{VoiceDetector, SILENCE_MARKER_FRAMES};
+use resampler::Resampler;
+use recorder::Recorder;
+use stats::CallStats;
+use bitrate::BitrateSettings;
+use fec::{FecConfig, FecEncoder};
+use source::{Source, PEER};
+use backend::{AudioBackend, AudioDevices, AudioStream, BackendConfig, StreamSettings};
 
+mod jitter_buffer;
+mod concealment;
+mod vad;
+mod resampler;
+mod source;
+pub mod bitrate;
+pub mod fec;
+pub mod recorder;
+pub mod stats;
+pub mod samples;
+pub mod wav;
+pub mod codec;
+pub mod backend;
 
+#[derive(Clone, Debug)]
 pub struct PhoneConfig {
     pub(crate) sample_rate: f64,
     pub(crate) frames_per_buffer: u32,
+    /// Rate the far end captures at, its frames are resampled to ours on arrival.
+    pub(crate) remote_sample_rate: f64,
     pub(crate) jitter_delay_nanos: u64,
+    pub(crate) codec: CodecType,
+    pub(crate) sample_format: SampleFormat,
+    pub(crate) voice_activity_detection: bool,
+    pub(crate) backend: BackendConfig,
+    pub(crate) devices: AudioDevices,
+    /// Directory to record calls into, nothing is recorded when unset.
+    pub(crate) recording: Option<PathBuf>,
+    /// Repair frames to send with our audio, none when unset.
+    pub(crate) fec: Option<FecConfig>,
+}
+
+impl Default for PhoneConfig {
+    fn default() -> PhoneConfig {
+        return PhoneConfig {
+            sample_rate: 44100.0,
+            frames_per_buffer: 44100,
+            remote_sample_rate: 44100.0,
+            jitter_delay_nanos: 500000000,
+            codec: CodecType::Pcm,
+            sample_format: SampleFormat::I16,
+            voice_activity_detection: true,
+            backend: BackendConfig::PortAudio,
+            devices: AudioDevices::default(),
+            recording: None,
+            fec: None,
+        };
+    }
 }
 
 pub struct Phone {
     config: PhoneConfig,
     data_sender: SyncSender<DataPacket>,
-    phone_buffer: Mutex<LinkedList<(Vec<u8>, u32)>>,
+    /// Everyone we can hear by the session they seal with, just `PEER` on a two way call.
+    sources: Mutex<BTreeMap<u64, Source>>,
+    /// Rates conference participants capture at, by session.
+    sample_rates: Mutex<HashMap<u64, f64>>,
+    codec: Box<dyn AudioCodec>,
+    recorder: Option<Arc<Recorder>>,
+    stats: Arc<CallStats>,
+    /// What we send with, the domain changes it as the call goes on.
+    bitrate: Arc<Mutex<BitrateSettings>>,
+    frame_duration: Duration,
 }
 
 impl Phone {
-    pub fn new(config: PhoneConfig, data_sender: SyncSender<DataPacket>) -> SyncSender<DataPacket> {
+    pub fn new(
+        config: PhoneConfig,
+        data_sender: SyncSender<DataPacket>,
+        recorder: Option<Arc<Recorder>>,
+        stats: Arc<CallStats>,
+        bitrate: Arc<Mutex<BitrateSettings>>
+    ) -> io::Result<SyncSender<DataPacket>> {
+        let backend = backend::create(&config.backend, &config.devices)?;
+        return Self::with_backend(config, data_sender, recorder, stats, bitrate, backend);
+    }
+
+    pub fn with_backend(
+        config: PhoneConfig,
+        data_sender: SyncSender<DataPacket>,
+        recorder: Option<Arc<Recorder>>,
+        stats: Arc<CallStats>,
+        bitrate: Arc<Mutex<BitrateSettings>>,
+        backend: Box<dyn AudioBackend>
+    ) -> io::Result<SyncSender<DataPacket>> {
+        let frame_duration = Duration::from_secs_f64(
+            config.frames_per_buffer as f64 / config.sample_rate
+        );
+        let codec = codec::get_codec(config.codec);
 
         let mut phone = Arc::from(Phone{
             config,
+            codec,
+            recorder,
+            stats,
+            bitrate,
+            frame_duration,
             data_sender,
-            phone_buffer:Mutex::new(LinkedList::new()),
+            sources: Mutex::new(BTreeMap::new()),
+            sample_rates: Mutex::new(HashMap::new()),
         });
 
-        println!("Starting up speaker!");
-        let stream = phone.clone().spawn_streams();
-        return phone.clone().spawn_receiver(stream.1, stream.0);
+        eprintln!("Starting up speaker!");
+        let stream = phone.clone().spawn_streams(backend.as_ref())?;
+        return Ok(phone.clone().spawn_receiver(stream.1, stream.0));
     }
 
-    fn spawn_receiver(self: Arc<Self>, mut speaker: Stream<NonBlocking, Output<u8>>, mut mic: Stream<NonBlocking, Input<u8>>) -> SyncSender<DataPacket>{
+    fn spawn_receiver(self: Arc<Self>, mut speaker: Box<dyn AudioStream>, mic: Box<dyn AudioStream>) -> SyncSender<DataPacket>{
         let (sender, receiver): (SyncSender<DataPacket>, Receiver<DataPacket>) = sync_channel(1000);
 
-        println!("Spawning receiver");
+        eprintln!("Spawning receiver");
         thread::spawn(move || {
-            // Collect first packet and wait to adjust for jitter
-            let (counter, buffer) = Self::parse_packet(receiver.recv().unwrap());
-
-
-            self.clone().update_buffer(buffer, counter);
-            thread::sleep(Duration::from_nanos(self.config.jitter_delay_nanos));
+            // Collect first packet, the jitter buffer holds playout back until it has
+            // enough frames queued to cover the jitter delay.
+            match receiver.recv() {
+                Ok(DataPacket::HangUp{ .. }) | Err(_) => return,
+                Ok(packet) => self.clone().handle_packet(packet),
+            }
 
-            // Run the speaker
-            speaker.start();
-            mic.time();
+            // Run the speaker, the mic is moved in so it lives as long as the call. Without
+            // a speaker the call can't go on, dropping the receiver tells the domain.
+            if let Err(err) = speaker.start() {
+                eprintln!("{}", err);
+                return;
+            }
+            let mut mic = mic;
 
-            // Run the loop to update the data.
+            // Run the loop until the call hangs up or the sender goes away.
             loop {
                 match receiver.recv() {
-                    Ok(DataPacket::Data{
-                        counter,
-                        buffer
-                       }) => {
-                        self.clone().update_buffer(buffer, counter);
-                    }
-                    Err(err) => panic!("Error {}", err),
-                    _ => {
-                        println!("Bad packet passed to phone! ignoring");
-                    }
+                    Ok(DataPacket::HangUp{ .. }) | Err(_) => break,
+                    Ok(packet) => self.clone().handle_packet(packet),
                 }
             }
+
+            eprintln!("Stopping phone");
+            if let Err(err) = mic.stop() {
+                eprintln!("{}", err);
+            }
+            if let Err(err) = speaker.stop() {
+                eprintln!("{}", err);
+            }
         });
         return sender;
     }
 
-    fn parse_packet(packet: DataPacket) -> (u32, Vec<u8>) {
-        return if let DataPacket::Data{counter, buffer} = packet {
-            return (counter, buffer);
-        } else {
-            panic!("Invalid packet sent to phone.");
+    fn handle_packet(self: Arc<Self>, packet: DataPacket) {
+        match packet {
+            DataPacket::FromParticipant{ session_id, packet } => self.handle_media(session_id, *packet),
+            DataPacket::Conference{ session_id, sample_rates, .. } => {
+                let mut rates = self.sample_rates.lock().unwrap();
+                for (index, rate) in sample_rates.iter().enumerate() {
+                    rates.insert(session_id.wrapping_add(index as u64), *rate as f64);
+                }
+            }
+            packet => self.handle_media(PEER, packet),
         }
     }
 
-    fn update_buffer(self: Arc<Self>, packet_data: Vec<u8>, priority: u32) {
-        let mut buffer = self.phone_buffer.lock()
-            .expect("Unable to unlock buffer");
-        // TODO: Implement algorithm to handle out-of-order messages
-        buffer.push_back((packet_data, priority));
-    }
+    fn handle_media(self: Arc<Self>, source_id: u64, packet: DataPacket) {
+        let mut sources = self.sources.lock().expect("Unable to unlock sources");
+        let source = match sources.entry(source_id) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => match self.create_source(source_id) {
+                Ok(source) => entry.insert(source),
+                Err(err) => {
+                    eprintln!("Ignoring audio we can't play: {}", err);
+                    return;
+                }
+            }
+        };
 
-    fn spawn_streams(self: Arc<Self>) -> (Stream<NonBlocking, Input<u8>>, Stream<NonBlocking, Output<u8>>) {
-        let pa = pa::PortAudio::new()
-            .expect("Unable to start portaudio");
+        match packet {
+            DataPacket::Data{ counter, format, buffer, codec } => {
+                self.stats.frame_received(source_id, counter);
+                let rebuilt = source.fec_decoder.received(counter, &buffer);
+                self.update_buffer(source, buffer, format, codec, counter);
+                self.rebuilt(source, rebuilt);
+            }
+            DataPacket::Repair{ counters, format, length, parity, codec } => {
+                let rebuilt = source.fec_decoder.repair(counters, format, length, parity, codec);
+                self.rebuilt(source, rebuilt);
+            }
+            DataPacket::Silence{ counter, frames, level } => {
+                source.buffer.push_silence(counter, frames, level);
+            }
+            _ => {
+                eprintln!("Bad packet passed to phone! ignoring");
+            }
+        }
+    }
 
-        let mut output_settings = pa.default_output_stream_settings(
-                1,
-                self.config.sample_rate,
-                self.config.frames_per_buffer
-            ).expect("Unable to set output settings");
-        output_settings.flags = pa::stream_flags::CLIP_OFF;
+    fn create_source(&self, source_id: u64) -> io::Result<Source> {
+        let remote_rate = self.sample_rates.lock().unwrap()
+            .get(&source_id)
+            .cloned()
+            .unwrap_or(self.config.remote_sample_rate);
+        let resampler = Resampler::new(remote_rate, self.config.sample_rate)?;
+        if !resampler.is_passthrough() {
+            eprintln!("Resampling peer audio from {} Hz to {} Hz", remote_rate, self.config.sample_rate);
+        }
 
-        let input_settings = pa.default_input_stream_settings(
-                1,
-                self.config.sample_rate,
-                self.config.frames_per_buffer
-            ).expect("Unable to set input settings");
+        return Ok(Source::new(self.frame_duration, Duration::from_nanos(self.config.jitter_delay_nanos), resampler));
+    }
 
-        return (self.clone().create_receiver(input_settings, &pa), self.clone().create_sender(output_settings, &pa));
+    fn rebuilt(&self, source: &mut Source, packets: Vec<DataPacket>) {
+        for packet in packets {
+            if let DataPacket::Data{ counter, format, buffer, codec } = packet {
+                self.stats.frame_recovered();
+                if let Some(recorder) = &self.recorder {
+                    recorder.frame_recovered();
+                }
+                self.update_buffer(source, buffer, format, codec, counter);
+            }
+        }
     }
 
-    fn create_sender(self: Arc<Self>, settings: OutputStreamSettings<u8>, pa: &PortAudio) -> Stream<NonBlocking, Output<u8>> {
-        let cloned_self = self.clone();
-        let cb = move |pa::OutputStreamCallbackArgs{buffer, ..}| {
+    fn update_buffer(&self, source: &mut Source, packet_data: Vec<u8>, format: SampleFormat, codec: Option<CodecType>, counter: u32) {
+        // Decoding lands in 16-bit whatever the sender captured in, the backend converts
+        // to our own device format on playout.
+        let decoded = match codec {
+            Some(codec) if codec != self.codec.codec_type() => codec::get_codec(codec).decode(&packet_data, format),
+            _ => self.codec.decode(&packet_data, format)
+        };
+        let samples = source.resampler.resample(&decoded);
 
-            let value = match cloned_self.phone_buffer.lock().unwrap().pop_front() {
-                None => {
-                    return pa::Continue;
-                }
-                Some(packet) => packet.0
-            };
+        if !source.buffer.push(counter, samples) {
+            self.stats.frame_dropped();
+            if let Some(recorder) = &self.recorder {
+                recorder.frame_dropped();
+            }
+        }
+    }
 
-            buffer[..].clone_from_slice(value.as_slice());
-            return pa::Continue;
+    fn spawn_streams(self: Arc<Self>, backend: &dyn AudioBackend) -> io::Result<(Box<dyn AudioStream>, Box<dyn AudioStream>)> {
+        let settings = StreamSettings {
+            sample_rate: self.config.sample_rate,
+            frames_per_buffer: self.config.frames_per_buffer,
+            sample_format: self.config.sample_format,
         };
 
-        let mut stream = pa
-            .open_non_blocking_stream(settings, cb)
-            .expect("Unable to start output stream");
+        return Ok((self.clone().create_receiver(&settings, backend)?, self.clone().create_sender(&settings, backend)?));
+    }
+
+    fn create_sender(self: Arc<Self>, settings: &StreamSettings, backend: &dyn AudioBackend) -> io::Result<Box<dyn AudioStream>> {
+        let cloned_self = self.clone();
+        let cb = move |buffer: &mut [i16]| {
+            let mut frames = vec![];
+            let mut kinds = vec![];
+            for (source_id, source) in cloned_self.sources.lock().unwrap().iter_mut() {
+                let (frame, kind) = source.play(buffer.len());
+                cloned_self.stats.frame_played(*source_id, kind, source.buffer.depth());
+                frames.push(frame);
+                kinds.push(kind);
+            }
+
+            source::mix(&frames, buffer);
+            let kind = source::mixed_kind(&kinds);
+            if let Some(recorder) = &cloned_self.recorder {
+                recorder.record_playback(buffer, kind);
+            }
+        };
 
-        return stream;
+        return backend.open_output(settings, Box::new(cb));
     }
 
-    fn create_receiver(self: Arc<Self>, settings: InputStreamSettings<u8>, pa: &PortAudio) -> Stream<NonBlocking, Input<u8>> {
+    fn create_receiver(self: Arc<Self>, settings: &StreamSettings, backend: &dyn AudioBackend) -> io::Result<Box<dyn AudioStream>> {
         let cloned_self = self.clone();
         let mut counter = 0;
-        let cb = move |pa::InputStreamCallbackArgs{ time,  buffer, ..}| {
-            cloned_self.data_sender
-                .send(DataPacket::Data{
-                    counter,
-                    buffer: buffer.to_vec(),
-                })
-                .unwrap();
+        let mut detector = VoiceDetector::new(self.frame_duration);
+        let mut silence_remaining = 0;
+        let mut sending = *self.bitrate.lock().unwrap();
+        let mut audio_codec = codec::get_codec(sending.codec);
+        let mut fec_encoder = self.clone().create_fec_encoder(&sending);
+        let cb = move |samples: &[i16]| {
+            if let Some(recorder) = &cloned_self.recorder {
+                recorder.record_mic(samples);
+            }
+
+            let mut repairs = vec![];
+            let latest = *cloned_self.bitrate.lock().unwrap();
+            if latest.codec != sending.codec || latest.fec != sending.fec {
+                // Repair frames can't mix codecs, finish the group before switching.
+                if let Some(encoder) = &mut fec_encoder {
+                    repairs = encoder.flush();
+                }
+                audio_codec = codec::get_codec(latest.codec);
+                fec_encoder = cloned_self.clone().create_fec_encoder(&latest);
+            }
+            sending = latest;
+
+            if !cloned_self.config.voice_activity_detection || detector.is_voice(samples) {
+                silence_remaining = 0;
+                let buffer = audio_codec.encode(samples, cloned_self.config.sample_format);
+                if let Some(encoder) = &mut fec_encoder {
+                    repairs.extend(encoder.add(counter, &buffer));
+                }
+
+                cloned_self.data_sender
+                    .send(DataPacket::Data{
+                        counter,
+                        format: cloned_self.config.sample_format,
+                        buffer,
+                        codec: cloned_self.wire_codec(sending.codec),
+                    })
+                    .unwrap();
+            } else if silence_remaining == 0 {
+                // One marker covers several frames, nothing more is sent until it runs out
+                // or someone starts talking again.
+                silence_remaining = SILENCE_MARKER_FRAMES;
+                if let Some(encoder) = &mut fec_encoder {
+                    repairs.extend(encoder.flush());
+                }
+                cloned_self.data_sender
+                    .send(DataPacket::Silence{
+                        counter,
+                        frames: SILENCE_MARKER_FRAMES,
+                        level: detector.get_noise_level(),
+                    })
+                    .unwrap();
+            }
+
+            for repair in repairs {
+                cloned_self.data_sender
+                    .send(repair)
+                    .unwrap();
+            }
+
+            silence_remaining = silence_remaining.saturating_sub(1);
             counter += 1;
-            return pa::Continue;
         };
 
-        let mut stream = pa
-            .open_non_blocking_stream(settings, cb)
-            .expect("Unable to start input stream");
-        stream.start();
-        return stream;
+        let mut stream = backend.open_input(settings, Box::new(cb))?;
+        stream.start()?;
+        return Ok(stream);
+    }
+
+    fn create_fec_encoder(self: Arc<Self>, settings: &BitrateSettings) -> Option<FecEncoder> {
+        return settings.fec
+            .map(|fec| FecEncoder::new(fec, self.config.sample_format, self.wire_codec(settings.codec)));
+    }
+
+    /// Frames in the codec agreed at the start of the call don't need to say so.
+    fn wire_codec(&self, codec: CodecType) -> Option<CodecType> {
+        if codec == self.config.codec {
+            return None;
+        }
+
+        return Some(codec);
     }
 }
\ No newline at end of file
@@ -0,0 +1,315 @@
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use chrono::Utc;
+use serde::Serialize;
+use crate::phone::PhoneConfig;
+use crate::phone::stats::CallStatsSnapshot;
+use crate::phone::wav::{WavSpec, WavWriter};
+
+/// How often the sidecar is rewritten while the call runs.
+const SIDECAR_INTERVAL: Duration = Duration::from_secs(5);
+/// Records waiting on the writer, the audio callbacks never wait on the disk.
+const RECORD_QUEUE: usize = 1000;
+
+/// What the speaker played for a frame.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PlayoutKind {
+    Received,
+    Concealed,
+    ComfortNoise,
+    Buffering,
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct LossStats {
+    pub frames_played: u64,
+    pub frames_concealed: u64,
+    pub frames_comfort_noise: u64,
+    pub frames_buffering: u64,
+    /// Frames that arrived too late to play or twice.
+    pub frames_dropped: u64,
+    /// Lost frames rebuilt from repair frames.
+    pub frames_recovered: u64,
+}
+
+#[derive(Clone, Debug, Serialize)]
+struct FrameRecord {
+    counter: u32,
+    txid: String,
+}
+
+#[derive(Debug, Serialize)]
+struct Sidecar {
+    peer_address: String,
+    start_time: String,
+    sample_rate: u32,
+    codec: String,
+    mic_track: String,
+    peer_track: String,
+    sent_frames: Vec<FrameRecord>,
+    received_frames: Vec<FrameRecord>,
+    loss: LossStats,
+    /// The call's statistics once it has hung up.
+    summary: Option<CallStatsSnapshot>,
+}
+
+/// What the call hands the writer thread.
+enum Record {
+    Mic(Vec<i16>),
+    Playback(Vec<i16>, PlayoutKind),
+    Dropped,
+    Recovered,
+    Sent(FrameRecord),
+    Received(FrameRecord),
+    Summary(CallStatsSnapshot),
+}
+
+/// The files, only the writer thread touches them.
+struct RecorderState {
+    mic: WavWriter,
+    peer: WavWriter,
+    sidecar: Sidecar,
+    sidecar_path: PathBuf,
+    last_saved: Instant,
+}
+
+/// Records a call as two mono WAV tracks, our microphone and what we played of the
+/// peer, next to a JSON sidecar describing the call.
+///
+/// Everything is written by a thread of its own, so the audio callbacks only queue what
+/// they hand it. Like the WAV files the sidecar is kept up to date while the call runs,
+/// so a call that ends abruptly still leaves a usable recording. Write errors are logged
+/// rather than interrupting the call.
+pub struct Recorder {
+    sender: Option<SyncSender<Record>>,
+    writer: Option<JoinHandle<()>>,
+}
+
+impl Recorder {
+    pub fn create(directory: &Path, peer_address: String, config: &PhoneConfig) -> io::Result<Recorder> {
+        fs::create_dir_all(directory)?;
+
+        let start_time = Utc::now();
+        let name = format!("call-{}", start_time.format("%Y%m%d-%H%M%S"));
+        let mic_path = directory.join(format!("{}-mic.wav", name));
+        let peer_path = directory.join(format!("{}-peer.wav", name));
+        let spec = WavSpec {
+            channels: 1,
+            sample_rate: config.sample_rate as u32,
+            bits_per_sample: 16,
+        };
+
+        let mut state = RecorderState {
+            mic: WavWriter::create(&mic_path, spec)?,
+            peer: WavWriter::create(&peer_path, spec)?,
+            sidecar: Sidecar {
+                peer_address,
+                start_time: start_time.to_rfc3339(),
+                sample_rate: spec.sample_rate,
+                codec: format!("{:?}", config.codec),
+                mic_track: mic_path.display().to_string(),
+                peer_track: peer_path.display().to_string(),
+                sent_frames: vec![],
+                received_frames: vec![],
+                loss: LossStats::default(),
+                summary: None,
+            },
+            sidecar_path: directory.join(format!("{}.json", name)),
+            last_saved: Instant::now(),
+        };
+        state.write_sidecar()?;
+        eprintln!("Recording call to {}", state.sidecar_path.display());
+
+        let (sender, receiver) = sync_channel(RECORD_QUEUE);
+        return Ok(Recorder {
+            sender: Some(sender),
+            writer: Some(thread::spawn(move || state.run(receiver))),
+        });
+    }
+
+    pub fn record_mic(&self, samples: &[i16]) {
+        self.send(Record::Mic(samples.to_vec()));
+    }
+
+    pub fn record_playback(&self, samples: &[i16], kind: PlayoutKind) {
+        self.send(Record::Playback(samples.to_vec(), kind));
+    }
+
+    pub fn frame_dropped(&self) {
+        self.send(Record::Dropped);
+    }
+
+    pub fn frame_recovered(&self) {
+        self.send(Record::Recovered);
+    }
+
+    pub fn frame_sent(&self, counter: u32, txid: String) {
+        self.send(Record::Sent(FrameRecord { counter, txid }));
+    }
+
+    pub fn frame_received(&self, counter: u32, txid: String) {
+        self.send(Record::Received(FrameRecord { counter, txid }));
+    }
+
+    pub fn set_summary(&self, summary: CallStatsSnapshot) {
+        self.send(Record::Summary(summary));
+    }
+
+    fn send(&self, record: Record) {
+        if let Some(sender) = &self.sender {
+            if let Err(TrySendError::Full(_)) = sender.try_send(record) {
+                eprintln!("Recording can't keep up, some of the call is missing from it");
+            }
+        }
+    }
+}
+
+impl Drop for Recorder {
+    /// Waits for the writer to finish off the files.
+    fn drop(&mut self) {
+        self.sender.take();
+        if let Some(writer) = self.writer.take() {
+            writer.join().ok();
+        }
+    }
+}
+
+impl RecorderState {
+    fn run(mut self, receiver: Receiver<Record>) {
+        for record in receiver {
+            match record {
+                Record::Mic(samples) => {
+                    if let Err(err) = self.mic.write_samples(&samples) {
+                        eprintln!("Unable to record microphone: {}", err);
+                    }
+                }
+                Record::Playback(samples, kind) => {
+                    if let Err(err) = self.peer.write_samples(&samples) {
+                        eprintln!("Unable to record playback: {}", err);
+                    }
+
+                    let loss = &mut self.sidecar.loss;
+                    match kind {
+                        PlayoutKind::Received => loss.frames_played += 1,
+                        PlayoutKind::Concealed => loss.frames_concealed += 1,
+                        PlayoutKind::ComfortNoise => loss.frames_comfort_noise += 1,
+                        PlayoutKind::Buffering => loss.frames_buffering += 1,
+                    }
+                }
+                Record::Dropped => self.sidecar.loss.frames_dropped += 1,
+                Record::Recovered => self.sidecar.loss.frames_recovered += 1,
+                Record::Sent(frame) => self.sidecar.sent_frames.push(frame),
+                Record::Received(frame) => self.sidecar.received_frames.push(frame),
+                Record::Summary(summary) => self.sidecar.summary = Some(summary),
+            }
+
+            if self.last_saved.elapsed() >= SIDECAR_INTERVAL {
+                self.save();
+            }
+        }
+
+        // The call is over, whatever came in since the last save goes in now.
+        self.save();
+    }
+
+    fn save(&mut self) {
+        if let Err(err) = self.write_sidecar() {
+            eprintln!("Unable to write recording sidecar: {}", err);
+        }
+    }
+
+    fn write_sidecar(&mut self) -> io::Result<()> {
+        self.last_saved = Instant::now();
+
+        // Write then rename so a crash never leaves half a JSON document behind.
+        let temp_path = self.sidecar_path.with_extension("json.tmp");
+        serde_json::to_writer_pretty(File::create(&temp_path)?, &self.sidecar)?;
+        return fs::rename(temp_path, &self.sidecar_path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process;
+    use crate::phone::stats::CallStats;
+    use crate::phone::wav;
+    use super::*;
+
+    fn temp_directory(name: &str) -> PathBuf {
+        let directory = std::env::temp_dir().join(format!("bitcoinphone-{}-{}", name, process::id()));
+        fs::remove_dir_all(&directory).ok();
+        return directory;
+    }
+
+    fn config() -> PhoneConfig {
+        return PhoneConfig { sample_rate: 8000.0, ..PhoneConfig::default() };
+    }
+
+    fn samples(path: &serde_json::Value) -> Vec<i16> {
+        let (spec, data) = wav::read(path.as_str().unwrap()).unwrap();
+        assert_eq!((spec.channels, spec.sample_rate, spec.bits_per_sample), (1, 8000, 16));
+        return data.chunks(2)
+            .map(|sample| i16::from_le_bytes([sample[0], sample[1]]))
+            .collect();
+    }
+
+    #[test]
+    fn records_both_tracks_and_the_sidecar() {
+        let directory = temp_directory("recording");
+        let recorder = Recorder::create(&directory, "1peer".to_string(), &config()).unwrap();
+
+        recorder.record_mic(&[1, 2, 3]);
+        recorder.record_playback(&[4, 5], PlayoutKind::Received);
+        recorder.record_playback(&[6], PlayoutKind::Concealed);
+        recorder.record_playback(&[0], PlayoutKind::ComfortNoise);
+        recorder.record_playback(&[0], PlayoutKind::Buffering);
+        recorder.record_playback(&[7], PlayoutKind::Received);
+        recorder.frame_dropped();
+        recorder.frame_recovered();
+        recorder.frame_sent(1, "aa".to_string());
+        recorder.frame_sent(2, "bb".to_string());
+        recorder.frame_received(5, "cc".to_string());
+        recorder.set_summary(CallStats::new(Duration::from_millis(100), Duration::from_millis(0)).snapshot());
+        drop(recorder);
+
+        let sidecar_path = fs::read_dir(&directory).unwrap()
+            .map(|entry| entry.unwrap().path())
+            .find(|path| path.extension().map(|extension| extension == "json").unwrap_or(false))
+            .unwrap();
+        let sidecar: serde_json::Value = serde_json::from_reader(File::open(sidecar_path).unwrap()).unwrap();
+
+        assert_eq!(sidecar["peer_address"], "1peer");
+        assert_eq!(sidecar["sample_rate"], 8000);
+        assert_eq!(samples(&sidecar["mic_track"]), vec![1, 2, 3]);
+        assert_eq!(samples(&sidecar["peer_track"]), vec![4, 5, 6, 0, 0, 7]);
+        assert_eq!(sidecar["sent_frames"], serde_json::json!([
+            { "counter": 1, "txid": "aa" },
+            { "counter": 2, "txid": "bb" },
+        ]));
+        assert_eq!(sidecar["received_frames"], serde_json::json!([{ "counter": 5, "txid": "cc" }]));
+        assert_eq!(sidecar["loss"], serde_json::json!({
+            "frames_played": 2,
+            "frames_concealed": 1,
+            "frames_comfort_noise": 1,
+            "frames_buffering": 1,
+            "frames_dropped": 1,
+            "frames_recovered": 1,
+        }));
+        assert!(sidecar["summary"].is_object());
+
+        fs::remove_dir_all(&directory).ok();
+    }
+
+    #[test]
+    fn fails_to_create_where_it_cant_write() {
+        let file = temp_directory("recording-file");
+        fs::write(&file, b"").unwrap();
+
+        assert!(Recorder::create(&file, "1peer".to_string(), &config()).is_err());
+        fs::remove_file(&file).ok();
+    }
+}
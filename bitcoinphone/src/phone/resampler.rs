@@ -0,0 +1,146 @@
+use std::io::{self, Error, ErrorKind};
+
+/// Converts frames from the far end's sample rate to ours.
+///
+/// Frames arrive as independent transactions and any of them may be lost, so each one
+/// is resampled on its own. Upsampling interpolates linearly between neighbours,
+/// downsampling averages the samples each output covers so high frequencies don't
+/// alias back into the voice band.
+#[derive(Clone, Copy, Debug)]
+pub struct Resampler {
+    from_rate: f64,
+    to_rate: f64,
+}
+
+impl Resampler {
+    pub fn new(from_rate: f64, to_rate: f64) -> io::Result<Resampler> {
+        if !(from_rate > 0.0 && to_rate > 0.0) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Can't resample from {} Hz to {} Hz, sample rates must be positive", from_rate, to_rate)
+            ));
+        }
+        return Ok(Resampler { from_rate, to_rate });
+    }
+
+    pub fn is_passthrough(&self) -> bool {
+        return self.from_rate == self.to_rate;
+    }
+
+    /// Length of a frame of `input_len` samples once converted.
+    pub fn output_len(&self, input_len: usize) -> usize {
+        return (input_len as f64 * self.to_rate / self.from_rate).round() as usize;
+    }
+
+    pub fn resample(&self, input: &[i16]) -> Vec<i16> {
+        if self.is_passthrough() {
+            return input.to_vec();
+        }
+
+        return Self::resample_to(input, self.output_len(input.len()));
+    }
+
+    /// Stretches or squeezes the frame to exactly `output_len` samples.
+    pub fn resample_to(input: &[i16], output_len: usize) -> Vec<i16> {
+        if input.is_empty() || output_len == 0 {
+            return vec![0; output_len];
+        }
+        if input.len() == output_len {
+            return input.to_vec();
+        }
+
+        let step = input.len() as f64 / output_len as f64;
+        let last = input.len() - 1;
+
+        return (0..output_len)
+            .map(|index| {
+                // Centre of this output sample in input sample positions.
+                let position = ((index as f64 + 0.5) * step - 0.5).max(0.0).min(last as f64);
+
+                if step > 1.0 {
+                    let start = (position - step / 2.0).ceil().max(0.0) as usize;
+                    let end = ((position + step / 2.0).floor() as usize).min(last);
+                    let window = &input[start.min(end)..=end];
+                    let sum = window.iter().fold(0i64, |sum, sample| sum + *sample as i64);
+                    (sum / window.len() as i64) as i16
+                } else {
+                    let before = position.floor() as usize;
+                    let after = (before + 1).min(last);
+                    let fraction = position - before as f64;
+                    let value = input[before] as f64 * (1.0 - fraction) + input[after] as f64 * fraction;
+                    value.round() as i16
+                }
+            })
+            .collect();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f64::consts::PI;
+    use super::*;
+
+    fn tone(frequency: f64, rate: f64, samples: usize) -> Vec<i16> {
+        return (0..samples)
+            .map(|sample| ((sample as f64 * frequency * 2.0 * PI / rate).sin() * 8000.0) as i16)
+            .collect();
+    }
+
+    /// Frequency of a tone worked out from how often it crosses zero.
+    fn frequency(samples: &[i16], rate: f64) -> f64 {
+        let crossings = samples.windows(2)
+            .filter(|pair| (pair[0] < 0) != (pair[1] < 0))
+            .count();
+        return crossings as f64 / 2.0 * rate / samples.len() as f64;
+    }
+
+    #[test]
+    fn refuses_rates_of_zero() {
+        assert_eq!(Resampler::new(0.0, 8000.0).unwrap_err().kind(), ErrorKind::InvalidInput);
+        assert_eq!(Resampler::new(8000.0, 0.0).unwrap_err().kind(), ErrorKind::InvalidInput);
+        assert!(Resampler::new(8000.0, 8000.0).unwrap().is_passthrough());
+    }
+
+    #[test]
+    fn interpolates_when_upsampling() {
+        let resampler = Resampler::new(8000.0, 16000.0).unwrap();
+
+        assert_eq!(resampler.resample(&[0, 100, 200, 300]), vec![0, 25, 75, 125, 175, 225, 275, 300]);
+    }
+
+    #[test]
+    fn averages_when_downsampling() {
+        let resampler = Resampler::new(16000.0, 8000.0).unwrap();
+
+        assert_eq!(resampler.resample(&[0, 100, 200, 300, -400, 400]), vec![50, 250, 0]);
+    }
+
+    #[test]
+    fn keeps_frames_the_same_length_in_time() {
+        for (from, to, frame) in [(48000.0, 44100.0, 480), (8000.0, 44100.0, 160), (44100.0, 8000.0, 441)].iter() {
+            let resampler = Resampler::new(*from, *to).unwrap();
+            let input = tone(440.0, *from, frame * 10);
+
+            let output = input.chunks(*frame)
+                .map(|chunk| resampler.resample(chunk))
+                .collect::<Vec<Vec<i16>>>();
+            assert!(output.iter().all(|chunk| chunk.len() == resampler.output_len(*frame)));
+            assert_eq!(output.concat().len(), resampler.output_len(input.len()));
+        }
+    }
+
+    #[test]
+    fn keeps_a_tone_at_its_frequency() {
+        for (from, to) in [(8000.0, 44100.0), (44100.0, 8000.0), (16000.0, 8000.0)].iter() {
+            let resampler = Resampler::new(*from, *to).unwrap();
+            let input = tone(440.0, *from, *from as usize);
+
+            let output = input.chunks(*from as usize / 10)
+                .flat_map(|chunk| resampler.resample(chunk))
+                .collect::<Vec<i16>>();
+            let heard = frequency(&output, *to);
+            assert!((heard - 440.0).abs() < 10.0, "{} Hz to {} Hz heard {} Hz", from, to, heard);
+            assert!(output.iter().any(|sample| sample.abs() > 6000), "{} Hz to {} Hz lost the tone", from, to);
+        }
+    }
+}
@@ -0,0 +1,84 @@
+use std::convert::TryInto;
+use portaudio::Sample;
+use crate::util::constants::SampleFormat;
+
+/// Device sample types the phone can capture and play.
+///
+/// Everything inside the phone works on 16-bit samples, conversion only happens at
+/// the audio backend and on the wire.
+pub trait PcmSample: Sample + Send + 'static {
+    const FORMAT: SampleFormat;
+
+    fn to_pcm(self) -> i16;
+    fn from_pcm(sample: i16) -> Self;
+}
+
+impl PcmSample for u8 {
+    const FORMAT: SampleFormat = SampleFormat::U8;
+
+    fn to_pcm(self) -> i16 {
+        return ((self as i16) - 128) << 8;
+    }
+
+    fn from_pcm(sample: i16) -> u8 {
+        return ((sample >> 8) + 128) as u8;
+    }
+}
+
+impl PcmSample for i16 {
+    const FORMAT: SampleFormat = SampleFormat::I16;
+
+    fn to_pcm(self) -> i16 {
+        return self;
+    }
+
+    fn from_pcm(sample: i16) -> i16 {
+        return sample;
+    }
+}
+
+impl PcmSample for f32 {
+    const FORMAT: SampleFormat = SampleFormat::F32;
+
+    fn to_pcm(self) -> i16 {
+        return (self.max(-1.0).min(1.0) * i16::MAX as f32).round() as i16;
+    }
+
+    fn from_pcm(sample: i16) -> f32 {
+        return sample as f32 / i16::MAX as f32;
+    }
+}
+
+pub fn bytes_per_sample(format: SampleFormat) -> usize {
+    return match format {
+        SampleFormat::U8 => 1,
+        SampleFormat::I16 => 2,
+        SampleFormat::F32 => 4,
+    };
+}
+
+/// Serializes samples as `format`, little endian.
+pub fn encode(samples: &[i16], format: SampleFormat) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(samples.len() * bytes_per_sample(format));
+
+    for sample in samples {
+        match format {
+            SampleFormat::U8 => bytes.push(u8::from_pcm(*sample)),
+            SampleFormat::I16 => bytes.extend_from_slice(&sample.to_le_bytes()),
+            SampleFormat::F32 => bytes.extend_from_slice(&f32::from_pcm(*sample).to_bits().to_le_bytes()),
+        }
+    }
+
+    return bytes;
+}
+
+/// Reads samples serialized as `format`, ignoring a trailing partial sample.
+pub fn decode(bytes: &[u8], format: SampleFormat) -> Vec<i16> {
+    return bytes.chunks_exact(bytes_per_sample(format))
+        .map(|chunk| match format {
+            SampleFormat::U8 => chunk[0].to_pcm(),
+            SampleFormat::I16 => i16::from_le_bytes(chunk.try_into().unwrap()),
+            SampleFormat::F32 => f32::from_bits(u32::from_le_bytes(chunk.try_into().unwrap())).to_pcm(),
+        })
+        .collect();
+}
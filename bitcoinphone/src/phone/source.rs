@@ -0,0 +1,66 @@
+use std::time::Duration;
+use crate::phone::concealment::{Concealer, SILENCE};
+use crate::phone::fec::FecDecoder;
+use crate::phone::jitter_buffer::{JitterBuffer, Playout};
+use crate::phone::recorder::PlayoutKind;
+use crate::phone::resampler::Resampler;
+
+/// Packets that don't say who sent them come from the one peer on a two way call.
+pub const PEER: u64 = 0;
+
+/// Everything kept for one person we can hear, a conference has one for each participant
+/// since they all number their frames from zero and may capture at different rates.
+pub struct Source {
+    pub buffer: JitterBuffer,
+    pub fec_decoder: FecDecoder,
+    pub resampler: Resampler,
+    concealer: Concealer,
+}
+
+impl Source {
+    pub fn new(frame_duration: Duration, delay: Duration, resampler: Resampler) -> Source {
+        return Source {
+            buffer: JitterBuffer::new(frame_duration, delay),
+            fec_decoder: FecDecoder::new(),
+            resampler,
+            concealer: Concealer::new(),
+        };
+    }
+
+    /// Pulls the next `length` samples to play.
+    pub fn play(&mut self, length: usize) -> (Vec<i16>, PlayoutKind) {
+        return match self.buffer.pop() {
+            Playout::Frame(mut frame) => {
+                // Resampled frames can be a sample off our buffer size.
+                frame.resize(length, SILENCE);
+                self.concealer.received(&frame);
+                (frame, PlayoutKind::Received)
+            },
+            Playout::Missing { gap, .. } => (self.concealer.conceal(length, gap), PlayoutKind::Concealed),
+            Playout::Silence(level) => (self.concealer.comfort_noise(length, level), PlayoutKind::ComfortNoise),
+            Playout::Buffering => (Concealer::silence(length), PlayoutKind::Buffering)
+        };
+    }
+}
+
+/// Adds everyone's frames together, clipping rather than wrapping when it gets loud.
+pub fn mix(frames: &[Vec<i16>], output: &mut [i16]) {
+    for (index, sample) in output.iter_mut().enumerate() {
+        let sum: i32 = frames.iter()
+            .map(|frame| *frame.get(index).unwrap_or(&SILENCE) as i32)
+            .sum();
+        *sample = sum.max(i16::MIN as i32).min(i16::MAX as i32) as i16;
+    }
+}
+
+/// What the recording marks a mixed frame as, the best of what went into it.
+pub fn mixed_kind(kinds: &[PlayoutKind]) -> PlayoutKind {
+    let rank = |kind: &PlayoutKind| match kind {
+        PlayoutKind::Received => 3,
+        PlayoutKind::Concealed => 2,
+        PlayoutKind::ComfortNoise => 1,
+        PlayoutKind::Buffering => 0,
+    };
+
+    return kinds.iter().cloned().max_by_key(rank).unwrap_or(PlayoutKind::Buffering);
+}
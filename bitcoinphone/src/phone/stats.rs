@@ -0,0 +1,163 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use serde::Serialize;
+use crate::phone::recorder::PlayoutKind;
+
+#[derive(Default)]
+struct Counters {
+    frames_sent: u64,
+    frames_received: u64,
+    frames_reordered: u64,
+    frames_recovered: u64,
+    frames_dropped: u64,
+    frames_played: u64,
+    frames_lost: u64,
+    underruns: u64,
+    highest_received: BTreeMap<u64, u32>,
+    buffer_depths: BTreeMap<u64, u32>,
+    playing: BTreeMap<u64, bool>,
+    sats_spent: i64,
+}
+
+/// How a call is going at one moment, see `CallStats::snapshot`.
+#[derive(Clone, Debug, Serialize)]
+pub struct CallStatsSnapshot {
+    pub duration_secs: f64,
+    pub frames_sent: u64,
+    pub frames_received: u64,
+    /// Frames that arrived after one with a higher counter.
+    pub frames_reordered: u64,
+    /// Lost frames rebuilt from repair frames.
+    pub frames_recovered: u64,
+    /// Frames that arrived too late to play or twice.
+    pub frames_dropped: u64,
+    /// Frames that never turned up in time and were concealed.
+    pub frames_lost: u64,
+    /// Share of the peer's frames due for playout that were lost.
+    pub loss_rate: f64,
+    /// From the peer's microphone to our speaker.
+    pub latency_ms: f64,
+    /// Counters between the next frame to play and the newest one buffered, for the
+    /// deepest of the buffers on a conference.
+    pub buffer_depth: u32,
+    /// Times playout ran dry and had to buffer again.
+    pub underruns: u64,
+    /// Fees and dust paid by our last transaction, which replaces all the ones before it.
+    pub sats_spent: i64,
+}
+
+/// Live statistics for the call in progress, shared by the domain and the phone.
+///
+/// End to end latency can't be measured directly since frames carry no timestamps, it's
+/// the one way delay the sync exchange measured plus a frame of capture plus however
+/// many frames the jitter buffer is holding back.
+///
+/// Frames are counted per source, each participant on a conference numbers their own.
+pub struct CallStats {
+    started: Instant,
+    frame_duration: Duration,
+    network_delay: Duration,
+    counters: Mutex<Counters>,
+}
+
+impl CallStats {
+    pub fn new(frame_duration: Duration, network_delay: Duration) -> CallStats {
+        return CallStats {
+            started: Instant::now(),
+            frame_duration,
+            network_delay,
+            counters: Mutex::new(Counters::default()),
+        };
+    }
+
+    pub fn frame_sent(&self) {
+        self.counters.lock().unwrap().frames_sent += 1;
+    }
+
+    pub fn frame_received(&self, source: u64, counter: u32) {
+        let mut counters = self.counters.lock().unwrap();
+        counters.frames_received += 1;
+
+        match counters.highest_received.get(&source) {
+            Some(highest) if counter < *highest => counters.frames_reordered += 1,
+            _ => { counters.highest_received.insert(source, counter); }
+        }
+    }
+
+    pub fn frame_recovered(&self) {
+        self.counters.lock().unwrap().frames_recovered += 1;
+    }
+
+    pub fn frame_dropped(&self) {
+        self.counters.lock().unwrap().frames_dropped += 1;
+    }
+
+    /// Notes what the speaker played from a source and how deep its jitter buffer was afterwards.
+    pub fn frame_played(&self, source: u64, kind: PlayoutKind, buffer_depth: u32) {
+        let mut counters = self.counters.lock().unwrap();
+        counters.buffer_depths.insert(source, buffer_depth);
+
+        let was_playing = counters.playing.get(&source).cloned().unwrap_or(false);
+        match kind {
+            PlayoutKind::Received => counters.frames_played += 1,
+            PlayoutKind::Concealed => counters.frames_lost += 1,
+            PlayoutKind::ComfortNoise => {}
+            PlayoutKind::Buffering => {
+                if was_playing {
+                    counters.underruns += 1;
+                }
+            }
+        }
+        counters.playing.insert(source, kind != PlayoutKind::Buffering);
+    }
+
+    pub fn set_sats_spent(&self, sats: i64) {
+        self.counters.lock().unwrap().sats_spent = sats;
+    }
+
+    pub fn snapshot(&self) -> CallStatsSnapshot {
+        let counters = self.counters.lock().unwrap();
+        let due = counters.frames_played + counters.frames_lost;
+        let buffer_depth = counters.buffer_depths.values().max().cloned().unwrap_or(0);
+        let held = self.frame_duration * (1 + buffer_depth);
+
+        return CallStatsSnapshot {
+            duration_secs: self.started.elapsed().as_secs_f64(),
+            frames_sent: counters.frames_sent,
+            frames_received: counters.frames_received,
+            frames_reordered: counters.frames_reordered,
+            frames_recovered: counters.frames_recovered,
+            frames_dropped: counters.frames_dropped,
+            frames_lost: counters.frames_lost,
+            loss_rate: if due == 0 { 0.0 } else { counters.frames_lost as f64 / due as f64 },
+            latency_ms: (self.network_delay + held).as_secs_f64() * 1000.0,
+            buffer_depth,
+            underruns: counters.underruns,
+            sats_spent: counters.sats_spent,
+        };
+    }
+}
+
+impl fmt::Display for CallStatsSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return write!(
+            f,
+            "{:.0}s, sent {}, received {} ({} reordered, {} recovered, {} dropped), lost {} ({:.1}%), \
+             latency {:.0} ms, buffer {} frames, {} underruns, {} sats spent",
+            self.duration_secs,
+            self.frames_sent,
+            self.frames_received,
+            self.frames_reordered,
+            self.frames_recovered,
+            self.frames_dropped,
+            self.frames_lost,
+            self.loss_rate * 100.0,
+            self.latency_ms,
+            self.buffer_depth,
+            self.underruns,
+            self.sats_spent
+        );
+    }
+}
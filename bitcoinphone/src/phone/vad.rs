@@ -0,0 +1,182 @@
+use std::time::Duration;
+
+/// Frames need this much more energy than the background noise to count as speech.
+const SPEECH_TO_NOISE_RATIO: f64 = 3.0;
+/// Energy floor below which a frame is never speech, in 16-bit sample units.
+const MIN_SPEECH_RMS: f64 = 400.0;
+/// How quickly the noise floor follows the energy of silent frames.
+const NOISE_ADAPTATION: f64 = 0.1;
+/// How quickly it follows while we think someone is talking, so a steady hum that
+/// started loud eventually becomes the floor.
+const SPEECH_NOISE_ADAPTATION: f64 = 0.01;
+/// How long we keep transmitting after speech stops, so word endings aren't clipped.
+pub const HANGOVER: Duration = Duration::from_millis(300);
+/// Frames covered by a single silence marker.
+pub const SILENCE_MARKER_FRAMES: u32 = 8;
+
+/// Energy-based voice activity detector with hangover.
+pub struct VoiceDetector {
+    noise_floor: f64,
+    hangover_frames: u32,
+    remaining_hangover: u32,
+}
+
+impl VoiceDetector {
+    pub fn new(frame_duration: Duration) -> VoiceDetector {
+        let frame_nanos = frame_duration.as_nanos().max(1) as f64;
+        let hangover_frames = (HANGOVER.as_nanos() as f64 / frame_nanos).ceil() as u32;
+
+        return VoiceDetector {
+            noise_floor: 0.0,
+            hangover_frames,
+            remaining_hangover: 0,
+        };
+    }
+
+    /// Returns true if the frame should be transmitted.
+    pub fn is_voice(&mut self, samples: &[i16]) -> bool {
+        let energy = Self::rms(samples);
+        let threshold = (self.noise_floor * SPEECH_TO_NOISE_RATIO).max(MIN_SPEECH_RMS);
+        let speech = energy > threshold;
+
+        // Drop straight down to quieter backgrounds, creep up to louder ones.
+        self.noise_floor = if energy < self.noise_floor {
+            energy
+        } else if speech {
+            self.noise_floor + (energy - self.noise_floor) * SPEECH_NOISE_ADAPTATION
+        } else {
+            self.noise_floor + (energy - self.noise_floor) * NOISE_ADAPTATION
+        };
+
+        if speech {
+            self.remaining_hangover = self.hangover_frames;
+            return true;
+        }
+
+        if self.remaining_hangover > 0 {
+            self.remaining_hangover -= 1;
+            return true;
+        }
+
+        return false;
+    }
+
+    /// Background level to reproduce as comfort noise on the far end.
+    pub fn get_noise_level(&self) -> u16 {
+        return self.noise_floor.min(u16::MAX as f64) as u16;
+    }
+
+    pub fn rms(samples: &[i16]) -> f64 {
+        if samples.is_empty() {
+            return 0.0;
+        }
+
+        let sum = samples.iter()
+            .fold(0.0, |sum, sample| sum + (*sample as f64) * (*sample as f64));
+
+        return (sum / samples.len() as f64).sqrt();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::phone::jitter_buffer::{JitterBuffer, Playout};
+    use super::*;
+
+    const FRAME: Duration = Duration::from_millis(20);
+    const FRAME_LEN: usize = 160;
+
+    /// A frame whose RMS is `level`.
+    fn frame(level: i16) -> Vec<i16> {
+        return (0..FRAME_LEN)
+            .map(|sample| if sample % 2 == 0 { level } else { -level })
+            .collect();
+    }
+
+    fn hangover_frames() -> usize {
+        return (HANGOVER.as_millis() / FRAME.as_millis()) as usize;
+    }
+
+    #[test]
+    fn keeps_sending_for_the_hangover() {
+        let mut detector = VoiceDetector::new(FRAME);
+        assert!(!detector.is_voice(&frame(50)));
+        assert!(detector.is_voice(&frame(5000)));
+
+        // Word endings go out for `HANGOVER`, then nothing does.
+        for _ in 0..hangover_frames() {
+            assert!(detector.is_voice(&frame(50)));
+        }
+        assert!(!detector.is_voice(&frame(50)));
+        assert!(!detector.is_voice(&frame(50)));
+
+        // Speech during the hangover starts it over.
+        detector.is_voice(&frame(5000));
+        for _ in 0..hangover_frames() - 1 {
+            detector.is_voice(&frame(50));
+        }
+        assert!(detector.is_voice(&frame(5000)));
+        for _ in 0..hangover_frames() {
+            assert!(detector.is_voice(&frame(50)));
+        }
+        assert!(!detector.is_voice(&frame(50)));
+    }
+
+    #[test]
+    fn rounds_the_hangover_up_to_whole_frames() {
+        let mut detector = VoiceDetector::new(Duration::from_millis(70));
+        detector.is_voice(&frame(5000));
+
+        // 300 ms takes five 70 ms frames.
+        for _ in 0..5 {
+            assert!(detector.is_voice(&frame(0)));
+        }
+        assert!(!detector.is_voice(&frame(0)));
+    }
+
+    #[test]
+    fn measures_the_background_for_comfort_noise() {
+        let mut detector = VoiceDetector::new(FRAME);
+        assert_eq!(detector.get_noise_level(), 0);
+        for _ in 0..100 {
+            detector.is_voice(&frame(300));
+        }
+        assert_eq!(detector.get_noise_level(), 299);
+
+        // Talking barely moves it, a quieter room takes over at once.
+        detector.is_voice(&frame(5000));
+        assert!(detector.get_noise_level() < 400);
+        detector.is_voice(&frame(100));
+        assert_eq!(detector.get_noise_level(), 100);
+    }
+
+    #[test]
+    fn counts_quiet_frames_over_a_loud_floor_as_silence() {
+        let mut detector = VoiceDetector::new(FRAME);
+        for _ in 0..100 {
+            detector.is_voice(&frame(1000));
+        }
+
+        // Loud enough to be speech in a quiet room, but not over this background.
+        assert!(!detector.is_voice(&frame(2500)));
+        assert!(detector.is_voice(&frame(4000)));
+    }
+
+    #[test]
+    fn fills_a_silence_marker_at_the_background_level() {
+        let mut detector = VoiceDetector::new(FRAME);
+        for _ in 0..100 {
+            assert!(!detector.is_voice(&frame(200)));
+        }
+        let level = detector.get_noise_level();
+
+        let mut buffer = JitterBuffer::new(FRAME, FRAME);
+        buffer.push(0, frame(5000));
+        assert!(matches!(buffer.pop(), Playout::Frame(_)));
+        buffer.push_silence(1, SILENCE_MARKER_FRAMES, level);
+        for _ in 0..SILENCE_MARKER_FRAMES {
+            assert!(matches!(buffer.pop(), Playout::Silence(played) if played == level));
+        }
+        assert!(!matches!(buffer.pop(), Playout::Silence(_)));
+    }
+}
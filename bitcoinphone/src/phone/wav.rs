@@ -0,0 +1,134 @@
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{self, Error, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+const WAVE_FORMAT_PCM: u16 = 1;
+const HEADER_SIZE: u32 = 44;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct WavSpec {
+    pub channels: u16,
+    pub sample_rate: u32,
+    pub bits_per_sample: u16,
+}
+
+impl WavSpec {
+    pub fn block_align(&self) -> u16 {
+        return self.channels * self.bits_per_sample / 8;
+    }
+}
+
+/// Minimal PCM WAV writer.
+///
+/// The RIFF and data sizes are rewritten after every write, so the file is a valid WAV
+/// even if the process is killed mid-call.
+pub struct WavWriter {
+    file: File,
+    spec: WavSpec,
+    data_bytes: u32,
+}
+
+impl WavWriter {
+    pub fn create(path: impl AsRef<Path>, spec: WavSpec) -> io::Result<WavWriter> {
+        let mut writer = WavWriter {
+            file: File::create(path)?,
+            spec,
+            data_bytes: 0,
+        };
+        writer.write_header()?;
+
+        return Ok(writer);
+    }
+
+    /// Appends interleaved sample bytes in the writer's format.
+    pub fn write_bytes(&mut self, data: &[u8]) -> io::Result<()> {
+        self.file.seek(SeekFrom::End(0))?;
+        self.file.write_all(data)?;
+        self.data_bytes += data.len() as u32;
+
+        return self.write_header();
+    }
+
+    /// Appends 16-bit samples, the writer must have been created with 16 bits per sample.
+    pub fn write_samples(&mut self, samples: &[i16]) -> io::Result<()> {
+        let mut bytes = Vec::with_capacity(samples.len() * 2);
+        for sample in samples {
+            bytes.extend_from_slice(&sample.to_le_bytes());
+        }
+
+        return self.write_bytes(&bytes);
+    }
+
+    pub fn get_spec(&self) -> WavSpec {
+        return self.spec;
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        let spec = self.spec;
+        let mut header = Vec::with_capacity(HEADER_SIZE as usize);
+        header.extend_from_slice(b"RIFF");
+        header.extend_from_slice(&(HEADER_SIZE - 8 + self.data_bytes).to_le_bytes());
+        header.extend_from_slice(b"WAVEfmt ");
+        header.extend_from_slice(&16u32.to_le_bytes());
+        header.extend_from_slice(&WAVE_FORMAT_PCM.to_le_bytes());
+        header.extend_from_slice(&spec.channels.to_le_bytes());
+        header.extend_from_slice(&spec.sample_rate.to_le_bytes());
+        header.extend_from_slice(&(spec.sample_rate * spec.block_align() as u32).to_le_bytes());
+        header.extend_from_slice(&spec.block_align().to_le_bytes());
+        header.extend_from_slice(&spec.bits_per_sample.to_le_bytes());
+        header.extend_from_slice(b"data");
+        header.extend_from_slice(&self.data_bytes.to_le_bytes());
+
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.write_all(&header)?;
+        return self.file.flush();
+    }
+}
+
+/// Reads a PCM WAV file, returning its format and the raw interleaved sample bytes.
+pub fn read(path: impl AsRef<Path>) -> io::Result<(WavSpec, Vec<u8>)> {
+    let mut bytes = vec![];
+    File::open(path)?.read_to_end(&mut bytes)?;
+
+    if bytes.len() < 12 || &bytes[0..4] != b"RIFF" || &bytes[8..12] != b"WAVE" {
+        return Err(Error::new(ErrorKind::InvalidData, "Not a RIFF/WAVE file"));
+    }
+
+    let mut spec = None;
+    let mut position = 12;
+    while position + 8 <= bytes.len() {
+        let id = &bytes[position..position + 4];
+        let size = u32::from_le_bytes(bytes[position + 4..position + 8].try_into().unwrap()) as usize;
+        let body_start = position + 8;
+        let body_end = (body_start + size).min(bytes.len());
+        let body = &bytes[body_start..body_end];
+
+        match id {
+            b"fmt " if body.len() >= 16 => {
+                let format = u16::from_le_bytes(body[0..2].try_into().unwrap());
+                if format != WAVE_FORMAT_PCM {
+                    return Err(Error::new(ErrorKind::InvalidData, "Only PCM WAV files are supported"));
+                }
+
+                spec = Some(WavSpec {
+                    channels: u16::from_le_bytes(body[2..4].try_into().unwrap()),
+                    sample_rate: u32::from_le_bytes(body[4..8].try_into().unwrap()),
+                    bits_per_sample: u16::from_le_bytes(body[14..16].try_into().unwrap()),
+                });
+            }
+            b"data" => {
+                return match spec {
+                    Some(spec) => Ok((spec, body.to_vec())),
+                    None => Err(Error::new(ErrorKind::InvalidData, "Data chunk before fmt chunk"))
+                };
+            }
+            _ => {}
+        }
+
+        // Chunks are padded to an even length.
+        position = body_start + size + (size & 1);
+    }
+
+    return Err(Error::new(ErrorKind::InvalidData, "WAV file has no data chunk"));
+}
@@ -36,9 +36,9 @@ impl KeyManager {
             key_map: HashMap::new(),
             wallet
         };
-        println!("Setting up keys");
+        eprintln!("Setting up keys");
         manager.setup_keys();
-        println!("Key setup complete!");
+        eprintln!("Key setup complete!");
 
         return manager;
     }
@@ -51,7 +51,7 @@ impl KeyManager {
     }
 
     pub fn setup_keys(&mut self) {
-        println!("We have {} keys", self.wallet.get_key_count());
+        eprintln!("We have {} keys", self.wallet.get_key_count());
         while self.wallet.get_key_count() < 2 {
             self.wallet.gen_key(&self.curve, &mut self.rng);
         }
@@ -21,6 +21,9 @@ pub const WALLET_FILE_NAME: &'static str = "wallet.json";
 pub struct Wallet {
     keys: Vec<Key>,
     utxos: Vec<UTXO>,
+    /// Whether changes are written to `WALLET_FILE_NAME`.
+    #[serde(skip)]
+    persistent: bool,
 }
 
 pub trait Walletable {
@@ -29,22 +32,39 @@ pub trait Walletable {
     fn gen_key(&self, curve: &Secp256k1<All>, rng: &mut OsRng);
     fn get_key(&self, index: usize) -> Key;
     fn get_balance(&self) -> u64;
+    fn get_utxos(&self) -> Vec<UTXO>;
     fn get_utxo_set(&self, amount: i64) -> Option<Vec<UTXO>>;
 }
 
 impl Wallet {
     pub fn new() -> Arc<Mutex<Wallet>> {
         let wallet_file = Self::open_file();
-        let wallet = wallet_file.unwrap_or(Wallet{
+        let mut wallet = wallet_file.unwrap_or(Wallet{
             keys: vec![],
-            utxos: vec![]
+            utxos: vec![],
+            persistent: true
         });
+        wallet.persistent = true;
         let arced_wallet = Arc::from(Mutex::from(wallet));
-        println!("Wallet has {} sats balance", arced_wallet.get_balance());
+        eprintln!("Wallet has {} sats balance", arced_wallet.get_balance());
 
         return arced_wallet;
     }
 
+    /// A wallet that's never saved, for phones that only last as long as a test.
+    pub fn in_memory() -> Arc<Mutex<Wallet>> {
+        return Arc::from(Mutex::from(Wallet{
+            keys: vec![],
+            utxos: vec![],
+            persistent: false
+        }));
+    }
+
+    /// Whether there's a wallet file to open, so commands that only read it don't make one.
+    pub fn exists() -> bool {
+        return fs::metadata(WALLET_FILE_NAME).is_ok();
+    }
+
     fn open_file() -> Option<Wallet> {
         if fs::metadata(WALLET_FILE_NAME).is_err() {
             return None;
@@ -57,6 +77,10 @@ impl Wallet {
     }
 
     fn update_file(&self) {
+        if !self.persistent {
+            return;
+        }
+
         let string = serde_yaml::to_string(self)
             .expect("Unable to serialize as YAML");
         fs::write(WALLET_FILE_NAME, string);
@@ -77,7 +101,7 @@ impl Spawnable<UTXOPacket> for Arc<Mutex<Wallet>> {
                     (packet as Vec<UTXO>)
                         .into_iter()
                         .for_each(|utxo| {
-                            println!("Got new utxo: {} sats", utxo.sats);
+                            eprintln!("Got new utxo: {} sats", utxo.sats);
                             this.add_utxo(utxo);
                         });
                 }
@@ -102,7 +126,7 @@ impl Walletable for Arc<Mutex<Wallet>> {
             pubkeyhash
         });
         unlocked_this.update_file();
-        println!("Created {} keys", this.get_key_count());
+        eprintln!("Created {} keys", unlocked_this.keys.len());
     }
 
     fn add_utxo(&self, mut utxo: UTXO) {
@@ -143,6 +167,10 @@ impl Walletable for Arc<Mutex<Wallet>> {
             .fold(0, |prev, cur| cur.sats as u64 + prev);
     }
 
+    fn get_utxos(&self) -> Vec<UTXO> {
+        return self.lock().unwrap().utxos.clone();
+    }
+
     fn get_utxo_set(&self, amount: i64) -> Option<Vec<UTXO>> {
         let mut wallet = self
             .lock()
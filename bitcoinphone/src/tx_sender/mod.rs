@@ -1,13 +1,17 @@
 use serde::Serialize;
 use std::sync::mpsc::{Sender, SyncSender, sync_channel, RecvError};
+use sv::util::Hash256;
 use sv::messages::{Tx, OutPoint, TxIn, TxOut, Payload};
 use std::collections::HashMap;
 use sv::script::Script;
 use std::thread;
 use std::sync::{Arc, Mutex, RwLock};
-use crate::net::{TxOperation, NetworkInterface};
-use sv::transaction::p2pkh::create_unlock_script;
-use crate::util::constants::{UTXO, DataPacket, CommunicationsKey, UTXOPacket, PaymentKey};
+use crate::net::{FrameTx, TxOperation, NetworkInterface};
+use crate::crypto::Session;
+use crate::wire;
+use rand::Rng;
+use sv::transaction::p2pkh::{create_lock_script, create_unlock_script};
+use crate::util::constants::{UTXO, DataPacket, CommunicationsKey, UTXOPacket, PaymentKey, PubKeyHash, PHONE_PREFIX};
 use crate::util::traits::Spawnable;
 use tx_builder::TxBuilder;
 use crate::tx_sender::keys::{KeyManager, Wallet, Walletable};
@@ -29,6 +33,9 @@ pub struct TxSender {
     network_interface: NetworkInterface,
     pub key_manager: KeyManager,
     total_funding_amount: RwLock<i64>,
+    /// Fees and dust paid by the last transaction we sent. Each one replaces the one before
+    /// by spending the same funding, so only the last can be mined.
+    sats_spent: RwLock<i64>,
     expected_locktime: u32
 }
 
@@ -38,22 +45,28 @@ impl TxSender {
         payment_sender: SyncSender<UTXOPacket>,
         data_sender: SyncSender<DataPacket>,
     ) -> Arc<TxSender> {
-        let mut network_interface = NetworkInterface::new(
+        let network_interface = NetworkInterface::new(
             payment_sender,
             data_sender
         );
 
-        println!("Attempting to connect to {}, peers..", MAXIMUM_PEERS);
+        return Self::with_network(key_manager, network_interface);
+    }
+
+    /// Sends and receives through an interface that's already set up, such as a loopback.
+    pub fn with_network(key_manager: KeyManager, mut network_interface: NetworkInterface) -> Arc<TxSender> {
+        eprintln!("Attempting to connect to {}, peers..", MAXIMUM_PEERS);
         network_interface.subscribe_to_payments(&key_manager.get_key(PaymentKey));
         network_interface.subscribe_to_comms(&key_manager.get_key(CommunicationsKey));
         network_interface.connect(MAXIMUM_PEERS);
-        println!("Connected to peers!");
+        eprintln!("Connected to peers!");
 
         return Arc::from(TxSender{
             active_utxos: RwLock::from(Vec::new()),
             network_interface,
             key_manager,
             total_funding_amount: RwLock::from(0),
+            sats_spent: RwLock::from(0),
             expected_locktime: get_timestamp()
                 .add(Duration::from_secs(7200))
                 .as_secs() as u32
@@ -66,7 +79,7 @@ impl TxSender {
             .get_utxo_set(sats_needed as i64);
 
         if utxo_set.is_none() {
-            println!("You need to fund your address!");
+            eprintln!("You need to fund your address!");
             return;
         }
 
@@ -94,18 +107,107 @@ impl TxSender {
         self.network_interface.broadcast(tx);
     }
 
-    pub fn send_data(self: Arc<Self>, data: impl Serialize, receiver_output: Script) {
+    /// Sends everything in the wallet to `pubkeyhash`, less the miner's fee. Returns the
+    /// txid and how much it sent, `None` when the wallet holds too little to be worth it.
+    pub fn sweep(&self, pubkeyhash: PubKeyHash) -> Option<(Hash256, i64)> {
+        let mut inputs = self.key_manager.wallet
+            .get_utxo_set(i64::MAX)
+            .unwrap_or_default();
+        let total = inputs.iter().fold(0, |total, utxo| total + utxo.sats);
+
+        // Unlike a call's transactions this one is final, so it can be mined right away.
+        let tx = TxBuilder::new(0, total)
+            .add_change_output(pubkeyhash)
+            .build(&mut inputs, &self.key_manager);
+
+        if tx.outputs.is_empty() {
+            inputs.into_iter().for_each(|utxo| self.key_manager.wallet.add_utxo(utxo));
+            return None;
+        }
+
+        let sats = tx.outputs[0].satoshis;
+        return Some((self.network_interface.broadcast(tx), sats));
+    }
+
+    /// Reports the txid of every voice frame we receive.
+    pub fn watch_frames(&self, watcher: SyncSender<FrameTx>) {
+        self.network_interface.watch_frames(watcher);
+    }
+
+    pub fn set_session(&self, session: Option<Arc<Session>>) {
+        self.network_interface.set_session(session);
+    }
+
+    pub fn set_conference(&self, sessions: Vec<Arc<Session>>) {
+        self.network_interface.set_conference(sessions);
+    }
+
+    /// Sends the packet to the receivers, each gets a dust output in the same transaction.
+    /// Returns the txid it went out in. Packets too big for one output are sent in
+    /// fragments, one transaction each, and the first txid is returned.
+    pub fn send_data(self: Arc<Self>, packet: DataPacket, receiver_outputs: &[Script]) -> Hash256 {
+        let fragments = wire::split(
+            wire::encode(&packet),
+            rand::thread_rng().gen(),
+            MAX_BYTES_PER_PACKET - PHONE_PREFIX.len()
+        );
+
+        let txids = fragments
+            .into_iter()
+            .map(|data| self.send_outputs(vec![data], receiver_outputs))
+            .collect::<Vec<Hash256>>();
+
+        return txids[0];
+    }
+
+    /// Sends several packets in one transaction, an output each, saving the dust and fees
+    /// of a transaction per packet. Returns the txid each packet went out in, packets
+    /// that need fragments still go in transactions of their own.
+    pub fn send_batch(self: Arc<Self>, packets: Vec<DataPacket>, receiver_outputs: &[Script]) -> Vec<Hash256> {
+        let max_len = MAX_BYTES_PER_PACKET - PHONE_PREFIX.len();
+        let encoded = packets.iter()
+            .map(wire::encode)
+            .collect::<Vec<Vec<u8>>>();
+
+        if encoded.len() == 1 || encoded.iter().any(|data| data.len() > max_len) {
+            return packets.into_iter()
+                .map(|packet| self.clone().send_data(packet, receiver_outputs))
+                .collect();
+        }
+
+        let count = encoded.len();
+        let txid = self.send_outputs(encoded, receiver_outputs);
+        return vec![txid; count];
+    }
+
+    /// What's left of the funding once everything we've sent is paid for.
+    pub fn get_funds_left(&self) -> i64 {
+        return *self.total_funding_amount.read().unwrap() - self.get_sats_spent();
+    }
+
+    /// What the transaction that will be mined pays, the last one we sent.
+    pub fn get_sats_spent(&self) -> i64 {
+        return *self.sats_spent.read().unwrap();
+    }
+
+    fn send_outputs(&self, data: Vec<Vec<u8>>, receiver_outputs: &[Script]) -> Hash256 {
         let funding = *self.total_funding_amount.read().unwrap();
         let mut inputs = self.active_utxos.write().unwrap();
-        let mut data = bincode::serialize(&data)
-            .expect("Unable to serialize packet data.");
+        let change_script = create_lock_script(&self.key_manager.get_key(PaymentKey).pubkeyhash);
 
-        let mut tx = TxBuilder::new(self.expected_locktime, funding)
-            .add_data_output(data,0)
-            .add_script_output(receiver_output, MIN_DUST)
+        let builder = data.into_iter()
+            .fold(TxBuilder::new(self.expected_locktime, funding), |builder, data| builder.add_data_output(data, 0));
+        let mut tx = receiver_outputs.iter()
+            .fold(builder, |builder, output| builder.add_script_output(output.clone(), MIN_DUST))
             .add_change_output(self.key_manager.get_key(PaymentKey).pubkeyhash)
             .build(&mut inputs, &self.key_manager);
 
-        self.network_interface.broadcast(tx);
+        // Whatever doesn't come back to us as change went to the peers or the miners.
+        let change = tx.outputs.iter()
+            .filter(|output| output.lock_script == change_script)
+            .fold(0, |total, output| total + output.satoshis);
+        *self.sats_spent.write().unwrap() = funding - change;
+
+        return self.network_interface.broadcast(tx);
     }
 }
\ No newline at end of file
@@ -94,7 +94,7 @@ impl TxBuilder {
             .collect::<Vec<TxOut>>();
 
         if self.change_output.is_none() {
-            println!("Danger: Building tx without change output");
+            eprintln!("Danger: Building tx without change output");
             return tx;
         }
 
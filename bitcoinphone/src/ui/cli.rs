@@ -0,0 +1,314 @@
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::thread;
+use std::time::Duration;
+use serde_json::{json, Value};
+use sv::address::addr_decode;
+use sv::network::Network;
+use sv::transaction::p2pkh::create_lock_script;
+use crate::domain::call::{CallEvent, CallState};
+use crate::net::NetworkInterface;
+use crate::tx_sender::{TxSender, MAXIMUM_PEERS};
+use crate::tx_sender::keys::{KeyManager, Wallet, Walletable};
+use crate::ui::{describe_event, get_address};
+use crate::util::constants::{CommunicationsKey, DataPacket, PaymentKey, PubKeyHash, UIEvent};
+use crate::util::traits::Spawnable;
+
+/// How long `sweep` stays connected once it has announced its transaction, peers fetch
+/// it from us.
+pub const BROADCAST_WAIT: Duration = Duration::from_secs(10);
+
+pub const USAGE: &str = "Usage: bitcoinphone [command] [--json] [options]
+
+Commands:
+  init               create a wallet and print its addresses
+  address            print the funding and communication addresses
+  balance            print the balance in sats
+  utxos              list the outputs the wallet can spend
+  call <address...>  call an address, or several for a conference, and quit once it ends
+  listen             answer every incoming call until Ctrl+C
+  sweep <address>    send the whole balance to an address
+  peers              connect to the network and list the peers found
+
+Without a command the phone starts with a prompt. --json prints results and call events
+as one JSON document per line.";
+
+/// Options that stand alone, every other option is followed by its value.
+const FLAGS: &[&str] = &["--json", "--list-devices", "--help"];
+const OPTIONS: &[&str] = &[
+    "--sample-rate", "--sample-format", "--fec", "--backend", "--wav-input", "--wav-output",
+    "--input-device", "--output-device", "--record",
+];
+
+pub enum Command {
+    /// No command, the user types what to do.
+    Prompt,
+    Init,
+    Address,
+    Balance,
+    Utxos,
+    Call { outputs: Vec<Vec<u8>> },
+    Listen,
+    Sweep { pubkeyhash: PubKeyHash },
+    Peers,
+}
+
+impl Command {
+    /// Reads the command and its arguments, skipping the options around them.
+    pub fn parse(args: &[String]) -> Command {
+        let mut words = vec![];
+        let mut args = args.iter().skip(1);
+        while let Some(arg) = args.next() {
+            if OPTIONS.contains(&arg.as_str()) {
+                args.next();
+            } else if !arg.starts_with("--") {
+                words.push(arg.as_str());
+            } else if !FLAGS.contains(&arg.as_str()) {
+                fail(USAGE);
+            }
+        }
+
+        return match words.as_slice() {
+            [] => Command::Prompt,
+            ["init"] => Command::Init,
+            ["address"] => Command::Address,
+            ["balance"] => Command::Balance,
+            ["utxos"] => Command::Utxos,
+            ["call", addresses @ ..] if !addresses.is_empty() => Command::Call {
+                outputs: addresses.iter()
+                    .map(|address| create_lock_script(&parse_address(address)).0)
+                    .collect()
+            },
+            ["listen"] => Command::Listen,
+            ["sweep", address] => Command::Sweep{ pubkeyhash: parse_address(address) },
+            ["peers"] => Command::Peers,
+            _ => fail(USAGE)
+        };
+    }
+}
+
+/// How commands print what they found.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Output {
+    Text,
+    Json,
+}
+
+impl Output {
+    pub fn from_args(args: &[String]) -> Output {
+        if args.iter().any(|arg| arg == "--json") {
+            return Output::Json;
+        }
+        return Output::Text;
+    }
+
+    /// Prints `text` for a person or `json` on a line of its own for a script.
+    pub fn print(&self, text: String, json: Value) {
+        match self {
+            Output::Text => println!("{}", text),
+            Output::Json => println!("{}", json),
+        }
+    }
+}
+
+pub fn init(output: Output) {
+    print_addresses(&KeyManager::new(Wallet::new()), output);
+}
+
+pub fn address(output: Output) {
+    print_addresses(&KeyManager::new(open_wallet()), output);
+}
+
+pub fn balance(output: Output) {
+    let balance = open_wallet().get_balance();
+    output.print(format!("{} sats", balance), json!({ "balance": balance }));
+}
+
+pub fn utxos(output: Output) {
+    let utxos = open_wallet().get_utxos();
+    let text = utxos.iter()
+        .map(|utxo| format!("{}:{} {} sats", utxo.outpoint.hash.encode(), utxo.outpoint.index, utxo.sats))
+        .collect::<Vec<String>>()
+        .join("\n");
+    let json = utxos.iter()
+        .map(|utxo| json!({
+            "txid": utxo.outpoint.hash.encode(),
+            "vout": utxo.outpoint.index,
+            "sats": utxo.sats,
+        }))
+        .collect::<Vec<Value>>();
+
+    output.print(text, Value::Array(json));
+}
+
+pub fn sweep(pubkeyhash: PubKeyHash, output: Output) {
+    let wallet = open_wallet();
+    let key_manager = KeyManager::new(wallet.clone());
+    let (data_sender, _data_receiver) = sync_channel(1000);
+    let tx_sender = TxSender::new(key_manager, wallet.spawn_gateway(), data_sender);
+
+    let (txid, sats) = match tx_sender.sweep(pubkeyhash) {
+        Some(sent) => sent,
+        None => fail("Nothing to sweep, the balance doesn't cover the fee")
+    };
+    output.print(
+        format!("Sent {} sats to {} in {}", sats, get_address(&pubkeyhash), txid.encode()),
+        json!({ "txid": txid.encode(), "sats": sats })
+    );
+
+    thread::sleep(BROADCAST_WAIT);
+}
+
+pub fn peers(output: Output) {
+    let (payment_sender, _payment_receiver) = sync_channel(1000);
+    let (data_sender, _data_receiver) = sync_channel(1000);
+    let mut network_interface = NetworkInterface::new(payment_sender, data_sender);
+    network_interface.connect(MAXIMUM_PEERS);
+
+    let peers = network_interface.peers();
+    let text = peers.iter()
+        .map(|peer| format!("{}:{} {} at height {}", peer.ip, peer.port, peer.user_agent, peer.start_height))
+        .collect::<Vec<String>>()
+        .join("\n");
+
+    output.print(text, serde_json::to_value(&peers).expect("Unable to serialize peers"));
+}
+
+pub fn print_addresses(key_manager: &KeyManager, output: Output) {
+    let funding = get_address(&key_manager.get_key(PaymentKey).pubkeyhash);
+    let communication = get_address(&key_manager.get_key(CommunicationsKey).pubkeyhash);
+
+    output.print(
+        format!("Funding address: {}\nCommunication address: {}", funding, communication),
+        json!({ "funding_address": funding, "communication_address": communication })
+    );
+}
+
+/// Reports how calls go for `call` and `listen`. A listener answers every call, otherwise
+/// the phone quits once its call has ended.
+pub fn watch_headless(events: Receiver<CallEvent>, sender: SyncSender<DataPacket>, output: Output, listening: bool) {
+    thread::spawn(move || loop {
+        let event = match events.recv() {
+            Ok(event) => event,
+            Err(_) => return
+        };
+
+        match output {
+            Output::Text => {
+                if let Some(text) = describe_event(&event) {
+                    println!("{}", text);
+                }
+            }
+            Output::Json => println!("{}", event_json(&event)),
+        }
+
+        match event {
+            CallEvent::IncomingCall{ .. } if listening => {
+                sender.send(DataPacket::UIEvent(UIEvent::Accept)).ok();
+            }
+            CallEvent::StateChanged{ to: CallState::Ended, .. } if !listening => {
+                sender.send(DataPacket::UIEvent(UIEvent::Quit)).ok();
+            }
+            _ => {}
+        }
+    });
+}
+
+fn event_json(event: &CallEvent) -> Value {
+    return match event {
+        CallEvent::StateChanged{ from, to } => {
+            json!({ "event": "state_changed", "from": format!("{:?}", from), "to": format!("{:?}", to) })
+        }
+        CallEvent::IncomingCall{ caller } => json!({ "event": "incoming_call", "caller": caller }),
+        CallEvent::Retrying{ attempt } => json!({ "event": "retrying", "attempt": attempt }),
+        CallEvent::Stats(stats) => json!({ "event": "stats", "stats": stats }),
+        CallEvent::Summary(stats) => json!({ "event": "summary", "stats": stats }),
+        CallEvent::ConferenceStarted{ participants } => {
+            json!({ "event": "conference_started", "participants": participants })
+        }
+        CallEvent::ParticipantFailed{ participant, error } => {
+            json!({ "event": "participant_failed", "participant": participant, "error": error.to_string() })
+        }
+        CallEvent::ParticipantLeft{ participant } => json!({ "event": "participant_left", "participant": participant }),
+        CallEvent::MessageSent{ id } => json!({ "event": "message_sent", "id": format!("{:016x}", id) }),
+        CallEvent::TextReceived{ from, text } => json!({ "event": "text_received", "from": from, "text": text }),
+        CallEvent::AttachmentReceived{ from, name, data } => {
+            json!({ "event": "attachment_received", "from": from, "name": name, "data": hex::encode(data) })
+        }
+        CallEvent::MessageDelivered{ id, to } => {
+            json!({ "event": "message_delivered", "id": format!("{:016x}", id), "to": to })
+        }
+        CallEvent::MessageFailed{ id, to } => {
+            json!({ "event": "message_failed", "id": format!("{:016x}", id), "to": to })
+        }
+        CallEvent::Failed(err) => json!({ "event": "failed", "error": err.to_string() }),
+    };
+}
+
+/// The wallet `init` made, commands that only read it shouldn't make one.
+fn open_wallet() -> Arc<Mutex<Wallet>> {
+    if !Wallet::exists() {
+        fail("There's no wallet here yet, run `bitcoinphone init` first");
+    }
+    return Wallet::new();
+}
+
+fn parse_address(address: &str) -> PubKeyHash {
+    return match addr_decode(address.trim_end_matches(','), Network::Mainnet) {
+        Ok((pubkeyhash, _)) => pubkeyhash,
+        Err(_) => fail(&format!("{} isn't a valid address", address))
+    };
+}
+
+/// Prints the message and exits with status 1.
+pub fn fail(message: &str) -> ! {
+    eprintln!("{}", message);
+    std::process::exit(1);
+}
+
+#[cfg(test)]
+mod tests {
+    use sv::util::Hash160;
+    use super::*;
+
+    fn args(line: &str) -> Vec<String> {
+        return line.split_whitespace().map(|arg| arg.to_string()).collect();
+    }
+
+    #[test]
+    fn parses_commands_before_options() {
+        let address = get_address(&Hash160([7; 20]));
+        match Command::parse(&args(&format!("bitcoinphone call {} --json --fec 4:1", address))) {
+            Command::Call{ outputs } => assert_eq!(outputs, vec![create_lock_script(&Hash160([7; 20])).0]),
+            _ => panic!("Expected a call")
+        }
+        match Command::parse(&args(&format!("bitcoinphone sweep {}", address))) {
+            Command::Sweep{ pubkeyhash } => assert_eq!(pubkeyhash, Hash160([7; 20])),
+            _ => panic!("Expected a sweep")
+        }
+        assert!(matches!(Command::parse(&args("bitcoinphone balance --json")), Command::Balance));
+
+        // An option's value isn't taken for a command.
+        assert!(matches!(Command::parse(&args("bitcoinphone --sample-rate 8000")), Command::Prompt));
+    }
+
+    #[test]
+    fn parses_commands_after_options() {
+        let address = get_address(&Hash160([7; 20]));
+        match Command::parse(&args(&format!("bitcoinphone --json call {}", address))) {
+            Command::Call{ outputs } => assert_eq!(outputs, vec![create_lock_script(&Hash160([7; 20])).0]),
+            _ => panic!("Expected a call")
+        }
+        assert!(matches!(Command::parse(&args("bitcoinphone --fec 4:1 --json balance")), Command::Balance));
+        assert!(matches!(Command::parse(&args("bitcoinphone --record calls listen --json")), Command::Listen));
+    }
+
+    #[test]
+    fn prints_json_on_request() {
+        assert_eq!(Output::from_args(&args("bitcoinphone balance --json")), Output::Json);
+        assert_eq!(Output::from_args(&args("bitcoinphone balance")), Output::Text);
+
+        let event = CallEvent::StateChanged{ from: CallState::Dialing, to: CallState::Syncing };
+        assert_eq!(event_json(&event).to_string(), r#"{"event":"state_changed","from":"Dialing","to":"Syncing"}"#);
+    }
+}
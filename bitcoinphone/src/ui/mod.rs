@@ -1,13 +1,15 @@
 use std::thread;
-use std::sync::mpsc::SyncSender;
-use crate::util::constants::{DataPacket, UIEvent, PaymentKey, PubKeyHash, CommunicationsKey};
+use std::sync::mpsc::{Receiver, SyncSender};
+use crate::util::constants::{DataPacket, UIEvent, PaymentKey, PubKeyHash, CommunicationsKey, MAX_ATTACHMENT_SIZE};
 use crate::tx_sender::keys::KeyManager;
+use crate::domain::call::{CallEvent, CallState};
 use sv::address::{addr_encode, AddressType, addr_decode};
 use sv::network::Network;
 use sv::util::Hash160;
 use sv::transaction::p2pkh::create_lock_script;
 
 mod events;
+pub mod cli;
 
 pub fn start(key_manager: KeyManager, sender: SyncSender<DataPacket>) {
     thread::spawn(move || {
@@ -15,30 +17,158 @@ pub fn start(key_manager: KeyManager, sender: SyncSender<DataPacket>) {
         let comms_address = get_address(&key_manager.get_key(CommunicationsKey).pubkeyhash);
         println!("Welcome to Bitphone, please fund this address {}", funding_address);
         println!("Your personal communication address is {}", comms_address);
-        let mut address = String::new();
-        std::io::stdin().read_line(&mut address)
-            .expect("unable to read from input");
-
-        let output = create_lock_script(&get_pubkeyhash(address)).0;
-        sender.send(DataPacket::UIEvent(UIEvent::Start{
-            output
-        }));
+        println!("Enter an address to call it, several for a conference, or 'hangup' to end a call");
+        println!("'msg [address] <text>' sends a text and 'file [address] <path>' a small file, to the call without an address");
+
+        // Every address entered starts a call, so a failed one can be dialed again.
+        loop {
+            let mut address = String::new();
+            std::io::stdin().read_line(&mut address)
+                .expect("unable to read from input");
+            if address.trim().is_empty() {
+                continue;
+            }
+            let command = match address.trim() {
+                "accept" => Some(UIEvent::Accept),
+                "decline" => Some(UIEvent::Decline),
+                "hangup" => Some(UIEvent::HangUp),
+                _ => None
+            };
+            if let Some(command) = command {
+                sender.send(DataPacket::UIEvent(command));
+                continue;
+            }
+            if let Some(message) = parse_message(address.trim()) {
+                if let Some(message) = message {
+                    sender.send(DataPacket::UIEvent(message));
+                }
+                continue;
+            }
+
+            let outputs = address
+                .split(|c: char| c.is_whitespace() || c == ',')
+                .filter(|address| !address.is_empty())
+                .map(|address| addr_decode(address, Network::Mainnet)
+                    .map(|(pubkeyhash, _)| create_lock_script(&pubkeyhash).0))
+                .collect::<Result<Vec<Vec<u8>>, _>>();
+            let outputs = match outputs {
+                Ok(outputs) => outputs,
+                Err(_) => {
+                    println!("That isn't a valid address, please try again");
+                    continue;
+                }
+            };
+
+            sender.send(DataPacket::UIEvent(UIEvent::Start{
+                outputs
+            }));
+        }
     });
 }
 
+/// Tells the user how their calls are going.
+pub fn watch_calls(events: Receiver<CallEvent>) {
+    thread::spawn(move || loop {
+        match events.recv() {
+            Ok(CallEvent::IncomingCall{ caller }) => {
+                println!("Incoming call from {}, type 'accept' or 'decline'", caller);
+            }
+            Ok(event) => {
+                if let Some(text) = describe_event(&event) {
+                    println!("{}", text);
+                }
+            }
+            Err(_) => return
+        }
+    });
+}
+
+/// What to tell the user about a call event, `None` for the ones they don't need to hear about.
+pub fn describe_event(event: &CallEvent) -> Option<String> {
+    let text = match event {
+        CallEvent::StateChanged{ to: CallState::Dialing, .. } => "Calling...".to_string(),
+        CallEvent::StateChanged{ to: CallState::InCall, .. } => "Call connected.".to_string(),
+        CallEvent::StateChanged{ to: CallState::Ended, .. } => "Call ended.".to_string(),
+        CallEvent::StateChanged{ .. } => return None,
+        CallEvent::IncomingCall{ caller } => format!("Incoming call from {}", caller),
+        CallEvent::Retrying{ attempt } => format!("No answer yet, trying again (attempt {})", attempt),
+        CallEvent::Stats(stats) => format!("Call quality: {}", stats),
+        CallEvent::Summary(stats) => format!("Call summary: {}", stats),
+        CallEvent::ConferenceStarted{ participants } => format!("Conference with {}", participants.join(", ")),
+        CallEvent::ParticipantFailed{ participant, error } => {
+            format!("{} didn't join the conference: {}", participant, error)
+        }
+        CallEvent::ParticipantLeft{ participant } => format!("{} left the conference", participant),
+        CallEvent::MessageSent{ id } => format!("Message {:016x} sent", id),
+        CallEvent::TextReceived{ from, text } => format!("{}: {}", from, text),
+        CallEvent::AttachmentReceived{ from, name, data } => {
+            let mut text = format!("{} sent {} ({} bytes)", from, name, data.len());
+            if let Ok(contents) = std::str::from_utf8(data) {
+                text = format!("{}\n{}", text, contents);
+            }
+            text
+        }
+        CallEvent::MessageDelivered{ id, to } => format!("Message {:016x} delivered to {}", id, to),
+        CallEvent::MessageFailed{ id, to } => format!("Message {:016x} never reached {}", id, to),
+        CallEvent::Failed(err) => format!("Call failed: {}", err),
+    };
 
+    return Some(text);
+}
 
+/// Reads a 'msg' or 'file' command, `None` if it's neither and `Some(None)` if it can't
+/// be sent.
+fn parse_message(line: &str) -> Option<Option<UIEvent>> {
+    let (command, rest) = match line.find(char::is_whitespace) {
+        Some(split) => (&line[..split], line[split..].trim_start()),
+        None => (line, "")
+    };
+    if command != "msg" && command != "file" {
+        return None;
+    }
 
-fn get_address(pubkeyhash: &PubKeyHash) -> String {
+    // Leading words that are addresses say who it's for.
+    let mut outputs = vec![];
+    let mut body = rest;
+    while let Some(word) = body.split_whitespace().next() {
+        match addr_decode(word.trim_end_matches(','), Network::Mainnet) {
+            Ok((pubkeyhash, _)) => outputs.push(create_lock_script(&pubkeyhash).0),
+            Err(_) => break
+        }
+        body = body[word.len()..].trim_start();
+    }
+    if body.is_empty() {
+        println!("Usage: {} [address] <{}>", command, if command == "msg" { "text" } else { "path" });
+        return Some(None);
+    }
+
+    if command == "msg" {
+        return Some(Some(UIEvent::Text{ outputs, text: body.to_string() }));
+    }
+
+    let data = match std::fs::read(body) {
+        Ok(data) => data,
+        Err(err) => {
+            println!("Unable to read {}: {}", body, err);
+            return Some(None);
+        }
+    };
+    if data.len() > MAX_ATTACHMENT_SIZE {
+        println!("{} is {} bytes, attachments can be at most {}", body, data.len(), MAX_ATTACHMENT_SIZE);
+        return Some(None);
+    }
+    let name = std::path::Path::new(body)
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_else(|| body.to_string());
+
+    return Some(Some(UIEvent::Attachment{ outputs, name, data }));
+}
+
+pub fn get_address(pubkeyhash: &PubKeyHash) -> String {
     return addr_encode(
         &pubkeyhash,
         AddressType::P2PKH,
         Network::Mainnet
     );
 }
-fn get_pubkeyhash(address: String) -> Hash160 {
-    return addr_decode(
-        &address.trim(),
-        Network::Mainnet
-    ).expect("Unable to get pubkeyhash").0;
-}
\ No newline at end of file
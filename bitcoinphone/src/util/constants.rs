@@ -16,32 +16,181 @@ pub const CHANNELS: i32 = 1;
 
 pub const BUFFER_SIZE: usize = SAMPLE_RATE as usize;
 pub const PHONE_PREFIX: &[u8] = &[OP_FALSE, OP_RETURN, 0x70, 0x68, 0x6f, 0x6e, 0x65];
+/// Largest file we send as an attachment, anything bigger takes too many transactions.
+pub const MAX_ATTACHMENT_SIZE: usize = 64 * 1024;
 
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub enum UIEvent {
+    /// Call these outputs, more than one makes a conference we host.
     Start{
-        output: Vec<u8>
+        outputs: Vec<Vec<u8>>
+    },
+    /// Answer the call that is ringing.
+    Accept,
+    Decline,
+    HangUp,
+    /// Hang up any call and shut down.
+    Quit,
+    /// Message these outputs, or everyone on the call when there are none.
+    Text{
+        outputs: Vec<Vec<u8>>,
+        text: String
+    },
+    /// Send a small file, addressed the same way as a text.
+    Attachment{
+        outputs: Vec<Vec<u8>>,
+        name: String,
+        data: Vec<u8>
     }
 }
 
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+pub enum CodecType {
+    Pcm,
+    MuLaw,
+    ImaAdpcm,
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+pub enum SampleFormat {
+    U8,
+    I16,
+    F32,
+}
+
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub enum DataPacket {
     UIEvent(UIEvent),
     Start {
         output: Vec<u8>,
-        sync_count: u64
+        /// Picked by the caller, every later packet in the call carries it.
+        session_id: u64,
+        sync_count: u64,
+        codecs: Vec<CodecType>,
+        sample_rate: u32,
+        frames_per_buffer: u32,
+        public_key: Vec<u8>,
+        salt: Vec<u8>
     },
     StartAck {
         output: Vec<u8>,
-        sync_count: u64
+        session_id: u64,
+        sync_count: u64,
+        codec: CodecType,
+        sample_rate: u32,
+        frames_per_buffer: u32,
+        public_key: Vec<u8>,
+        salt: Vec<u8>,
+        /// Every codec the callee can decode, the caller may switch between them mid-call.
+        /// Empty from phones that can only take `codec`.
+        codecs: Vec<CodecType>
     },
     Sync {
         time: u128,
         count: u64
     },
+    /// Answers a sync straight away so the sender can time the round trip.
+    SyncReply {
+        count: u64,
+        /// The sync's `time`.
+        origin: u128,
+        /// When the sync reached us.
+        received: u128,
+        time: u128
+    },
     Data {
         counter: u32,
-        buffer: Vec<u8>
+        format: SampleFormat,
+        buffer: Vec<u8>,
+        /// Codec the frame is in, `None` for the one agreed when the call started.
+        codec: Option<CodecType>
+    },
+    Silence {
+        counter: u32,
+        frames: u32,
+        level: u16
+    },
+    /// XOR of the data frames listed, rebuilds one of them if it goes missing.
+    Repair {
+        counters: Vec<u32>,
+        format: SampleFormat,
+        /// XOR of the frames' lengths.
+        length: u32,
+        parity: Vec<u8>,
+        /// Codec of the frames covered, as for `Data`.
+        codec: Option<CodecType>
+    },
+    /// Control packets carry the id of the call they're for, anything for another call
+    /// is ignored.
+    HangUp {
+        session_id: u64
+    },
+    /// Sent back to a caller when we're already on a call.
+    Busy {
+        session_id: u64
+    },
+    Reject {
+        session_id: u64,
+        reason: String
+    },
+    /// Tells the peer we're still here when we have nothing else to send.
+    KeepAlive,
+    /// Any other packet sealed with the call's session keys.
+    Encrypted {
+        session_id: u64,
+        counter: u64,
+        payload: Vec<u8>
+    },
+    /// Sent by a conference's host to each participant over their own session, with
+    /// what everyone needs to talk to everyone else.
+    Conference {
+        /// Participant `n` seals with this plus `n`.
+        session_id: u64,
+        /// The participant this copy is for.
+        index: u32,
+        /// Every participant's keys are derived from this.
+        secret: Vec<u8>,
+        /// Everyone's output, the host first.
+        participants: Vec<Vec<u8>>,
+        /// Rate each participant captures at, in the same order.
+        sample_rates: Vec<u32>
+    },
+    /// A chat message, sent in or out of a call. The receiver acks it to `from`.
+    Text {
+        id: u64,
+        from: Vec<u8>,
+        text: String
+    },
+    /// A small file, sent and acked like a text.
+    Attachment {
+        id: u64,
+        from: Vec<u8>,
+        name: String,
+        data: Vec<u8>
+    },
+    /// `from` got the text or attachment `id`.
+    MessageAck {
+        id: u64,
+        from: Vec<u8>
+    },
+    /// Never sent, the network side wraps packets from a conference's participants in
+    /// this to say who sent them.
+    FromParticipant {
+        session_id: u64,
+        packet: Box<DataPacket>
+    },
+    /// Any other packet signed with the sender's communications key, for packets that go
+    /// out without a session.
+    Signed {
+        public_key: Vec<u8>,
+        signature: Vec<u8>,
+        payload: Vec<u8>
+    },
+    /// Never sent, the network side wraps signed hang ups and refusals in this to say
+    /// whose key signed them.
+    FromSender {
+        output: Vec<u8>,
+        packet: Box<DataPacket>
     }
 }
 
@@ -73,7 +222,7 @@ impl Key {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct UTXO {
     pub outpoint: OutPoint,
     pub sats: i64,
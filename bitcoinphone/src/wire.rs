@@ -0,0 +1,824 @@
+//! The bytes phones send each other after `PHONE_PREFIX`.
+//!
+//! Every packet is wrapped in an envelope:
+//!
+//! | size | field                                                 |
+//! |------|-------------------------------------------------------|
+//! | 1    | version, currently 1                                  |
+//! | 1    | packet type                                           |
+//! | 1    | flags, bit 0 marks a fragment, the rest are sent as 0 |
+//! | 4    | length of the body                                    |
+//! | n    | body                                                  |
+//!
+//! The body holds the packet's fields in the order below. Integers are big endian and
+//! fixed width. Bytes and text have a 4 byte length in front of them, lists a 4 byte
+//! count. Decoders ignore anything in the body after the fields they know, so
+//! new fields can be added to the end of a packet without a new version.
+//!
+//! | type | packet     | fields                                                                                                  |
+//! |------|------------|---------------------------------------------------------------------------------------------------------|
+//! | 1    | Start      | output bytes, session_id u64, sync_count u64, codecs list, sample_rate u32, frames_per_buffer u32, public_key bytes, salt bytes |
+//! | 2    | StartAck   | output bytes, session_id u64, sync_count u64, codec u8, sample_rate u32, frames_per_buffer u32, public_key bytes, salt bytes, codecs list* |
+//! | 3    | Sync       | time u128, count u64                                                                                    |
+//! | 4    | Data       | counter u32, format u8, buffer bytes, codec u8*                                                         |
+//! | 5    | Silence    | counter u32, frames u32, level u16                                                                      |
+//! | 6    | HangUp     | session_id u64                                                                                          |
+//! | 7    | Busy       | session_id u64                                                                                          |
+//! | 8    | Reject     | session_id u64, reason text                                                                             |
+//! | 9    | KeepAlive  |                                                                                                         |
+//! | 10   | Encrypted  | session_id u64, counter u64, payload bytes, itself an encoded packet once opened                       |
+//! | 11   | Repair     | counters list of u32, format u8, length u32, parity bytes, codec u8*                                  |
+//! | 12   | SyncReply  | count u64, origin u128, received u128, time u128                                                        |
+//! | 13   | Conference | session_id u64, index u32, secret bytes, participants list of bytes, sample_rates list of u32          |
+//! | 14   | Text       | id u64, from bytes, text text                                                                           |
+//! | 15   | Attachment | id u64, from bytes, name text, data bytes                                                               |
+//! | 16   | MessageAck | id u64, from bytes                                                                                      |
+//! | 17   | Signed     | public_key bytes, signature bytes, payload bytes, an encoded packet signed by public_key                |
+//!
+//! Fields marked * were added later and are left off when empty or unset, so packets
+//! without them still encode exactly as they always have.
+//!
+//! The codecs list holds one byte per codec, 0 for PCM, 1 for mu-law and 2 for IMA ADPCM.
+//! Codecs we don't know in a start's list are skipped. Sample formats are 0 for U8, 1 for
+//! I16 and 2 for F32.
+//!
+//! A packet too big for one output is split into fragments. Each is an envelope with the
+//! fragment flag set, the type of the packet it carries part of, and a body of message id
+//! u64, index u16, count u16 and chunk bytes. Joined in index order the chunks of a message
+//! are the packet's complete encoding.
+
+use std::convert::TryInto;
+use std::fmt;
+use crate::util::constants::{CodecType, DataPacket, SampleFormat};
+
+pub const VERSION: u8 = 1;
+
+const HEADER_SIZE: usize = 7;
+/// Envelope and fragment header around each chunk.
+pub const FRAGMENT_OVERHEAD: usize = HEADER_SIZE + 8 + 2 + 2 + 4;
+
+const FLAG_FRAGMENT: u8 = 0x01;
+
+const START: u8 = 1;
+const START_ACK: u8 = 2;
+const SYNC: u8 = 3;
+const DATA: u8 = 4;
+const SILENCE: u8 = 5;
+const HANG_UP: u8 = 6;
+const BUSY: u8 = 7;
+const REJECT: u8 = 8;
+const KEEP_ALIVE: u8 = 9;
+const ENCRYPTED: u8 = 10;
+const REPAIR: u8 = 11;
+const SYNC_REPLY: u8 = 12;
+const CONFERENCE: u8 = 13;
+const TEXT: u8 = 14;
+const ATTACHMENT: u8 = 15;
+const MESSAGE_ACK: u8 = 16;
+const SIGNED: u8 = 17;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum WireError {
+    /// Sent by a phone newer than us.
+    UnsupportedVersion(u8),
+    UnknownType(u8),
+    /// The packet ends before a field does.
+    Truncated,
+    /// A field holds a value we can't use.
+    Invalid(&'static str),
+}
+
+impl fmt::Display for WireError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return match self {
+            WireError::UnsupportedVersion(version) => write!(f, "Unsupported wire version {}", version),
+            WireError::UnknownType(packet_type) => write!(f, "Unknown packet type {}", packet_type),
+            WireError::Truncated => f.write_str("Packet is truncated"),
+            WireError::Invalid(field) => write!(f, "Invalid {}", field),
+        };
+    }
+}
+
+impl std::error::Error for WireError {}
+
+/// One piece of a packet that was too big to send whole.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Fragment {
+    pub message_id: u64,
+    pub index: u16,
+    pub count: u16,
+    pub chunk: Vec<u8>,
+}
+
+#[derive(Debug)]
+pub enum Envelope {
+    Packet(DataPacket),
+    Fragment(Fragment),
+}
+
+pub fn encode(packet: &DataPacket) -> Vec<u8> {
+    let mut body = Writer(vec![]);
+
+    let packet_type = match packet {
+        DataPacket::UIEvent(_) => panic!("UI events never leave the phone"),
+        DataPacket::FromParticipant { .. } => panic!("Participant packets are only tagged once they arrive"),
+        DataPacket::FromSender { .. } => panic!("Signed packets are only tagged once they arrive"),
+        DataPacket::Start { output, session_id, sync_count, codecs, sample_rate, frames_per_buffer, public_key, salt } => {
+            body.bytes(output);
+            body.u64(*session_id);
+            body.u64(*sync_count);
+            body.bytes(&codecs.iter().map(|codec| codec_to_byte(*codec)).collect::<Vec<u8>>());
+            body.u32(*sample_rate);
+            body.u32(*frames_per_buffer);
+            body.bytes(public_key);
+            body.bytes(salt);
+            START
+        }
+        DataPacket::StartAck { output, session_id, sync_count, codec, sample_rate, frames_per_buffer, public_key, salt, codecs } => {
+            body.bytes(output);
+            body.u64(*session_id);
+            body.u64(*sync_count);
+            body.u8(codec_to_byte(*codec));
+            body.u32(*sample_rate);
+            body.u32(*frames_per_buffer);
+            body.bytes(public_key);
+            body.bytes(salt);
+            if !codecs.is_empty() {
+                body.bytes(&codecs.iter().map(|codec| codec_to_byte(*codec)).collect::<Vec<u8>>());
+            }
+            START_ACK
+        }
+        DataPacket::Sync { time, count } => {
+            body.u128(*time);
+            body.u64(*count);
+            SYNC
+        }
+        DataPacket::SyncReply { count, origin, received, time } => {
+            body.u64(*count);
+            body.u128(*origin);
+            body.u128(*received);
+            body.u128(*time);
+            SYNC_REPLY
+        }
+        DataPacket::Data { counter, format, buffer, codec } => {
+            body.u32(*counter);
+            body.u8(format_to_byte(*format));
+            body.bytes(buffer);
+            if let Some(codec) = codec {
+                body.u8(codec_to_byte(*codec));
+            }
+            DATA
+        }
+        DataPacket::Silence { counter, frames, level } => {
+            body.u32(*counter);
+            body.u32(*frames);
+            body.u16(*level);
+            SILENCE
+        }
+        DataPacket::Repair { counters, format, length, parity, codec } => {
+            body.u32(counters.len() as u32);
+            counters.iter().for_each(|counter| body.u32(*counter));
+            body.u8(format_to_byte(*format));
+            body.u32(*length);
+            body.bytes(parity);
+            if let Some(codec) = codec {
+                body.u8(codec_to_byte(*codec));
+            }
+            REPAIR
+        }
+        DataPacket::HangUp { session_id } => {
+            body.u64(*session_id);
+            HANG_UP
+        }
+        DataPacket::Busy { session_id } => {
+            body.u64(*session_id);
+            BUSY
+        }
+        DataPacket::Reject { session_id, reason } => {
+            body.u64(*session_id);
+            body.bytes(reason.as_bytes());
+            REJECT
+        }
+        DataPacket::KeepAlive => KEEP_ALIVE,
+        DataPacket::Encrypted { session_id, counter, payload } => {
+            body.u64(*session_id);
+            body.u64(*counter);
+            body.bytes(payload);
+            ENCRYPTED
+        }
+        DataPacket::Conference { session_id, index, secret, participants, sample_rates } => {
+            body.u64(*session_id);
+            body.u32(*index);
+            body.bytes(secret);
+            body.u32(participants.len() as u32);
+            participants.iter().for_each(|participant| body.bytes(participant));
+            body.u32(sample_rates.len() as u32);
+            sample_rates.iter().for_each(|sample_rate| body.u32(*sample_rate));
+            CONFERENCE
+        }
+        DataPacket::Text { id, from, text } => {
+            body.u64(*id);
+            body.bytes(from);
+            body.bytes(text.as_bytes());
+            TEXT
+        }
+        DataPacket::Attachment { id, from, name, data } => {
+            body.u64(*id);
+            body.bytes(from);
+            body.bytes(name.as_bytes());
+            body.bytes(data);
+            ATTACHMENT
+        }
+        DataPacket::MessageAck { id, from } => {
+            body.u64(*id);
+            body.bytes(from);
+            MESSAGE_ACK
+        }
+        DataPacket::Signed { public_key, signature, payload } => {
+            body.bytes(public_key);
+            body.bytes(signature);
+            body.bytes(payload);
+            SIGNED
+        }
+    };
+
+    let mut envelope = Writer(Vec::with_capacity(HEADER_SIZE + body.0.len()));
+    envelope.u8(VERSION);
+    envelope.u8(packet_type);
+    envelope.u8(0);
+    envelope.bytes(&body.0);
+
+    return envelope.0;
+}
+
+/// Splits an encoded packet into fragments of at most `max_len` bytes, or hands it back
+/// alone if it already fits.
+pub fn split(encoded: Vec<u8>, message_id: u64, max_len: usize) -> Vec<Vec<u8>> {
+    if encoded.len() <= max_len {
+        return vec![encoded];
+    }
+
+    assert!(max_len > FRAGMENT_OVERHEAD, "Fragments of {} bytes can't hold any data", max_len);
+    let chunks = encoded.chunks(max_len - FRAGMENT_OVERHEAD);
+    let count = chunks.len();
+    assert!(count <= u16::MAX as usize, "Packet of {} bytes needs too many fragments", encoded.len());
+
+    return chunks
+        .enumerate()
+        .map(|(index, chunk)| {
+            let mut body = Writer(vec![]);
+            body.u64(message_id);
+            body.u16(index as u16);
+            body.u16(count as u16);
+            body.bytes(chunk);
+
+            let mut envelope = Writer(Vec::with_capacity(HEADER_SIZE + body.0.len()));
+            envelope.u8(VERSION);
+            envelope.u8(encoded[1]);
+            envelope.u8(FLAG_FRAGMENT);
+            envelope.bytes(&body.0);
+            envelope.0
+        })
+        .collect();
+}
+
+/// Decodes a complete packet, fragments are refused.
+pub fn decode(bytes: &[u8]) -> Result<DataPacket, WireError> {
+    return match decode_envelope(bytes)? {
+        Envelope::Packet(packet) => Ok(packet),
+        Envelope::Fragment(_) => Err(WireError::Invalid("fragment")),
+    };
+}
+
+pub fn decode_envelope(bytes: &[u8]) -> Result<Envelope, WireError> {
+    let mut envelope = Reader { bytes, position: 0 };
+
+    let version = envelope.u8()?;
+    if version != VERSION {
+        return Err(WireError::UnsupportedVersion(version));
+    }
+    let packet_type = envelope.u8()?;
+    let flags = envelope.u8()?;
+    let mut body = Reader { bytes: envelope.bytes()?, position: 0 };
+
+    if flags & FLAG_FRAGMENT != 0 {
+        let fragment = Fragment {
+            message_id: body.u64()?,
+            index: body.u16()?,
+            count: body.u16()?,
+            chunk: body.bytes()?.to_vec(),
+        };
+        if fragment.index >= fragment.count {
+            return Err(WireError::Invalid("fragment index"));
+        }
+        return Ok(Envelope::Fragment(fragment));
+    }
+
+    let packet = match packet_type {
+        START => DataPacket::Start {
+            output: body.bytes()?.to_vec(),
+            session_id: body.u64()?,
+            sync_count: body.u64()?,
+            codecs: body.bytes()?.iter().filter_map(|byte| byte_to_codec(*byte)).collect(),
+            sample_rate: body.u32()?,
+            frames_per_buffer: body.u32()?,
+            public_key: body.bytes()?.to_vec(),
+            salt: body.bytes()?.to_vec(),
+        },
+        START_ACK => DataPacket::StartAck {
+            output: body.bytes()?.to_vec(),
+            session_id: body.u64()?,
+            sync_count: body.u64()?,
+            codec: byte_to_codec(body.u8()?).ok_or(WireError::Invalid("codec"))?,
+            sample_rate: body.u32()?,
+            frames_per_buffer: body.u32()?,
+            public_key: body.bytes()?.to_vec(),
+            salt: body.bytes()?.to_vec(),
+            codecs: match body.is_done() {
+                true => vec![],
+                false => body.bytes()?.iter().filter_map(|byte| byte_to_codec(*byte)).collect(),
+            },
+        },
+        SYNC => DataPacket::Sync {
+            time: body.u128()?,
+            count: body.u64()?,
+        },
+        SYNC_REPLY => DataPacket::SyncReply {
+            count: body.u64()?,
+            origin: body.u128()?,
+            received: body.u128()?,
+            time: body.u128()?,
+        },
+        DATA => DataPacket::Data {
+            counter: body.u32()?,
+            format: byte_to_format(body.u8()?).ok_or(WireError::Invalid("sample format"))?,
+            buffer: body.bytes()?.to_vec(),
+            codec: body.optional_codec()?,
+        },
+        SILENCE => DataPacket::Silence {
+            counter: body.u32()?,
+            frames: body.u32()?,
+            level: body.u16()?,
+        },
+        REPAIR => DataPacket::Repair {
+            counters: body.u32_list()?,
+            format: byte_to_format(body.u8()?).ok_or(WireError::Invalid("sample format"))?,
+            length: body.u32()?,
+            parity: body.bytes()?.to_vec(),
+            codec: body.optional_codec()?,
+        },
+        HANG_UP => DataPacket::HangUp {
+            session_id: body.u64()?,
+        },
+        BUSY => DataPacket::Busy {
+            session_id: body.u64()?,
+        },
+        REJECT => DataPacket::Reject {
+            session_id: body.u64()?,
+            reason: body.text("reason")?,
+        },
+        KEEP_ALIVE => DataPacket::KeepAlive,
+        ENCRYPTED => DataPacket::Encrypted {
+            session_id: body.u64()?,
+            counter: body.u64()?,
+            payload: body.bytes()?.to_vec(),
+        },
+        CONFERENCE => DataPacket::Conference {
+            session_id: body.u64()?,
+            index: body.u32()?,
+            secret: body.bytes()?.to_vec(),
+            participants: body.bytes_list()?,
+            sample_rates: body.u32_list()?,
+        },
+        TEXT => DataPacket::Text {
+            id: body.u64()?,
+            from: body.bytes()?.to_vec(),
+            text: body.text("text")?,
+        },
+        ATTACHMENT => DataPacket::Attachment {
+            id: body.u64()?,
+            from: body.bytes()?.to_vec(),
+            name: body.text("name")?,
+            data: body.bytes()?.to_vec(),
+        },
+        MESSAGE_ACK => DataPacket::MessageAck {
+            id: body.u64()?,
+            from: body.bytes()?.to_vec(),
+        },
+        SIGNED => DataPacket::Signed {
+            public_key: body.bytes()?.to_vec(),
+            signature: body.bytes()?.to_vec(),
+            payload: body.bytes()?.to_vec(),
+        },
+        packet_type => return Err(WireError::UnknownType(packet_type)),
+    };
+
+    return Ok(Envelope::Packet(packet));
+}
+
+fn codec_to_byte(codec: CodecType) -> u8 {
+    return match codec {
+        CodecType::Pcm => 0,
+        CodecType::MuLaw => 1,
+        CodecType::ImaAdpcm => 2,
+    };
+}
+
+fn byte_to_codec(byte: u8) -> Option<CodecType> {
+    return match byte {
+        0 => Some(CodecType::Pcm),
+        1 => Some(CodecType::MuLaw),
+        2 => Some(CodecType::ImaAdpcm),
+        _ => None
+    };
+}
+
+fn format_to_byte(format: SampleFormat) -> u8 {
+    return match format {
+        SampleFormat::U8 => 0,
+        SampleFormat::I16 => 1,
+        SampleFormat::F32 => 2,
+    };
+}
+
+fn byte_to_format(byte: u8) -> Option<SampleFormat> {
+    return match byte {
+        0 => Some(SampleFormat::U8),
+        1 => Some(SampleFormat::I16),
+        2 => Some(SampleFormat::F32),
+        _ => None
+    };
+}
+
+struct Writer(Vec<u8>);
+
+impl Writer {
+    fn u8(&mut self, value: u8) {
+        self.0.push(value);
+    }
+
+    fn u16(&mut self, value: u16) {
+        self.0.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.0.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn u64(&mut self, value: u64) {
+        self.0.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn u128(&mut self, value: u128) {
+        self.0.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn bytes(&mut self, value: &[u8]) {
+        self.u32(value.len() as u32);
+        self.0.extend_from_slice(value);
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], WireError> {
+        let end = self.position.checked_add(len).ok_or(WireError::Truncated)?;
+        let taken = self.bytes.get(self.position..end).ok_or(WireError::Truncated)?;
+        self.position = end;
+        return Ok(taken);
+    }
+
+    fn u8(&mut self) -> Result<u8, WireError> {
+        return Ok(self.take(1)?[0]);
+    }
+
+    fn u16(&mut self) -> Result<u16, WireError> {
+        return Ok(u16::from_be_bytes(self.take(2)?.try_into().unwrap()));
+    }
+
+    fn u32(&mut self) -> Result<u32, WireError> {
+        return Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap()));
+    }
+
+    fn u64(&mut self) -> Result<u64, WireError> {
+        return Ok(u64::from_be_bytes(self.take(8)?.try_into().unwrap()));
+    }
+
+    fn u128(&mut self) -> Result<u128, WireError> {
+        return Ok(u128::from_be_bytes(self.take(16)?.try_into().unwrap()));
+    }
+
+    fn bytes(&mut self) -> Result<&'a [u8], WireError> {
+        let len = self.u32()? as usize;
+        return self.take(len);
+    }
+
+    fn text(&mut self, field: &'static str) -> Result<String, WireError> {
+        return String::from_utf8(self.bytes()?.to_vec())
+            .map_err(|_| WireError::Invalid(field));
+    }
+
+    /// Whether every byte has been read, fields added later are missing from older packets.
+    fn is_done(&self) -> bool {
+        return self.position >= self.bytes.len();
+    }
+
+    fn optional_codec(&mut self) -> Result<Option<CodecType>, WireError> {
+        if self.is_done() {
+            return Ok(None);
+        }
+
+        return byte_to_codec(self.u8()?)
+            .map(Some)
+            .ok_or(WireError::Invalid("codec"));
+    }
+
+    fn bytes_list(&mut self) -> Result<Vec<Vec<u8>>, WireError> {
+        let count = self.u32()? as usize;
+        // Every entry has at least its length, checked first for the same reason as below.
+        if self.bytes.len() - self.position < count.saturating_mul(4) {
+            return Err(WireError::Truncated);
+        }
+
+        return (0..count).map(|_| self.bytes().map(<[u8]>::to_vec)).collect();
+    }
+
+    fn u32_list(&mut self) -> Result<Vec<u32>, WireError> {
+        let count = self.u32()? as usize;
+        // Checked up front so a bogus count can't make us allocate.
+        if self.bytes.len() - self.position < count.saturating_mul(4) {
+            return Err(WireError::Truncated);
+        }
+
+        return (0..count).map(|_| self.u32()).collect();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Encodings every phone speaking version 1 has to agree on, these must never change.
+    fn golden_vectors() -> Vec<(DataPacket, &'static str)> {
+        return vec![
+            (
+                DataPacket::Start {
+                    output: vec![0x76, 0xa9],
+                    session_id: 0x0102030405060708,
+                    sync_count: 5,
+                    codecs: vec![CodecType::ImaAdpcm, CodecType::MuLaw, CodecType::Pcm],
+                    sample_rate: 8000,
+                    frames_per_buffer: 800,
+                    public_key: vec![0x02, 0xab],
+                    salt: vec![0xcd],
+                },
+                "010100000000300000000276a9010203040506070800000000000000050000000302010000001f40000003200000000202ab00000001cd"
+            ),
+            (
+                DataPacket::StartAck {
+                    output: vec![0x76, 0xa9],
+                    session_id: 0x0102030405060708,
+                    sync_count: 5,
+                    codec: CodecType::MuLaw,
+                    sample_rate: 44100,
+                    frames_per_buffer: 4410,
+                    public_key: vec![0x03, 0xef],
+                    salt: vec![0x12],
+                    codecs: vec![],
+                },
+                "0102000000002a0000000276a901020304050607080000000000000005010000ac440000113a0000000203ef0000000112"
+            ),
+            (
+                DataPacket::StartAck {
+                    output: vec![0x76, 0xa9],
+                    session_id: 0x0102030405060708,
+                    sync_count: 5,
+                    codec: CodecType::MuLaw,
+                    sample_rate: 44100,
+                    frames_per_buffer: 4410,
+                    public_key: vec![0x03, 0xef],
+                    salt: vec![0x12],
+                    codecs: vec![CodecType::ImaAdpcm, CodecType::MuLaw],
+                },
+                "010200000000300000000276a901020304050607080000000000000005010000ac440000113a0000000203ef0000000112000000020201"
+            ),
+            (
+                DataPacket::Sync { time: 1_600_000_000_000_000_000, count: 3 },
+                "01030000000018000000000000000016345785d8a000000000000000000003"
+            ),
+            (
+                DataPacket::SyncReply {
+                    count: 3,
+                    origin: 1_600_000_000_000_000_000,
+                    received: 1_600_000_000_250_000_000,
+                    time: 1_600_000_000_251_000_000,
+                },
+                "010c00000000380000000000000003000000000000000016345785d8a00000000000000000000016345785e786b280000000000000000016345785e795f4c0"
+            ),
+            (
+                DataPacket::Data { counter: 7, format: SampleFormat::I16, buffer: vec![1, 2, 3], codec: None },
+                "0104000000000c000000070100000003010203"
+            ),
+            (
+                DataPacket::Data { counter: 7, format: SampleFormat::I16, buffer: vec![1, 2, 3], codec: Some(CodecType::MuLaw) },
+                "0104000000000d00000007010000000301020301"
+            ),
+            (
+                DataPacket::Silence { counter: 8, frames: 800, level: 300 },
+                "0105000000000a0000000800000320012c"
+            ),
+            (DataPacket::HangUp { session_id: 1 }, "010600000000080000000000000001"),
+            (DataPacket::Busy { session_id: 1 }, "010700000000080000000000000001"),
+            (
+                DataPacket::Reject { session_id: 1, reason: "No answer".to_string() },
+                "010800000000150000000000000001000000094e6f20616e73776572"
+            ),
+            (DataPacket::KeepAlive, "01090000000000"),
+            (
+                DataPacket::Encrypted { session_id: 1, counter: 2, payload: vec![0xff, 0xee] },
+                "010a00000000160000000000000001000000000000000200000002ffee"
+            ),
+            (
+                DataPacket::Repair { counters: vec![4, 6], format: SampleFormat::I16, length: 2, parity: vec![0x0f, 0xf0], codec: None },
+                "010b00000000170000000200000004000000060100000002000000020ff0"
+            ),
+            (
+                DataPacket::Repair {
+                    counters: vec![4, 6],
+                    format: SampleFormat::I16,
+                    length: 2,
+                    parity: vec![0x0f, 0xf0],
+                    codec: Some(CodecType::ImaAdpcm),
+                },
+                "010b00000000180000000200000004000000060100000002000000020ff002"
+            ),
+            (
+                DataPacket::Conference {
+                    session_id: 1,
+                    index: 1,
+                    secret: vec![0xaa, 0xbb],
+                    participants: vec![vec![1], vec![2, 3]],
+                    sample_rates: vec![8000],
+                },
+                "010d000000002900000000000000010000000100000002aabb0000000200000001010000000202030000000100001f40"
+            ),
+            (
+                DataPacket::Text { id: 7, from: vec![1, 2], text: "hi".to_string() },
+                "010e00000000140000000000000007000000020102000000026869"
+            ),
+            (
+                DataPacket::Attachment { id: 8, from: vec![1], name: "a".to_string(), data: vec![0xff] },
+                "010f000000001700000000000000080000000101000000016100000001ff"
+            ),
+            (
+                DataPacket::MessageAck { id: 8, from: vec![1] },
+                "0110000000000d00000000000000080000000101"
+            ),
+            (
+                DataPacket::Signed { public_key: vec![2], signature: vec![0xaa, 0xbb], payload: vec![0xff] },
+                "01110000000010000000010200000002aabb00000001ff"
+            ),
+        ];
+    }
+
+    #[test]
+    fn encodes_golden_vectors() {
+        for (packet, expected) in golden_vectors() {
+            assert_eq!(hex::encode(encode(&packet)), expected, "{:?}", packet);
+        }
+    }
+
+    #[test]
+    fn decodes_golden_vectors() {
+        for (packet, encoded) in golden_vectors() {
+            let decoded = decode(&hex::decode(encoded).unwrap()).unwrap();
+            assert_eq!(format!("{:?}", decoded), format!("{:?}", packet));
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_versions() {
+        assert_eq!(decode(&[2, 6, 0, 0, 0, 0, 0]).unwrap_err(), WireError::UnsupportedVersion(2));
+        assert_eq!(decode(&[0]).unwrap_err(), WireError::UnsupportedVersion(0));
+    }
+
+    #[test]
+    fn rejects_unknown_types() {
+        assert_eq!(decode(&[1, 200, 0, 0, 0, 0, 0]).unwrap_err(), WireError::UnknownType(200));
+    }
+
+    #[test]
+    fn rejects_truncated_packets() {
+        let encoded = hex::decode("0104000000000c000000070100000003010203").unwrap();
+        for len in 0..encoded.len() {
+            let result = decode(&encoded[..len]);
+            assert!(result.is_err(), "decoded {} bytes as {:?}", len, result);
+        }
+
+        // The body length claims more than the fields hold.
+        let short_body = hex::decode("01040000000008000000070100000003").unwrap();
+        assert_eq!(decode(&short_body).unwrap_err(), WireError::Truncated);
+    }
+
+    #[test]
+    fn rejects_invalid_fields() {
+        let bad_format = hex::decode("0104000000000c000000070900000003010203").unwrap();
+        assert_eq!(decode(&bad_format).unwrap_err(), WireError::Invalid("sample format"));
+
+        let bad_codec = hex::decode("0104000000000d00000007010000000301020309").unwrap();
+        assert_eq!(decode(&bad_codec).unwrap_err(), WireError::Invalid("codec"));
+
+        let bad_reason = hex::decode("0108000000000e000000000000000100000002fffe").unwrap();
+        assert_eq!(decode(&bad_reason).unwrap_err(), WireError::Invalid("reason"));
+
+        let bad_name = hex::decode("010f000000001700000000000000080000000101000000019900000001ff").unwrap();
+        assert_eq!(decode(&bad_name).unwrap_err(), WireError::Invalid("name"));
+    }
+
+    #[test]
+    fn rejects_impossible_list_counts() {
+        let repair = hex::decode("010b0000000017ffffffff00000004000000060100000002000000020ff0").unwrap();
+        assert_eq!(decode(&repair).unwrap_err(), WireError::Truncated);
+
+        let conference = hex::decode("010d000000001400000000000000010000000100000000ffffffff").unwrap();
+        assert_eq!(decode(&conference).unwrap_err(), WireError::Truncated);
+    }
+
+    #[test]
+    fn ignores_fields_added_later() {
+        let extended = hex::decode("0105020000000c0000000800000320012cabcd").unwrap();
+        let packet = decode(&extended).unwrap();
+        assert_eq!(format!("{:?}", packet), "Silence { counter: 8, frames: 800, level: 300 }");
+    }
+
+    #[test]
+    fn splits_large_packets() {
+        let packet = DataPacket::Data { counter: 7, format: SampleFormat::I16, buffer: (0..32).collect(), codec: None };
+        let fragments = split(encode(&packet), 0x0a0b, FRAGMENT_OVERHEAD + 24);
+
+        assert_eq!(
+            fragments.iter().map(hex::encode).collect::<Vec<String>>(),
+            vec![
+                "010401000000280000000000000a0b0000000200000018010400000000290000000701000000200001020304050607",
+                "010401000000280000000000000a0b000100020000001808090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f",
+            ]
+        );
+
+        let mut joined = vec![];
+        for (index, fragment) in fragments.iter().enumerate() {
+            match decode_envelope(fragment).unwrap() {
+                Envelope::Fragment(fragment) => {
+                    assert_eq!((fragment.message_id, fragment.index, fragment.count), (0x0a0b, index as u16, 2));
+                    joined.extend(fragment.chunk);
+                }
+                packet => panic!("Decoded {:?}", packet),
+            }
+        }
+        assert_eq!(format!("{:?}", decode(&joined).unwrap()), format!("{:?}", packet));
+    }
+
+    #[test]
+    fn leaves_small_packets_whole() {
+        let encoded = encode(&DataPacket::KeepAlive);
+        assert_eq!(split(encoded.clone(), 1, encoded.len()), vec![encoded]);
+    }
+
+    #[test]
+    fn rejects_fragments_outside_their_message() {
+        let fragment = "010401000000280000000000000a0b000100020000001808090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+        assert_eq!(decode(&hex::decode(fragment).unwrap()).unwrap_err(), WireError::Invalid("fragment"));
+
+        let past_the_end = fragment.replace("0a0b00010002", "0a0b00020002");
+        assert_eq!(decode_envelope(&hex::decode(past_the_end).unwrap()).unwrap_err(), WireError::Invalid("fragment index"));
+    }
+
+    #[test]
+    fn skips_unknown_codecs() {
+        let packet = DataPacket::Start {
+            output: vec![],
+            session_id: 1,
+            sync_count: 5,
+            codecs: vec![CodecType::MuLaw],
+            sample_rate: 8000,
+            frames_per_buffer: 800,
+            public_key: vec![],
+            salt: vec![],
+        };
+        let mut encoded = encode(&packet);
+        // Offer a codec from the future ahead of mu-law, past the empty output, session
+        // id and sync count.
+        let codecs = HEADER_SIZE + 4 + 8 + 8;
+        encoded[HEADER_SIZE - 1] += 1;
+        encoded[codecs + 3] = 2;
+        encoded.insert(codecs + 4, 42);
+
+        match decode(&encoded).unwrap() {
+            DataPacket::Start { codecs, .. } => assert_eq!(codecs, vec![CodecType::MuLaw]),
+            packet => panic!("Decoded {:?}", packet),
+        }
+    }
+}